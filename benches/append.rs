@@ -0,0 +1,114 @@
+//! The cost of `append` on the logging thread: for messages without format
+//! arguments, which are not copied, for formatted ones, and for formatted ones
+//! that sampling drops before they are formatted.
+//!
+//! The batches are discarded by the upload task on a worker thread, and the
+//! allocations per append made on the logging thread are printed before the
+//! timings.
+
+use betterstack_logger::logger::BetterStackAppender;
+use betterstack_logger::sink::{LogMessage, SendError, Sink};
+use criterion::{criterion_group, criterion_main, Criterion};
+use futures_util::future::BoxFuture;
+use log::{Level, LevelFilter, Record};
+use log4rs::append::Append;
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::cell::Cell;
+
+struct CountingAllocator;
+
+thread_local! {
+    static ALLOCATIONS: Cell<u64> = const { Cell::new(0) };
+}
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let _ = ALLOCATIONS.try_with(|count| count.set(count.get() + 1));
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+struct Discard;
+
+impl Sink for Discard {
+    fn send<'a>(&'a self, _: &'a [LogMessage]) -> BoxFuture<'a, Result<(), SendError>> {
+        Box::pin(async { Ok(()) })
+    }
+}
+
+fn append_static(appender: &BetterStackAppender) {
+    appender
+        .append(
+            &Record::builder()
+                .args(format_args!("request handled"))
+                .level(Level::Info)
+                .target("bench")
+                .build(),
+        )
+        .unwrap();
+}
+
+fn append_formatted(appender: &BetterStackAppender, id: u64) {
+    appender
+        .append(
+            &Record::builder()
+                .args(format_args!("request {id} handled"))
+                .level(Level::Info)
+                .target("bench")
+                .build(),
+        )
+        .unwrap();
+}
+
+fn allocations(mut append: impl FnMut()) -> f64 {
+    const RUNS: u64 = 10_000;
+    let before = ALLOCATIONS.with(Cell::get);
+    for _ in 0..RUNS {
+        append();
+    }
+    (ALLOCATIONS.with(Cell::get) - before) as f64 / RUNS as f64
+}
+
+fn append(c: &mut Criterion) {
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .worker_threads(1)
+        .enable_all()
+        .build()
+        .unwrap();
+    let _guard = runtime.enter();
+    let builder = || {
+        BetterStackAppender::builder("http://127.0.0.1:9".to_string(), "token".to_string())
+            .channel_capacity(100_000)
+            .with_sink(Box::new(Discard))
+    };
+    let appender = builder().build();
+    let sampled_out = builder()
+        .sample_rate(Level::Info, 0.0)
+        .always_ship(LevelFilter::Error)
+        .build();
+
+    println!(
+        "allocations per append: static {}, formatted {}, formatted and sampled out {}",
+        allocations(|| append_static(&appender)),
+        allocations(|| append_formatted(&appender, 42)),
+        allocations(|| append_formatted(&sampled_out, 42)),
+    );
+
+    let mut group = c.benchmark_group("append");
+    group.bench_function("static", |b| b.iter(|| append_static(&appender)));
+    group.bench_function("formatted", |b| b.iter(|| append_formatted(&appender, 42)));
+    group.bench_function("formatted_sampled_out", |b| {
+        b.iter(|| append_formatted(&sampled_out, 42))
+    });
+    group.finish();
+}
+
+criterion_group!(benches, append);
+criterion_main!(benches);
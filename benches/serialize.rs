@@ -0,0 +1,71 @@
+//! Serializing batches as they are sent, with `HttpSink::serialize_batch`.
+//!
+//! Run with `--features lean-ndjson` to compare the lean NDJSON encoder to
+//! the serde_json baseline, and with `--features gzip` to include compression.
+
+use betterstack_logger::sink::{Format, HttpSink, LogMessage};
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+use serde_json::Map;
+use std::hint::black_box;
+use std::time::Instant;
+
+fn batch() -> Vec<LogMessage> {
+    (0..100)
+        .map(|i| {
+            let mut fields = Map::new();
+            fields.insert("request_id".to_string(), format!("req-{i}").into());
+            fields.insert("attempt".to_string(), i.into());
+            LogMessage {
+                timestamp: "2024-01-02T03:04:05.678Z".to_string(),
+                level: "INFO ".to_string(),
+                target: "myapp::handlers".to_string(),
+                thread: Some("tokio-runtime-worker".to_string()),
+                message: format!("handled request {i} in 12ms").into(),
+                module_path: Some("myapp::handlers".to_string()),
+                file: Some("src/handlers.rs".to_string()),
+                line: Some(42),
+                function: None,
+                fields,
+                enqueued_at: Instant::now(),
+            }
+        })
+        .collect()
+}
+
+fn sink(format: Format) -> HttpSink {
+    HttpSink::new(reqwest::Client::new(), String::new(), String::new()).format(format)
+}
+
+fn serialize(c: &mut Criterion) {
+    let batch = batch();
+    let mut group = c.benchmark_group("serialize_batch");
+    group.throughput(Throughput::Elements(batch.len() as u64));
+    for (name, format) in [("json", Format::Json), ("ndjson", Format::Ndjson)] {
+        let sink = sink(format);
+        group.bench_function(name, |b| {
+            b.iter(|| sink.serialize_batch(black_box(&batch)).unwrap())
+        });
+    }
+    // What the NDJSON path costs without the lean encoder.
+    group.bench_function("ndjson_serde_json", |b| {
+        b.iter(|| {
+            let mut body = Vec::new();
+            for message in black_box(&batch) {
+                serde_json::to_writer(&mut body, message).unwrap();
+                body.push(b'\n');
+            }
+            body
+        })
+    });
+    #[cfg(feature = "gzip")]
+    {
+        let sink = sink(Format::Json).compression(betterstack_logger::sink::Compression::Gzip);
+        group.bench_function("json_gzip", |b| {
+            b.iter(|| sink.serialize_batch(black_box(&batch)).unwrap())
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, serialize);
+criterion_main!(benches);
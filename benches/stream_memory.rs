@@ -0,0 +1,150 @@
+//! Peak heap use while sending one large gzip-compressed batch, with the body
+//! built in memory first and with `HttpSink::stream_bodies`.
+//!
+//! The batch is sent to a local server that reads and discards the body, and
+//! the peak is counted from after the batch was built, so it covers the
+//! serialized and compressed copies that are held while sending. For 20,000
+//! messages, 21.9 MB of JSON, in a release build:
+//!
+//! ```text
+//! buffered:  69889 KiB
+//! streamed:    526 KiB
+//! ```
+
+use betterstack_logger::sink::{Compression, HttpSink, LogMessage, Sink};
+use serde_json::Map;
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Instant;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+
+/// Tracks the bytes allocated on all threads, and their peak.
+struct PeakAllocator;
+
+static CURRENT: AtomicUsize = AtomicUsize::new(0);
+static PEAK: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for PeakAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let current = CURRENT.fetch_add(layout.size(), Ordering::Relaxed) + layout.size();
+        PEAK.fetch_max(current, Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        CURRENT.fetch_sub(layout.size(), Ordering::Relaxed);
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: PeakAllocator = PeakAllocator;
+
+const MESSAGES: usize = 20_000;
+
+fn batch() -> Vec<LogMessage> {
+    (0..MESSAGES)
+        .map(|i| {
+            let mut fields = Map::new();
+            fields.insert("request_id".to_string(), format!("req-{i}").into());
+            fields.insert("payload".to_string(), format!("{i:x}").repeat(220).into());
+            LogMessage {
+                timestamp: "2024-01-02T03:04:05.678Z".to_string(),
+                level: "INFO ".to_string(),
+                target: "myapp::handlers".to_string(),
+                thread: Some("tokio-runtime-worker".to_string()),
+                message: format!("handled request {i} in 12ms").into(),
+                module_path: Some("myapp::handlers".to_string()),
+                file: Some("src/handlers.rs".to_string()),
+                line: Some(42),
+                function: None,
+                fields,
+                enqueued_at: Instant::now(),
+            }
+        })
+        .collect()
+}
+
+/// Answers every request with `200 OK`, discarding its body, whether it has
+/// a length or is chunked.
+async fn serve(connection: TcpStream) -> std::io::Result<()> {
+    let mut reader = BufReader::new(connection);
+    let mut line = String::new();
+    loop {
+        let (mut length, mut chunked) = (0, false);
+        loop {
+            line.clear();
+            if reader.read_line(&mut line).await? == 0 {
+                return Ok(());
+            }
+            let header = line.trim_end().to_ascii_lowercase();
+            if header.is_empty() {
+                break;
+            }
+            if let Some(value) = header.strip_prefix("content-length:") {
+                length = value.trim().parse().unwrap_or(0);
+            }
+            chunked |= header == "transfer-encoding: chunked";
+        }
+        if chunked {
+            loop {
+                line.clear();
+                reader.read_line(&mut line).await?;
+                let size = line.trim_end().split(';').next().unwrap_or_default();
+                let size = u64::from_str_radix(size, 16).unwrap_or(0);
+                if size == 0 {
+                    // The empty trailer section.
+                    reader.read_line(&mut line).await?;
+                    break;
+                }
+                tokio::io::copy(&mut (&mut reader).take(size), &mut tokio::io::sink()).await?;
+                reader.read_line(&mut line).await?;
+            }
+        } else {
+            tokio::io::copy(&mut (&mut reader).take(length), &mut tokio::io::sink()).await?;
+        }
+        reader
+            .get_mut()
+            .write_all(b"HTTP/1.1 200 OK\r\ncontent-length: 0\r\n\r\n")
+            .await?;
+    }
+}
+
+/// The peak heap above what was allocated before sending `batch` with `sink`.
+async fn peak_while_sending(sink: &HttpSink, batch: &[LogMessage]) -> usize {
+    let before = CURRENT.load(Ordering::Relaxed);
+    PEAK.store(before, Ordering::Relaxed);
+    sink.send(batch).await.unwrap();
+    PEAK.load(Ordering::Relaxed) - before
+}
+
+fn main() {
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()
+        .unwrap();
+    runtime.block_on(async {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move {
+            while let Ok((connection, _)) = listener.accept().await {
+                tokio::spawn(serve(connection));
+            }
+        });
+
+        let batch = batch();
+        let size: usize = batch
+            .iter()
+            .map(|message| serde_json::to_vec(message).unwrap().len())
+            .sum();
+        println!("{MESSAGES} messages, {:.1} MB of JSON", size as f64 / 1e6);
+        for (name, stream) in [("buffered", false), ("streamed", true)] {
+            let sink = HttpSink::new(reqwest::Client::new(), url.clone(), "token".to_string())
+                .compression(Compression::Gzip)
+                .stream_bodies(stream);
+            let peak = peak_while_sending(&sink, &batch).await;
+            println!("{name}: {:>6} KiB", peak / 1024);
+        }
+    });
+}
@@ -0,0 +1,142 @@
+//! Correlation ids that tag every message logged within a scope, e.g. with
+//! the request id of an incoming HTTP request, and scope labels naming the
+//! logical operation a message was logged in.
+//!
+//! ```no_run
+//! use betterstack_logger::context;
+//!
+//! # async fn example(headers: http::HeaderMap) {
+//! let id = context::header_correlation_id(&headers, &["x-request-id"])
+//!     .unwrap_or_else(|| "unknown".to_string());
+//! context::correlation_scope(id, async {
+//!     log::info!("handling the request");
+//! })
+//! .await;
+//! # }
+//! ```
+//!
+//! Nested [`enter_scope`] guards form a dotted path in the `scope` field:
+//!
+//! ```
+//! use betterstack_logger::context;
+//!
+//! let import = context::enter_scope("import");
+//! {
+//!     let _parse = context::enter_scope("parse");
+//!     // Logged with "scope": "import.parse".
+//!     assert_eq!(context::scope().as_deref(), Some("import.parse"));
+//! }
+//! assert_eq!(context::scope().as_deref(), Some("import"));
+//! drop(import);
+//! assert_eq!(context::scope(), None);
+//! ```
+
+use http::HeaderMap;
+use std::cell::RefCell;
+use std::future::Future;
+use std::marker::PhantomData;
+use std::rc::Rc;
+
+/// The field messages logged within a correlation scope are tagged with.
+pub const CORRELATION_FIELD: &str = "correlation_id";
+
+/// The field messages logged within an [`enter_scope`] guard are tagged with.
+pub const SCOPE_FIELD: &str = "scope";
+
+tokio::task_local! {
+    static TASK_CORRELATION_ID: String;
+}
+
+thread_local! {
+    static THREAD_CORRELATION_ID: RefCell<Option<String>> = const { RefCell::new(None) };
+    static THREAD_SCOPES: RefCell<Vec<String>> = const { RefCell::new(Vec::new()) };
+}
+
+/// Tags the messages logged on this thread while `f` runs with a
+/// `correlation_id` field, restoring the previous id afterwards. Use
+/// [`correlation_scope`] for async code, where a thread-local id would leak to
+/// other tasks at `.await` points.
+pub fn with_correlation_scope<R>(id: impl Into<String>, f: impl FnOnce() -> R) -> R {
+    let previous = THREAD_CORRELATION_ID.with(|current| current.replace(Some(id.into())));
+    let _restore = RestoreThreadId(previous);
+    f()
+}
+
+/// Tags the messages logged while `future` runs with a `correlation_id`
+/// field. The id is kept in a task-local, so it follows the future across
+/// `.await` points and threads and does not leak to other tasks.
+pub async fn correlation_scope<F: Future>(id: impl Into<String>, future: F) -> F::Output {
+    TASK_CORRELATION_ID.scope(id.into(), future).await
+}
+
+/// The correlation id of the innermost scope, if any. A
+/// [`with_correlation_scope`] closure, which cannot yield, takes precedence
+/// over the [`correlation_scope`] of the task it runs in.
+pub fn correlation_id() -> Option<String> {
+    THREAD_CORRELATION_ID
+        .with(|current| current.borrow().clone())
+        .or_else(|| TASK_CORRELATION_ID.try_with(String::clone).ok())
+}
+
+/// The value of the first of `names` present in `headers`, e.g.
+/// `&["x-request-id", "x-correlation-id"]`. Values that are not visible
+/// ASCII are skipped.
+pub fn header_correlation_id(headers: &HeaderMap, names: &[&str]) -> Option<String> {
+    names
+        .iter()
+        .filter_map(|name| headers.get(*name)?.to_str().ok())
+        .find(|value| !value.is_empty())
+        .map(ToString::to_string)
+}
+
+/// Tags the messages logged on this thread with a `scope` field until the
+/// returned guard is dropped. Scopes entered while another is held are
+/// appended to it with a `.`, so `enter_scope("import")` and then
+/// `enter_scope("parse")` log `"scope": "import.parse"`. Dropping a guard
+/// also ends the scopes entered after it.
+///
+/// The guard cannot be sent to another thread. In async code, only hold it
+/// across code that does not `.await`, as the scope would otherwise tag
+/// messages of other tasks running on the thread in the meantime.
+pub fn enter_scope(name: impl AsRef<str>) -> ScopeGuard {
+    let depth = THREAD_SCOPES.with(|scopes| {
+        let mut scopes = scopes.borrow_mut();
+        scopes.push(name.as_ref().to_string());
+        scopes.len() - 1
+    });
+    ScopeGuard {
+        depth,
+        _not_send: PhantomData,
+    }
+}
+
+/// The dotted path of the scopes entered on this thread, if any.
+pub fn scope() -> Option<String> {
+    THREAD_SCOPES.with(|scopes| {
+        let scopes = scopes.borrow();
+        (!scopes.is_empty()).then(|| scopes.join("."))
+    })
+}
+
+/// Ends its scope when dropped, see [`enter_scope`].
+#[derive(Debug)]
+pub struct ScopeGuard {
+    depth: usize,
+    _not_send: PhantomData<Rc<()>>,
+}
+
+impl Drop for ScopeGuard {
+    fn drop(&mut self) {
+        THREAD_SCOPES.with(|scopes| scopes.borrow_mut().truncate(self.depth));
+    }
+}
+
+/// Puts back the id of the enclosing scope, also when the closure panics.
+struct RestoreThreadId(Option<String>);
+
+impl Drop for RestoreThreadId {
+    fn drop(&mut self) {
+        let previous = self.0.take();
+        THREAD_CORRELATION_ID.with(|current| *current.borrow_mut() = previous);
+    }
+}
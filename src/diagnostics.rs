@@ -0,0 +1,132 @@
+//! Reports about the logger's own problems, like a panicked upload task or a
+//! rejected source token, see `BetterStackAppenderBuilder::diagnostics_sink`.
+
+use std::cell::Cell;
+use std::fmt;
+use std::sync::mpsc::{self, Receiver, SyncSender};
+use std::sync::Arc;
+
+/// What a [`Diagnostic`] is about.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum DiagnosticKind {
+    /// The upload task is gone, so messages are no longer delivered.
+    PipelineClosed,
+    /// The upload task panicked.
+    TaskPanicked,
+    /// The validator rejected a message.
+    InvalidMessage,
+    /// The ingest endpoint rejected the source token.
+    AuthRejected,
+    /// The log4rs encoder failed, so the record's arguments were sent as is.
+    EncodeFailed,
+    /// A message could not be serialized and was skipped.
+    SerializationFailed,
+    /// A message was too large for a request and was dropped.
+    MessageTooLarge,
+    /// The server rejected compressed requests, so they are sent uncompressed.
+    CompressionRejected,
+    /// The local clock is off from the ingest server's.
+    ClockSkew,
+    /// A directive of the filter spec could not be parsed and is ignored.
+    InvalidFilterDirective,
+}
+
+/// One report of the logger about itself.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Diagnostic {
+    pub kind: DiagnosticKind,
+    /// Human readable description, as printed to stderr.
+    pub message: String,
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+/// Where the logger's own [`Diagnostic`]s go. Defaults to stderr.
+///
+/// Records logged from within a [`Callback`](Self::Callback) are not sent by
+/// the appender, so a callback that logs cannot feed back into itself, and
+/// diagnostics raised while it runs go to stderr instead.
+#[derive(Clone, Default)]
+pub enum DiagnosticsSink {
+    /// Print each diagnostic to stderr, prefixed with `betterstack_logger: `.
+    #[default]
+    Stderr,
+    /// Call the function with each diagnostic. It runs on the thread that hit
+    /// the problem, often the upload task, so it should return quickly.
+    Callback(Arc<dyn Fn(&Diagnostic) + Send + Sync>),
+    /// Send each diagnostic on the channel, dropping it if the channel is
+    /// full or disconnected.
+    Channel(SyncSender<Diagnostic>),
+    /// Drop every diagnostic.
+    Ignore,
+}
+
+impl DiagnosticsSink {
+    pub fn callback(callback: impl Fn(&Diagnostic) + Send + Sync + 'static) -> DiagnosticsSink {
+        DiagnosticsSink::Callback(Arc::new(callback))
+    }
+
+    /// A sink that buffers up to `capacity` diagnostics for the returned
+    /// receiver.
+    pub fn channel(capacity: usize) -> (DiagnosticsSink, Receiver<Diagnostic>) {
+        let (sender, receiver) = mpsc::sync_channel(capacity);
+        (DiagnosticsSink::Channel(sender), receiver)
+    }
+
+    pub(crate) fn report(&self, kind: DiagnosticKind, message: String) {
+        let diagnostic = Diagnostic { kind, message };
+        match self {
+            DiagnosticsSink::Callback(callback) if !in_callback() => {
+                let _guard = CallbackGuard::enter();
+                callback(&diagnostic);
+            }
+            DiagnosticsSink::Stderr | DiagnosticsSink::Callback(_) => {
+                eprintln!("betterstack_logger: {diagnostic}");
+            }
+            DiagnosticsSink::Channel(sender) => {
+                let _ = sender.try_send(diagnostic);
+            }
+            DiagnosticsSink::Ignore => {}
+        }
+    }
+}
+
+impl fmt::Debug for DiagnosticsSink {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DiagnosticsSink::Stderr => f.write_str("Stderr"),
+            DiagnosticsSink::Callback(_) => f.write_str("Callback(..)"),
+            DiagnosticsSink::Channel(_) => f.write_str("Channel(..)"),
+            DiagnosticsSink::Ignore => f.write_str("Ignore"),
+        }
+    }
+}
+
+thread_local! {
+    static IN_CALLBACK: Cell<bool> = const { Cell::new(false) };
+}
+
+/// Whether a diagnostics callback is running on this thread.
+pub(crate) fn in_callback() -> bool {
+    IN_CALLBACK.with(Cell::get)
+}
+
+/// Marks the thread as running a callback until dropped, even on a panic.
+struct CallbackGuard;
+
+impl CallbackGuard {
+    fn enter() -> CallbackGuard {
+        IN_CALLBACK.with(|flag| flag.set(true));
+        CallbackGuard
+    }
+}
+
+impl Drop for CallbackGuard {
+    fn drop(&mut self) {
+        IN_CALLBACK.with(|flag| flag.set(false));
+    }
+}
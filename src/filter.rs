@@ -0,0 +1,88 @@
+use log::{Level, LevelFilter};
+use std::collections::HashMap;
+use std::str::FromStr;
+
+/// Per-target level gating parsed from a `RUST_LOG`-style spec such as
+/// `info` or `myapp=debug,hyper=warn`.
+///
+/// Each comma-separated directive is either a level, a target prefix (which
+/// enables every level for it) or `target=level`. A record uses the directive
+/// with the longest matching target prefix, falling back to the bare level
+/// directive. Records no directive applies to are filtered out, except that
+/// an empty spec lets everything through.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TargetFilter {
+    default: Option<LevelFilter>,
+    // Sorted by descending target length so the first match is the most specific.
+    directives: Vec<(String, LevelFilter)>,
+}
+
+impl TargetFilter {
+    /// Parses `spec`, warning on stderr about directives that cannot be parsed.
+    pub fn parse(spec: &str) -> TargetFilter {
+        let (filter, invalid) = TargetFilter::parse_with_invalid(spec);
+        for directive in invalid {
+            eprintln!("betterstack_logger: ignoring invalid log filter directive `{directive}`");
+        }
+        filter
+    }
+
+    /// Parses `spec`, returning the directives that cannot be parsed along
+    /// with the filter of the others.
+    pub fn parse_with_invalid(spec: &str) -> (TargetFilter, Vec<String>) {
+        let mut filter = TargetFilter::default();
+        let mut invalid = Vec::new();
+        let mut any = false;
+
+        for directive in spec.split(',').map(str::trim).filter(|d| !d.is_empty()) {
+            any = true;
+            match directive.split_once('=') {
+                None => match LevelFilter::from_str(directive) {
+                    Ok(level) => filter.default = Some(level),
+                    Err(_) => filter.insert(directive, LevelFilter::Trace),
+                },
+                Some((target, level)) => match LevelFilter::from_str(level.trim()) {
+                    Ok(level) => filter.insert(target.trim(), level),
+                    Err(_) => invalid.push(directive.to_string()),
+                },
+            }
+        }
+        if !any {
+            filter.default = Some(LevelFilter::Trace);
+        }
+        (filter, invalid)
+    }
+
+    /// A filter with a level per target prefix, and `default` for targets
+    /// that match none of them.
+    pub fn from_levels(levels: HashMap<String, LevelFilter>, default: LevelFilter) -> TargetFilter {
+        let mut filter = TargetFilter {
+            default: Some(default),
+            directives: Vec::new(),
+        };
+        for (target, level) in levels {
+            filter.insert(&target, level);
+        }
+        filter
+    }
+
+    fn insert(&mut self, target: &str, level: LevelFilter) {
+        self.directives.retain(|(existing, _)| existing != target);
+        self.directives.push((target.to_string(), level));
+        self.directives
+            .sort_by_key(|(target, _)| std::cmp::Reverse(target.len()));
+    }
+
+    /// The most specific level configured for `target`, if any.
+    pub fn level_for(&self, target: &str) -> Option<LevelFilter> {
+        self.directives
+            .iter()
+            .find(|(prefix, _)| target.starts_with(prefix.as_str()))
+            .map(|(_, level)| *level)
+            .or(self.default)
+    }
+
+    pub fn enabled(&self, target: &str, level: Level) -> bool {
+        self.level_for(target).is_some_and(|max| level <= max)
+    }
+}
@@ -0,0 +1,1074 @@
+//! The HTTP sink and its request options, re-exported from
+//! [`sink`](crate::sink). Requires the `http` feature.
+
+use crate::diagnostics::{DiagnosticKind, DiagnosticsSink};
+use crate::logger::{AuthScheme, Counters};
+use crate::sink::{
+    serialize_batch, write_message, BatchEncoder, Casing, Envelope, Format, KeyStyle, LogMessage,
+    MissingLinePolicy, SendError, Sink,
+};
+use futures_util::future::BoxFuture;
+#[cfg(feature = "signing")]
+use hmac::Mac;
+use reqwest::header::{CONTENT_ENCODING, CONTENT_TYPE, DATE, RETRY_AFTER};
+use reqwest::{Client, Method, StatusCode};
+use serde_json::Value;
+use std::borrow::Cow;
+#[cfg(feature = "signing")]
+use std::fmt::Write;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc;
+
+/// Better Stack's limit for the size of a single request body.
+pub const DEFAULT_MAX_REQUEST_BYTES: usize = 10 * 1024 * 1024;
+
+/// Roughly how much of a streamed body is serialized at a time.
+const STREAM_CHUNK_BYTES: usize = 64 * 1024;
+
+/// A request body of the [`HttpSink`].
+enum Payload<'a> {
+    Buffered(Vec<u8>),
+    /// Serialized, and compressed, while it is being sent.
+    Streamed(&'a [LogMessage]),
+}
+
+/// A buffered request body as it is sent.
+struct EncodedBody<'a> {
+    body: Vec<u8>,
+    /// The `Content-Encoding`, if `body` is compressed.
+    encoding: Option<&'a str>,
+    /// Kept when compressed, in case the server rejects the encoding.
+    uncompressed: Option<Vec<u8>>,
+}
+
+/// How often the [`HttpSink`] compares the local clock to the server's.
+pub const CLOCK_CHECK_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// The default sink: POSTs each batch as a JSON array to a Better Stack
+/// ingest URL.
+pub struct HttpSink {
+    client: Client,
+    ingest_url: String,
+    method: Method,
+    source_token: String,
+    auth_scheme: AuthScheme,
+    compression: Compression,
+    compressor: Option<Arc<dyn Compressor>>,
+    compression_min_bytes: usize,
+    accepted_encodings: Option<Vec<String>>,
+    identity_fallback: AtomicBool,
+    max_request_bytes: usize,
+    max_messages_per_request: Option<usize>,
+    stop_after_failed_request: bool,
+    stream_bodies: bool,
+    format: Format,
+    envelope: Option<Envelope>,
+    keys: KeyStyle,
+    max_retries: u32,
+    retry_backoff: Duration,
+    retry_on: RetryCondition,
+    max_retry_duration: Option<Duration>,
+    idempotency_header: Option<String>,
+    #[cfg(feature = "signing")]
+    hmac_signing: Option<(HmacSha256, String)>,
+    clock_skew_threshold: Option<Duration>,
+    last_clock_check: Mutex<Option<Instant>>,
+    counters: Arc<Counters>,
+    diagnostics: DiagnosticsSink,
+    on_batch_sent: Option<BatchStatsCallback>,
+    batch_transform: Option<BatchTransform>,
+}
+
+pub(crate) type BatchStatsCallback = Arc<dyn Fn(&BatchStats) + Send + Sync>;
+pub(crate) type BatchTransform = Arc<dyn Fn(Vec<LogMessage>) -> Value + Send + Sync>;
+
+/// Size and timing of one request made by the [`HttpSink`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BatchStats {
+    /// Number of messages in the request.
+    pub messages: usize,
+    /// Size of the serialized JSON body.
+    pub serialized_bytes: usize,
+    /// Size of the body actually sent, if it was compressed.
+    pub compressed_bytes: Option<usize>,
+    /// Time from sending the request until the response (or error) arrived.
+    pub latency: Duration,
+    /// Whether the server accepted the batch.
+    pub success: bool,
+}
+
+impl HttpSink {
+    pub fn new(client: Client, ingest_url: String, source_token: String) -> HttpSink {
+        HttpSink {
+            client,
+            ingest_url,
+            method: Method::POST,
+            source_token,
+            auth_scheme: AuthScheme::default(),
+            compression: Compression::default(),
+            compressor: None,
+            compression_min_bytes: 0,
+            accepted_encodings: None,
+            identity_fallback: AtomicBool::new(false),
+            max_request_bytes: DEFAULT_MAX_REQUEST_BYTES,
+            max_messages_per_request: None,
+            stop_after_failed_request: false,
+            stream_bodies: false,
+            format: Format::default(),
+            envelope: None,
+            keys: KeyStyle::default(),
+            max_retries: 0,
+            retry_backoff: Duration::from_millis(500),
+            retry_on: RetryCondition::default(),
+            max_retry_duration: None,
+            idempotency_header: None,
+            #[cfg(feature = "signing")]
+            hmac_signing: None,
+            clock_skew_threshold: None,
+            last_clock_check: Mutex::new(None),
+            counters: Arc::default(),
+            diagnostics: DiagnosticsSink::default(),
+            on_batch_sent: None,
+            batch_transform: None,
+        }
+    }
+
+    /// The HTTP method of each request. Defaults to `POST`.
+    pub fn method(mut self, method: Method) -> Self {
+        self.method = method;
+        self
+    }
+
+    /// Append `path`, e.g. `/v1/logs`, to the ingest URL, with a single `/`
+    /// between them.
+    pub fn path(mut self, path: &str) -> Self {
+        self.ingest_url = join_path(&self.ingest_url, path);
+        self
+    }
+
+    /// How the source token is sent. Defaults to [`AuthScheme::Bearer`].
+    pub fn auth_scheme(mut self, scheme: AuthScheme) -> Self {
+        self.auth_scheme = scheme;
+        self
+    }
+
+    /// Compress request bodies. Off by default.
+    pub fn compression(mut self, compression: Compression) -> Self {
+        self.compression = compression;
+        self
+    }
+
+    /// Compress request bodies with a custom implementation, which takes
+    /// precedence over [`compression`](Self::compression).
+    pub fn compressor(self, compressor: impl Compressor + 'static) -> Self {
+        self.compressor_arc(Some(Arc::new(compressor)))
+    }
+
+    pub(crate) fn compressor_arc(mut self, compressor: Option<Arc<dyn Compressor>>) -> Self {
+        self.compressor = compressor;
+        self
+    }
+
+    /// Only compress bodies larger than this many bytes. Defaults to 0.
+    pub fn compression_min_bytes(mut self, min_bytes: usize) -> Self {
+        self.compression_min_bytes = min_bytes;
+        self
+    }
+
+    /// The `Content-Encoding`s the server accepts. Bodies are sent
+    /// uncompressed if the configured compression is not among them. By
+    /// default every encoding is assumed to work until the server answers
+    /// `415 Unsupported Media Type`, after which compression is turned off.
+    pub fn accepted_encodings<S: Into<String>>(
+        mut self,
+        encodings: impl IntoIterator<Item = S>,
+    ) -> Self {
+        self.accepted_encodings = Some(encodings.into_iter().map(Into::into).collect());
+        self
+    }
+
+    /// Split batches so that no request body exceeds this many bytes before
+    /// compression. Defaults to [`DEFAULT_MAX_REQUEST_BYTES`].
+    pub fn max_request_bytes(mut self, max_bytes: usize) -> Self {
+        self.max_request_bytes = max_bytes;
+        self
+    }
+
+    /// Split batches so that no request carries more than this many messages,
+    /// on top of the [`max_request_bytes`](Self::max_request_bytes) limit.
+    /// Values below 1 are treated as 1. Off by default.
+    pub fn max_messages_per_request(mut self, max: usize) -> Self {
+        self.max_messages_per_request = Some(max.max(1));
+        self
+    }
+
+    /// When a batch is split into several requests, send none of the requests
+    /// after one that failed, so that the undelivered messages are the tail
+    /// of the batch, see [`SendError::delivered`]. Off by default, which
+    /// sends the rest of the batch anyway.
+    pub fn stop_after_failed_request(mut self, stop: bool) -> Self {
+        self.stop_after_failed_request = stop;
+        self
+    }
+
+    /// Serialize and compress each request body while it is being sent in
+    /// chunks, instead of holding the whole body in memory. Bodies are then
+    /// compressed regardless of `compression_min_bytes`. Has no effect with a
+    /// custom [`Compressor`]. Off by default.
+    ///
+    /// Sending a 21.9 MB batch with gzip peaks at about 68 MiB of heap when
+    /// buffered and 0.5 MiB when streamed, see `benches/stream_memory.rs`.
+    pub fn stream_bodies(mut self, stream: bool) -> Self {
+        self.stream_bodies = stream;
+        self
+    }
+
+    /// The body format. Defaults to [`Format::Json`].
+    pub fn format(mut self, format: Format) -> Self {
+        self.format = format;
+        self
+    }
+
+    /// Wrap the JSON array in an object, see [`Envelope`].
+    pub fn envelope(mut self, envelope: Envelope) -> Self {
+        self.envelope = Some(envelope);
+        self
+    }
+
+    /// The key of the timestamp in each message. Defaults to `timestamp`.
+    pub fn timestamp_field_name(mut self, name: impl Into<String>) -> Self {
+        self.keys.timestamp = Some(name.into());
+        self
+    }
+
+    /// The casing of every key in each message, including its fields.
+    /// Defaults to [`Casing::Snake`].
+    pub fn key_casing(mut self, casing: Casing) -> Self {
+        self.keys.casing = casing;
+        self
+    }
+
+    /// Serialize the level as an object with its name and syslog severity.
+    /// Off by default.
+    pub fn level_as_object(mut self, enabled: bool) -> Self {
+        self.keys.level_as_object = enabled;
+        self
+    }
+
+    /// How messages without a source line serialize `line`. Defaults to
+    /// [`MissingLinePolicy::Null`].
+    pub fn missing_line_policy(mut self, policy: MissingLinePolicy) -> Self {
+        self.keys.missing_line = policy;
+        self
+    }
+
+    /// Retry a failed request up to `max_retries` times. Defaults to 0.
+    pub fn max_retries(mut self, max_retries: u32) -> Self {
+        self.max_retries = max_retries;
+        self
+    }
+
+    /// The delay before the first retry, doubled for each further one, unless
+    /// the response has a `Retry-After` header in seconds. Defaults to 500ms.
+    pub fn retry_backoff(mut self, backoff: Duration) -> Self {
+        self.retry_backoff = backoff;
+        self
+    }
+
+    /// Which failures are retried. Defaults to [`RetryCondition::Safe`].
+    pub fn retry_on(mut self, condition: RetryCondition) -> Self {
+        self.retry_on = condition;
+        self
+    }
+
+    /// Stop retrying once the next delay would take the time since the first
+    /// attempt past `budget`. Unlimited by default.
+    pub fn max_retry_duration(mut self, budget: Duration) -> Self {
+        self.max_retry_duration = Some(budget);
+        self
+    }
+
+    /// Send a random key, the same for all attempts at a request, in
+    /// `header` (e.g. `Idempotency-Key`). Off by default.
+    pub fn idempotency_header(mut self, header: impl Into<String>) -> Self {
+        self.idempotency_header = Some(header.into());
+        self
+    }
+
+    /// Send the HMAC-SHA256 of each request body under `key`, as lowercase
+    /// hex, in `header`. The signature covers the bytes as sent, after
+    /// compression, so bodies are not streamed. Off by default.
+    #[cfg(feature = "signing")]
+    pub fn hmac_signing(mut self, key: &[u8], header: impl Into<String>) -> Self {
+        let key = HmacSha256::new_from_slice(key).expect("HMAC accepts keys of any length");
+        self.hmac_signing = Some((key, header.into()));
+        self
+    }
+
+    /// Warn on stderr when the local clock differs from the `Date` header of
+    /// the server's responses by more than `threshold`. Checked on the first
+    /// response and then at most once per [`CLOCK_CHECK_INTERVAL`]. Off by
+    /// default.
+    pub fn warn_on_clock_skew(mut self, threshold: Duration) -> Self {
+        self.clock_skew_threshold = Some(threshold);
+        self
+    }
+
+    /// Call `callback` with the size and latency of every request.
+    pub fn on_batch_sent(mut self, callback: impl Fn(&BatchStats) + Send + Sync + 'static) -> Self {
+        self.on_batch_sent = Some(Arc::new(callback));
+        self
+    }
+
+    pub(crate) fn on_batch_sent_arc(mut self, callback: Option<BatchStatsCallback>) -> Self {
+        self.on_batch_sent = callback;
+        self
+    }
+
+    /// Send the JSON value `transform` returns for each batch as the request
+    /// body, instead of serializing the messages in the configured format.
+    /// The body is sent as `application/json` and compressed as configured,
+    /// but only split at `max_messages_per_request`, not at
+    /// `max_request_bytes`.
+    pub fn batch_transform(
+        self,
+        transform: impl Fn(Vec<LogMessage>) -> Value + Send + Sync + 'static,
+    ) -> Self {
+        self.batch_transform_arc(Some(Arc::new(transform)))
+    }
+
+    pub(crate) fn batch_transform_arc(mut self, transform: Option<BatchTransform>) -> Self {
+        self.batch_transform = transform;
+        self
+    }
+
+    /// Where problems like a rejected compression or clock skew are reported.
+    /// Defaults to stderr.
+    pub fn diagnostics_sink(mut self, diagnostics: DiagnosticsSink) -> Self {
+        self.diagnostics = diagnostics;
+        self
+    }
+
+    pub(crate) fn counters(mut self, counters: Arc<Counters>) -> Self {
+        self.counters = counters;
+        self
+    }
+
+    /// The `Content-Encoding` to compress with, unless the server does not
+    /// accept it.
+    fn encoding(&self) -> Option<&str> {
+        let encoding = match &self.compressor {
+            Some(compressor) => compressor.encoding(),
+            None => self.compression.encoding()?,
+        };
+        let accepted = match &self.accepted_encodings {
+            Some(accepted) => accepted
+                .iter()
+                .any(|name| name.eq_ignore_ascii_case(encoding)),
+            None => true,
+        };
+        (accepted && !self.identity_fallback.load(Ordering::Relaxed)).then_some(encoding)
+    }
+
+    /// Warns if the response's `Date` is too far from the local clock.
+    fn check_clock(&self, response: &reqwest::Response) {
+        let Some(threshold) = self.clock_skew_threshold else {
+            return;
+        };
+        {
+            let mut last_check = self.last_clock_check.lock().unwrap();
+            if last_check.is_some_and(|last| last.elapsed() < CLOCK_CHECK_INTERVAL) {
+                return;
+            }
+            *last_check = Some(Instant::now());
+        }
+        let Some(server_time) = response
+            .headers()
+            .get(DATE)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| chrono::DateTime::parse_from_rfc2822(value).ok())
+        else {
+            return;
+        };
+
+        let skew = chrono::Utc::now().signed_duration_since(server_time);
+        let (direction, skew) = if skew < chrono::TimeDelta::zero() {
+            ("behind", -skew)
+        } else {
+            ("ahead of", skew)
+        };
+        let skew = skew.to_std().unwrap_or_default();
+        if skew > threshold {
+            self.diagnostics.report(
+                DiagnosticKind::ClockSkew,
+                format!(
+                    "the local clock is {}s {direction} the ingest server's, log timestamps will be off",
+                    skew.as_secs()
+                ),
+            );
+        }
+    }
+
+    /// Serializes `batch` into the body of one request as it is sent, in the
+    /// configured format and compressed if the compression applies to it.
+    /// Does not split bodies over the request size limit. Requests with
+    /// [`stream_bodies`](Self::stream_bodies) carry the same messages, but
+    /// are compressed as they are written, which gives different bytes.
+    ///
+    /// ```
+    /// use betterstack_logger::sink::{Format, HttpSink};
+    ///
+    /// let sink = HttpSink::new(reqwest::Client::new(), String::new(), String::new())
+    ///     .format(Format::Ndjson);
+    /// assert!(sink.serialize_batch(&[]).unwrap().is_empty());
+    /// ```
+    pub fn serialize_batch(&self, batch: &[LogMessage]) -> std::io::Result<Vec<u8>> {
+        Ok(self.encode_body(self.serialize(batch))?.body)
+    }
+
+    /// Compresses a serialized body if the compression applies to it, as
+    /// both [`serialize_batch`](Self::serialize_batch) and the requests do.
+    fn encode_body(&self, body: Vec<u8>) -> std::io::Result<EncodedBody<'_>> {
+        match self.encoding() {
+            Some(encoding) if body.len() > self.compression_min_bytes => Ok(EncodedBody {
+                body: self.compress(&body)?,
+                encoding: Some(encoding),
+                uncompressed: Some(body),
+            }),
+            _ => Ok(EncodedBody {
+                body,
+                encoding: None,
+                uncompressed: None,
+            }),
+        }
+    }
+
+    fn compress(&self, body: &[u8]) -> std::io::Result<Vec<u8>> {
+        match &self.compressor {
+            Some(compressor) => compressor.compress(body),
+            None => self.compression.compress(body),
+        }
+    }
+
+    fn serialize(&self, batch: &[LogMessage]) -> Vec<u8> {
+        if let Some(transform) = &self.batch_transform {
+            return transform(batch.to_vec()).to_string().into_bytes();
+        }
+        serialize_batch(
+            batch,
+            self.format,
+            self.envelope.as_ref(),
+            &self.keys,
+            &self.counters.serialization_errors,
+            &self.diagnostics,
+        )
+    }
+
+    async fn send_batch(&self, batch: &[LogMessage]) -> Result<(), SendError> {
+        let Some(max) = self
+            .max_messages_per_request
+            .filter(|&max| batch.len() > max)
+        else {
+            return self.send_messages(batch).await;
+        };
+        let mut results = ChunkResults::default();
+        for chunk in batch.chunks(max) {
+            if !results.record(chunk.len(), self.send_messages(chunk).await, self) {
+                break;
+            }
+        }
+        results.finish()
+    }
+
+    /// Sends messages within the message count limit, split further at
+    /// `max_request_bytes`.
+    async fn send_messages(&self, batch: &[LogMessage]) -> Result<(), SendError> {
+        if self.batch_transform.is_some() {
+            let body = self.serialize(batch);
+            return self.send_body(batch.len(), Payload::Buffered(body)).await;
+        }
+        if self.stream_bodies && self.compressor.is_none() && !self.signs() {
+            let mut results = ChunkResults::default();
+            for chunk in self.split(batch) {
+                let result = self.send_body(chunk.len(), Payload::Streamed(&chunk)).await;
+                if !results.record(chunk.len(), result, self) {
+                    break;
+                }
+            }
+            return results.finish();
+        }
+
+        let body = self.serialize(batch);
+        if body.len() <= self.max_request_bytes {
+            return self.send_body(batch.len(), Payload::Buffered(body)).await;
+        }
+
+        let mut results = ChunkResults::default();
+        for chunk in self.split(batch) {
+            let body = self.serialize(&chunk);
+            let result = self.send_body(chunk.len(), Payload::Buffered(body)).await;
+            if !results.record(chunk.len(), result, self) {
+                break;
+            }
+        }
+        results.finish()
+    }
+
+    /// Serializes `batch` into `sender` in chunks, gzip-compressed if `gzip`.
+    /// Stops early when the request is over. Returns the serialized size and
+    /// the size sent.
+    async fn stream_body(
+        &self,
+        batch: &[LogMessage],
+        gzip: bool,
+        serialization_errors: Option<(&AtomicU64, &DiagnosticsSink)>,
+        sender: mpsc::Sender<std::io::Result<Vec<u8>>>,
+    ) -> (usize, usize) {
+        let mut encoder = BatchEncoder::new(
+            self.format,
+            self.envelope.as_ref(),
+            &self.keys,
+            serialization_errors,
+        );
+        let mut writer = StreamWriter::new(gzip);
+        let mut plain = Vec::new();
+        let (mut serialized, mut sent) = (0, 0);
+        let mut messages = batch.iter();
+
+        encoder.start(&mut plain);
+        loop {
+            let message = messages.next();
+            match message {
+                Some(message) => encoder.message(&mut plain, message),
+                None => encoder.finish(&mut plain),
+            }
+            let last = message.is_none();
+            if !last && plain.len() < STREAM_CHUNK_BYTES {
+                continue;
+            }
+
+            serialized += plain.len();
+            let chunk = writer.write(&plain).and_then(|()| {
+                if last {
+                    writer.finish()
+                } else {
+                    Ok(writer.take())
+                }
+            });
+            plain.clear();
+            let chunk = match chunk {
+                Ok(chunk) => chunk,
+                Err(error) => {
+                    // Fails the request.
+                    let _ = sender.send(Err(error)).await;
+                    break;
+                }
+            };
+            sent += chunk.len();
+            if !chunk.is_empty() && sender.send(Ok(chunk)).await.is_err() {
+                break;
+            }
+            if last {
+                break;
+            }
+        }
+        (serialized, sent)
+    }
+
+    /// Splits a batch that is too large for one request into chunks that fit
+    /// within `max_request_bytes`.
+    fn split<'a>(&self, batch: &'a [LogMessage]) -> Vec<Cow<'a, [LogMessage]>> {
+        let overhead = self.serialize(&[]);
+        let limit = self.max_request_bytes.saturating_sub(overhead.len());
+
+        let mut chunks = Vec::new();
+        let mut start = 0;
+        let mut size = 0;
+        let mut scratch = Vec::new();
+        for (i, message) in batch.iter().enumerate() {
+            scratch.clear();
+            // Messages that fail here are skipped by `serialize_batch` later.
+            let _ = write_message(&mut scratch, message, self.format, &self.keys);
+            // Plus the separating comma or newline.
+            let len = scratch.len() + 1;
+
+            if len > limit {
+                if start < i {
+                    chunks.push(Cow::Borrowed(&batch[start..i]));
+                }
+                if let Some(message) = truncate_message(message, len - limit, &self.diagnostics) {
+                    chunks.push(Cow::Owned(vec![message]));
+                }
+                start = i + 1;
+                size = 0;
+                continue;
+            }
+            if size + len > limit {
+                chunks.push(Cow::Borrowed(&batch[start..i]));
+                start = i;
+                size = 0;
+            }
+            size += len;
+        }
+        if start < batch.len() {
+            chunks.push(Cow::Borrowed(&batch[start..]));
+        }
+        chunks
+    }
+
+    /// Whether request bodies are signed, which needs them buffered.
+    fn signs(&self) -> bool {
+        #[cfg(feature = "signing")]
+        return self.hmac_signing.is_some();
+        #[cfg(not(feature = "signing"))]
+        false
+    }
+
+    fn content_type(&self) -> &'static str {
+        match self.batch_transform {
+            Some(_) => Format::Json.content_type(),
+            None => self.format.content_type(),
+        }
+    }
+
+    fn request(
+        &self,
+        body: impl Into<reqwest::Body>,
+        encoding: Option<&str>,
+        idempotency_key: Option<&str>,
+    ) -> reqwest::RequestBuilder {
+        let mut request = self
+            .client
+            .request(self.method.clone(), &self.ingest_url)
+            .header(CONTENT_TYPE, self.content_type())
+            .body(body);
+        if let Some(encoding) = encoding {
+            request = request.header(CONTENT_ENCODING, encoding);
+        }
+        if let (Some(header), Some(key)) = (&self.idempotency_header, idempotency_key) {
+            request = request.header(header, key);
+        }
+        match &self.auth_scheme {
+            AuthScheme::Bearer => request.bearer_auth(&self.source_token),
+            AuthScheme::QueryParam { name } => request.query(&[(name, &self.source_token)]),
+        }
+    }
+
+    async fn send_body(&self, messages: usize, body: Payload<'_>) -> Result<(), SendError> {
+        let mut serialized_bytes = match &body {
+            Payload::Buffered(body) => body.len(),
+            Payload::Streamed(_) => 0,
+        };
+        // The uncompressed body is kept in case the server rejects the encoding.
+        let (mut payload, mut encoding, mut uncompressed) = match body {
+            Payload::Buffered(body) => {
+                let encoded = self.encode_body(body).map_err(|error| SendError {
+                    status: None,
+                    attempts: 0,
+                    batch_size: messages,
+                    delivered: 0,
+                    reason: format!("failed to compress the request body: {error}"),
+                })?;
+                (
+                    Payload::Buffered(encoded.body),
+                    encoded.encoding,
+                    encoded.uncompressed.map(Payload::Buffered),
+                )
+            }
+            Payload::Streamed(batch) => {
+                let encoding = self.encoding();
+                (
+                    Payload::Streamed(batch),
+                    encoding,
+                    encoding.map(|_| Payload::Streamed(batch)),
+                )
+            }
+        };
+        // The same key on every attempt lets the server drop duplicates.
+        let idempotency_key = self
+            .idempotency_header
+            .as_ref()
+            .map(|_| uuid::Uuid::new_v4().to_string());
+
+        let mut attempts = 0;
+        let mut stale_retried = false;
+        let mut resent_uncompressed = false;
+        let mut backoff = self.retry_backoff;
+        let first_attempt = Instant::now();
+        loop {
+            attempts += 1;
+            let started = Instant::now();
+            let (result, compressed_bytes) = match &payload {
+                Payload::Buffered(body) => {
+                    #[allow(unused_mut)]
+                    let mut request =
+                        self.request(body.clone(), encoding, idempotency_key.as_deref());
+                    #[cfg(feature = "signing")]
+                    if let Some((key, header)) = &self.hmac_signing {
+                        request = request.header(header, hmac_hex(key, body));
+                    }
+                    (request.send().await, encoding.map(|_| body.len()))
+                }
+                Payload::Streamed(batch) => {
+                    let (sender, receiver) = mpsc::channel(2);
+                    let body = reqwest::Body::wrap_stream(futures_util::stream::unfold(
+                        receiver,
+                        |mut receiver| async move {
+                            let chunk = receiver.recv().await?;
+                            Some((chunk, receiver))
+                        },
+                    ));
+                    let request = self.request(body, encoding, idempotency_key.as_deref());
+                    // Messages that fail to serialize are only reported once.
+                    let errors = (attempts == 1)
+                        .then_some((&self.counters.serialization_errors, &self.diagnostics));
+                    let gzip = encoding.is_some();
+                    let (result, (serialized, sent)) = tokio::join!(
+                        request.send(),
+                        self.stream_body(batch, gzip, errors, sender)
+                    );
+                    serialized_bytes = serialized;
+                    (result, encoding.map(|_| sent))
+                }
+            };
+            let retry_after = result.as_ref().ok().and_then(retry_after);
+            if let Ok(response) = &result {
+                self.check_clock(response);
+            }
+            let result = result.and_then(|response| response.error_for_status());
+
+            if let Some(callback) = &self.on_batch_sent {
+                callback(&BatchStats {
+                    messages,
+                    serialized_bytes,
+                    compressed_bytes,
+                    latency: started.elapsed(),
+                    success: result.is_ok(),
+                });
+            }
+            let error = match result {
+                Ok(_) => return Ok(()),
+                Err(error) => error,
+            };
+            if error.status() == Some(StatusCode::UNSUPPORTED_MEDIA_TYPE) {
+                if let Some(body) = uncompressed.take() {
+                    if !self.identity_fallback.swap(true, Ordering::Relaxed) {
+                        self.diagnostics.report(
+                            DiagnosticKind::CompressionRejected,
+                            "the server rejected compressed requests, sending them uncompressed"
+                                .to_string(),
+                        );
+                    }
+                    (payload, encoding) = (body, None);
+                    resent_uncompressed = true;
+                    continue;
+                }
+            }
+            // A pooled connection that was dropped while idle fails the first
+            // request on it. It is not reused, so the retry gets a fresh one.
+            if !stale_retried && is_stale_connection(&error) {
+                stale_retried = true;
+                continue;
+            }
+            let delay = retry_after.unwrap_or(backoff);
+            let within_budget = self
+                .max_retry_duration
+                .is_none_or(|budget| first_attempt.elapsed() + delay <= budget);
+            // Neither resend is a retry of a failure, so both leave the
+            // retries to `max_retries`.
+            let retries = attempts - u32::from(stale_retried) - u32::from(resent_uncompressed);
+            if retries <= self.max_retries && within_budget && self.retry_on.should_retry(&error) {
+                tokio::time::sleep(delay).await;
+                backoff *= 2;
+                continue;
+            }
+            return Err(SendError {
+                status: error.status(),
+                attempts,
+                batch_size: messages,
+                delivered: 0,
+                reason: error.to_string(),
+            });
+        }
+    }
+}
+
+/// The outcome of a batch sent in several requests, in order: the first
+/// error, if any, and the messages delivered before it.
+#[derive(Default)]
+struct ChunkResults {
+    delivered: usize,
+    error: Option<SendError>,
+}
+
+impl ChunkResults {
+    /// Records the result of the next request of `len` messages, and returns
+    /// whether to send the rest.
+    fn record(&mut self, len: usize, result: Result<(), SendError>, sink: &HttpSink) -> bool {
+        match result {
+            Ok(()) => {
+                if self.error.is_none() {
+                    self.delivered += len;
+                }
+                true
+            }
+            Err(mut error) => {
+                if self.error.is_none() {
+                    error.delivered += self.delivered;
+                    self.error = Some(error);
+                }
+                !sink.stop_after_failed_request
+            }
+        }
+    }
+
+    fn finish(self) -> Result<(), SendError> {
+        self.error.map_or(Ok(()), Err)
+    }
+}
+
+impl Sink for HttpSink {
+    fn send<'a>(&'a self, batch: &'a [LogMessage]) -> BoxFuture<'a, Result<(), SendError>> {
+        Box::pin(self.send_batch(batch))
+    }
+}
+
+/// The delay requested by a `Retry-After` header in seconds, if any.
+fn retry_after(response: &reqwest::Response) -> Option<Duration> {
+    let value = response.headers().get(RETRY_AFTER)?.to_str().ok()?;
+    value.trim().parse().ok().map(Duration::from_secs)
+}
+
+#[cfg(feature = "signing")]
+type HmacSha256 = hmac::Hmac<sha2::Sha256>;
+
+/// The HMAC of `body` under `key`, as lowercase hex.
+#[cfg(feature = "signing")]
+fn hmac_hex(key: &HmacSha256, body: &[u8]) -> String {
+    let tag = key.clone().chain_update(body).finalize().into_bytes();
+    let mut hex = String::with_capacity(tag.len() * 2);
+    for byte in tag {
+        let _ = write!(hex, "{byte:02x}");
+    }
+    hex
+}
+
+/// Whether the request failed because the connection was closed or reset
+/// under it, as happens to idle connections dropped by a load balancer.
+fn is_stale_connection(error: &reqwest::Error) -> bool {
+    if error.is_timeout() || error.status().is_some() {
+        return false;
+    }
+    let mut source = std::error::Error::source(error);
+    while let Some(error) = source {
+        if let Some(error) = error.downcast_ref::<hyper::Error>() {
+            if error.is_incomplete_message() || error.is_canceled() {
+                return true;
+            }
+        }
+        if let Some(error) = error.downcast_ref::<std::io::Error>() {
+            use std::io::ErrorKind;
+            if matches!(
+                error.kind(),
+                ErrorKind::ConnectionReset | ErrorKind::ConnectionAborted | ErrorKind::BrokenPipe
+            ) {
+                return true;
+            }
+        }
+        source = error.source();
+    }
+    false
+}
+
+/// Joins `path` onto `base` with exactly one `/` between them.
+pub(crate) fn join_path(base: &str, path: &str) -> String {
+    let path = path.trim_start_matches('/');
+    if path.is_empty() {
+        return base.to_string();
+    }
+    format!("{}/{path}", base.trim_end_matches('/'))
+}
+
+/// The failures after which the [`HttpSink`] retries a request.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum RetryCondition {
+    /// Connection failures, `429 Too Many Requests` and `5xx` responses.
+    #[default]
+    Safe,
+    /// Timeouts as well. The server may have received the batch before the
+    /// timeout, so retrying can duplicate logs unless it honors the
+    /// idempotency key.
+    SafeAndTimeouts,
+}
+
+impl RetryCondition {
+    fn should_retry(self, error: &reqwest::Error) -> bool {
+        if error.is_connect() {
+            return true;
+        }
+        if let Some(status) = error.status() {
+            return status == StatusCode::TOO_MANY_REQUESTS || status.is_server_error();
+        }
+        error.is_timeout() && self == RetryCondition::SafeAndTimeouts
+    }
+}
+
+/// A custom compression of request bodies, e.g. with a faster crate than
+/// the built-in `flate2`. Install it with `HttpSink::compressor` or
+/// `BetterStackAppenderBuilder::with_compressor`.
+pub trait Compressor: Send + Sync {
+    /// The `Content-Encoding` header value, e.g. `gzip`.
+    fn encoding(&self) -> &str;
+
+    fn compress(&self, body: &[u8]) -> std::io::Result<Vec<u8>>;
+}
+
+/// Compression applied to request bodies by the [`HttpSink`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Compression {
+    #[default]
+    None,
+    /// Requires the `gzip` feature.
+    #[cfg(feature = "gzip")]
+    Gzip,
+}
+
+impl Compression {
+    /// The `Content-Encoding` header value, or `None` for no compression.
+    pub(crate) fn encoding(self) -> Option<&'static str> {
+        match self {
+            Compression::None => None,
+            #[cfg(feature = "gzip")]
+            Compression::Gzip => Some("gzip"),
+        }
+    }
+
+    fn compress(self, body: &[u8]) -> std::io::Result<Vec<u8>> {
+        match self {
+            Compression::None => Ok(body.to_vec()),
+            #[cfg(feature = "gzip")]
+            Compression::Gzip => {
+                use std::io::Write;
+
+                let mut encoder =
+                    flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+                encoder.write_all(body)?;
+                encoder.finish()
+            }
+        }
+    }
+}
+
+/// Shortens the message so that its serialized size drops by at least
+/// `excess` bytes, marking it with `message_truncated`. Returns `None`, after
+/// reporting it, if the other fields alone are already too large.
+fn truncate_message(
+    message: &LogMessage,
+    excess: usize,
+    diagnostics: &DiagnosticsSink,
+) -> Option<LogMessage> {
+    // Removing a character shrinks the output by at least its encoded length;
+    // the slack covers the marker field.
+    let excess = excess + r#","message_truncated":true"#.len();
+    if excess > message.message.len() {
+        diagnostics.report(
+            DiagnosticKind::MessageTooLarge,
+            "dropping log message that exceeds the maximum request size".to_string(),
+        );
+        return None;
+    }
+
+    let mut end = message.message.len() - excess;
+    while !message.message.is_char_boundary(end) {
+        end -= 1;
+    }
+    let mut message = message.clone();
+    message.message = message.message[..end].to_string().into();
+    message
+        .fields
+        .insert("message_truncated".to_string(), Value::Bool(true));
+    Some(message)
+}
+
+/// The output of a streamed body, compressed or not.
+enum StreamWriter {
+    Plain(Vec<u8>),
+    #[cfg(feature = "gzip")]
+    Gzip(flate2::write::GzEncoder<Vec<u8>>),
+}
+
+impl StreamWriter {
+    fn new(gzip: bool) -> StreamWriter {
+        #[cfg(feature = "gzip")]
+        if gzip {
+            return StreamWriter::Gzip(flate2::write::GzEncoder::new(
+                Vec::new(),
+                flate2::Compression::default(),
+            ));
+        }
+        #[cfg(not(feature = "gzip"))]
+        let _ = gzip;
+        StreamWriter::Plain(Vec::new())
+    }
+
+    fn write(&mut self, data: &[u8]) -> std::io::Result<()> {
+        use std::io::Write;
+
+        match self {
+            StreamWriter::Plain(out) => out.write_all(data),
+            #[cfg(feature = "gzip")]
+            StreamWriter::Gzip(encoder) => encoder.write_all(data),
+        }
+    }
+
+    /// Takes the output written so far.
+    fn take(&mut self) -> Vec<u8> {
+        match self {
+            StreamWriter::Plain(out) => std::mem::take(out),
+            #[cfg(feature = "gzip")]
+            StreamWriter::Gzip(encoder) => std::mem::take(encoder.get_mut()),
+        }
+    }
+
+    /// Ends the stream and takes the rest of the output.
+    fn finish(&mut self) -> std::io::Result<Vec<u8>> {
+        #[cfg(feature = "gzip")]
+        if let StreamWriter::Gzip(encoder) = self {
+            encoder.try_finish()?;
+        }
+        Ok(self.take())
+    }
+}
+
+#[cfg(all(test, feature = "signing"))]
+mod tests {
+    use super::*;
+
+    /// Test cases 1, 2 and 6 of RFC 4231.
+    #[test]
+    fn hmac_hex_matches_rfc_4231() {
+        let cases: [(&[u8], &[u8], &str); 3] = [
+            (
+                &[0x0b; 20],
+                b"Hi There",
+                "b0344c61d8db38535ca8afceaf0bf12b881dc200c9833da726e9376c2e32cff7",
+            ),
+            (
+                b"Jefe",
+                b"what do ya want for nothing?",
+                "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843",
+            ),
+            (
+                &[0xaa; 131],
+                b"Test Using Larger Than Block-Size Key - Hash Key First",
+                "60e431591ee0b67f0d8a26aacbf5b77f8e0bc6213728c5140546040f0ee37f54",
+            ),
+        ];
+        for (key, data, expected) in cases {
+            let key = HmacSha256::new_from_slice(key).unwrap();
+            assert_eq!(hmac_hex(&key, data), expected);
+        }
+    }
+}
@@ -0,0 +1,94 @@
+//! Sends the logs of each AWS Lambda invocation before the invocation
+//! returns. The execution environment is frozen between invocations, so the
+//! flush interval is of no use there, and messages still buffered when it is
+//! shut down are lost.
+//!
+//! With the [`lambda_runtime`](https://docs.rs/lambda_runtime) crate, wrap
+//! the handler service in a [`FlushAfterInvocation`]:
+//!
+//! ```ignore
+//! use betterstack_logger::lambda::FlushAfterInvocation;
+//! use betterstack_logger::logger::BetterStackAppender;
+//! use lambda_runtime::{service_fn, Error, LambdaEvent};
+//! use serde_json::Value;
+//!
+//! async fn handler(event: LambdaEvent<Value>) -> Result<Value, Error> {
+//!     log::info!("handling request {}", event.context.request_id);
+//!     Ok(event.payload)
+//! }
+//!
+//! #[tokio::main]
+//! async fn main() -> Result<(), Error> {
+//!     let appender = BetterStackAppender::builder(ingest_url(), source_token()).build();
+//!     let drain = appender.drain_handle();
+//!     // Hand `appender` to log4rs as usual.
+//!     lambda_runtime::run(FlushAfterInvocation::new(service_fn(handler), drain)).await
+//! }
+//! ```
+
+use crate::logger::DrainHandle;
+use futures_util::future::BoxFuture;
+use std::task::{Context, Poll};
+use tower_layer::Layer;
+use tower_service::Service;
+
+/// A service that drains the appender after each call of the wrapped
+/// handler, whether it succeeded or failed, and only then returns its result.
+#[derive(Clone)]
+pub struct FlushAfterInvocation<S> {
+    inner: S,
+    drain: DrainHandle,
+}
+
+impl<S> FlushAfterInvocation<S> {
+    pub fn new(inner: S, drain: DrainHandle) -> FlushAfterInvocation<S> {
+        FlushAfterInvocation { inner, drain }
+    }
+}
+
+impl<S, Request> Service<Request> for FlushAfterInvocation<S>
+where
+    S: Service<Request>,
+    S::Future: Send + 'static,
+    S::Response: Send,
+    S::Error: Send,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = BoxFuture<'static, Result<S::Response, S::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), S::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, request: Request) -> Self::Future {
+        let response = self.inner.call(request);
+        let drain = self.drain.clone();
+        Box::pin(async move {
+            let response = response.await;
+            drain.drain().await;
+            response
+        })
+    }
+}
+
+/// Wraps services in a [`FlushAfterInvocation`], for
+/// `lambda_runtime::Runtime::layer`.
+#[derive(Clone)]
+pub struct FlushAfterInvocationLayer {
+    drain: DrainHandle,
+}
+
+impl FlushAfterInvocationLayer {
+    pub fn new(drain: DrainHandle) -> FlushAfterInvocationLayer {
+        FlushAfterInvocationLayer { drain }
+    }
+}
+
+impl<S> Layer<S> for FlushAfterInvocationLayer {
+    type Service = FlushAfterInvocation<S>;
+
+    fn layer(&self, inner: S) -> FlushAfterInvocation<S> {
+        FlushAfterInvocation::new(inner, self.drain.clone())
+    }
+}
@@ -0,0 +1,199 @@
+use crate::context::{self, CORRELATION_FIELD, SCOPE_FIELD};
+use crate::diagnostics;
+use crate::logger::{current_timestamp, limit_message, Gate, LogMessage, MessageOptions, Pipeline};
+use serde_json::{Map, Value};
+use std::borrow::Cow;
+use std::fmt;
+use std::sync::Arc;
+use std::time::Instant;
+use tracing::field::{Field, Visit};
+use tracing::span::{Attributes, Id, Record};
+use tracing::{Event, Subscriber};
+use tracing_subscriber::layer::Context;
+use tracing_subscriber::registry::LookupSpan;
+use tracing_subscriber::Layer;
+
+/// A `tracing-subscriber` layer that feeds events into the same uploader as
+/// the [`BetterStackAppender`](crate::logger::BetterStackAppender) it was
+/// created from, see `BetterStackAppender::tracing_layer`.
+///
+/// Each message carries the ancestor span chain of its event as a `spans`
+/// array of `{name, fields}` objects, ordered from the root to the innermost
+/// span.
+///
+/// Events go through the appender's target filter, sampling and always-ship
+/// levels, and get its timestamps, static fields, control-character escaping
+/// and message length limits, and the correlation id and scope path of the
+/// enclosing scopes. The options that read `log` records, like the encoder,
+/// `timestamp_from_kv`, `max_fields` and thread info, and the audit and
+/// tenant routes do not apply to them.
+pub struct BetterStackLayer {
+    pipeline: Arc<Pipeline>,
+    options: MessageOptions,
+    gate: Arc<Gate>,
+    max_span_depth: usize,
+}
+
+impl BetterStackLayer {
+    pub(crate) fn new(pipeline: Arc<Pipeline>, options: MessageOptions, gate: Arc<Gate>) -> Self {
+        BetterStackLayer {
+            pipeline,
+            options,
+            gate,
+            max_span_depth: 16,
+        }
+    }
+
+    /// Keep at most this many of the innermost spans in the `spans` array.
+    /// Defaults to 16.
+    pub fn max_span_depth(mut self, depth: usize) -> Self {
+        self.max_span_depth = depth;
+        self
+    }
+}
+
+/// The recorded fields of a span, stored in its extensions.
+struct SpanFields(Map<String, Value>);
+
+impl<S> Layer<S> for BetterStackLayer
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn on_new_span(&self, attrs: &Attributes<'_>, id: &Id, ctx: Context<'_, S>) {
+        let Some(span) = ctx.span(id) else { return };
+        let mut visitor = FieldVisitor::default();
+        attrs.record(&mut visitor);
+        span.extensions_mut().insert(SpanFields(visitor.fields));
+    }
+
+    fn on_record(&self, id: &Id, values: &Record<'_>, ctx: Context<'_, S>) {
+        let Some(span) = ctx.span(id) else { return };
+        let mut extensions = span.extensions_mut();
+        if let Some(SpanFields(fields)) = extensions.get_mut::<SpanFields>() {
+            let mut visitor = FieldVisitor {
+                fields: std::mem::take(fields),
+                message: None,
+            };
+            values.record(&mut visitor);
+            *fields = visitor.fields;
+        }
+    }
+
+    fn on_event(&self, event: &Event<'_>, ctx: Context<'_, S>) {
+        // What a diagnostics callback logs would only lead to more diagnostics.
+        if diagnostics::in_callback() {
+            return;
+        }
+        let metadata = event.metadata();
+        let level = log_level(*metadata.level());
+        if !self.gate.admits(metadata.target(), level) {
+            return;
+        }
+        let mut fields = self.options.static_fields.clone();
+        if let Some(id) = context::correlation_id() {
+            fields.insert(CORRELATION_FIELD.to_string(), id.into());
+        }
+        if let Some(scope) = context::scope() {
+            fields.insert(SCOPE_FIELD.to_string(), scope.into());
+        }
+        let mut visitor = FieldVisitor {
+            fields,
+            message: None,
+        };
+        event.record(&mut visitor);
+
+        let mut spans: Vec<Value> = ctx
+            .event_scope(event)
+            .into_iter()
+            .flatten()
+            .take(self.max_span_depth)
+            .map(|span| {
+                let fields = span
+                    .extensions()
+                    .get::<SpanFields>()
+                    .map(|SpanFields(fields)| fields.clone())
+                    .unwrap_or_default();
+                serde_json::json!({ "name": span.name(), "fields": fields })
+            })
+            .collect();
+        if !spans.is_empty() {
+            spans.reverse();
+            visitor
+                .fields
+                .insert("spans".to_string(), Value::Array(spans));
+        }
+
+        let message = Cow::Owned(visitor.message.unwrap_or_default());
+        let (message, truncated) = limit_message(message, &self.options);
+        if truncated {
+            visitor
+                .fields
+                .insert("message_truncated".to_string(), Value::Bool(true));
+        }
+
+        self.pipeline.enqueue(LogMessage {
+            timestamp: current_timestamp(&self.options),
+            level: format!("{:<5}", level.to_string()),
+            target: metadata.target().to_string(),
+            thread: None,
+            message,
+            module_path: metadata.module_path().map(ToString::to_string),
+            file: metadata.file().map(ToString::to_string),
+            line: metadata.line(),
+            function: None,
+            fields: visitor.fields,
+            enqueued_at: Instant::now(),
+        });
+    }
+}
+
+fn log_level(level: tracing::Level) -> log::Level {
+    match level {
+        tracing::Level::ERROR => log::Level::Error,
+        tracing::Level::WARN => log::Level::Warn,
+        tracing::Level::INFO => log::Level::Info,
+        tracing::Level::DEBUG => log::Level::Debug,
+        tracing::Level::TRACE => log::Level::Trace,
+    }
+}
+
+#[derive(Default)]
+struct FieldVisitor {
+    fields: Map<String, Value>,
+    message: Option<String>,
+}
+
+impl Visit for FieldVisitor {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        if field.name() == "message" {
+            self.message = Some(value.to_string());
+        } else {
+            self.fields.insert(field.name().to_string(), value.into());
+        }
+    }
+
+    fn record_i64(&mut self, field: &Field, value: i64) {
+        self.fields.insert(field.name().to_string(), value.into());
+    }
+
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        self.fields.insert(field.name().to_string(), value.into());
+    }
+
+    fn record_f64(&mut self, field: &Field, value: f64) {
+        self.fields.insert(field.name().to_string(), value.into());
+    }
+
+    fn record_bool(&mut self, field: &Field, value: bool) {
+        self.fields.insert(field.name().to_string(), value.into());
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        if field.name() == "message" {
+            self.message = Some(format!("{value:?}"));
+        } else {
+            self.fields
+                .insert(field.name().to_string(), format!("{value:?}").into());
+        }
+    }
+}
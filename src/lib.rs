@@ -1 +1,19 @@
-pub mod logger;
\ No newline at end of file
+#![cfg_attr(feature = "nightly", feature(thread_id_value))]
+
+#[cfg(feature = "http")]
+pub mod context;
+pub mod diagnostics;
+pub mod filter;
+#[cfg(feature = "http")]
+mod http_sink;
+#[cfg(feature = "lambda")]
+pub mod lambda;
+#[cfg(feature = "tracing")]
+pub mod layer;
+#[cfg(feature = "http")]
+pub mod logger;
+#[cfg(feature = "lean-ndjson")]
+mod ndjson;
+pub mod sink;
+#[cfg(feature = "http")]
+pub mod writer;
@@ -1,164 +1,3628 @@
-use log::Record;
+use crate::context::{self, CORRELATION_FIELD, SCOPE_FIELD};
+use crate::diagnostics::{self, DiagnosticKind, DiagnosticsSink};
+use crate::filter::TargetFilter;
+use crate::sink::{
+    join_path, BatchStats, BatchStatsCallback, BatchTransform, Casing, Compression, Compressor,
+    Envelope, Format, HttpSink, MissingLinePolicy, RetryCondition, Sink, DEFAULT_MAX_REQUEST_BYTES,
+};
+pub use crate::sink::{LogMessage, SendError};
+use crate::writer::BetterStackWriter;
+use chrono::DateTime;
+use futures_util::future::BoxFuture;
+use futures_util::FutureExt;
+use log::kv::{self, VisitSource};
+use log::{Level, LevelFilter, Record};
 use log4rs::append::Append;
-use reqwest::Client;
-use serde_json::json;
-use std::fmt;
+use log4rs::config::{Deserialize, Deserializers};
+use log4rs::encode::writer::simple::SimpleWriter;
+use log4rs::encode::{Encode, EncoderConfig};
+use rand::rngs::SmallRng;
+use rand::{Rng, RngCore, SeedableRng};
+use reqwest::header::{AUTHORIZATION, CONTENT_ENCODING, CONTENT_LENGTH, CONTENT_TYPE};
+use reqwest::{Client, Method, StatusCode};
+use serde_json::{Map, Value};
+use std::any::Any;
+use std::borrow::Cow;
+use std::collections::{HashMap, VecDeque};
+use std::fmt::{self, Write};
+use std::future::Future;
+use std::panic::AssertUnwindSafe;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
 
-use std::time::Duration;
-use tokio::sync::mpsc;
-use tokio::time;
+#[cfg(feature = "timestamps")]
+use ::time::{format_description::BorrowedFormatItem, macros::format_description, OffsetDateTime};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::sync::mpsc::error::TrySendError;
+use tokio::sync::{mpsc, oneshot};
+use tokio::task::{self, JoinError, JoinHandle};
+use tokio::time::{self, MissedTickBehavior};
 
+/// A log4rs appender that ships records to Better Stack in batches.
+///
+/// Each pipeline has a single background task that awaits each request before
+/// sending the next one, so batches leave in the order their messages were
+/// enqueued. That order is only kept per pipeline: the audit and tenant
+/// routes have their own tasks, a failed batch is dropped while newer ones
+/// succeed, and with
+/// [`coalesce_repeated`](BetterStackAppenderBuilder::coalesce_repeated) the
+/// summary of a window arrives after the messages that followed it. See
+/// [`ordered_delivery`](BetterStackAppenderBuilder::ordered_delivery) to hold
+/// newer batches until a failed one is delivered.
 pub struct BetterStackAppender {
+    pipeline: Arc<Pipeline>,
+    audit: Option<(AuditRoute, Pipeline)>,
+    tenants: Option<(TenantSelector, HashMap<String, Pipeline>)>,
+    options: MessageOptions,
+    gate: Arc<Gate>,
+    error_receiver: Mutex<Option<mpsc::Receiver<SendError>>>,
+    counters: Arc<Counters>,
+}
+
+/// The sending half of a background upload task.
+pub(crate) struct Pipeline {
     sender: mpsc::Sender<LogMessage>,
+    control: mpsc::UnboundedSender<Command>,
+    closed_warned: AtomicBool,
+    dropped: Arc<AtomicU64>,
+    flush_window: Arc<Mutex<FlushWindow>>,
+    /// `None` for tasks started by a custom spawner.
+    task_id: Option<task::Id>,
+    diagnostics: DiagnosticsSink,
+}
+
+impl Pipeline {
+    fn spawn(sink: Box<dyn Sink>, config: Config) -> (Pipeline, UploaderTask) {
+        let (sender, receiver) = mpsc::channel(config.channel_capacity);
+        let (control, control_receiver) = mpsc::unbounded_channel();
+        let dropped = Arc::new(AtomicU64::new(0));
+        let flush_window = config.flush_window.clone();
+        let diagnostics = config.diagnostics.clone();
+        let spawner = config.spawner.clone();
+
+        let task = spawn_uploader(
+            BetterStackAppender::supervise(
+                receiver,
+                control_receiver,
+                sink,
+                config,
+                dropped.clone(),
+            ),
+            spawner.as_ref(),
+        );
+
+        let pipeline = Pipeline {
+            sender,
+            control,
+            closed_warned: AtomicBool::new(false),
+            dropped,
+            flush_window,
+            task_id: task.id(),
+            diagnostics,
+        };
+        (pipeline, task)
+    }
+
+    pub(crate) fn enqueue(&self, message: LogMessage) {
+        self.enqueue_with(|| message);
+    }
+
+    /// Like [`enqueue`](Self::enqueue), but only builds the message once
+    /// there is room for it, so dropped messages are never formatted.
+    pub(crate) fn enqueue_with(&self, build: impl FnOnce() -> LogMessage) {
+        match self.sender.try_reserve() {
+            Ok(permit) => permit.send(build()),
+            // Backpressure, which the drop counter and summaries report.
+            Err(TrySendError::Full(())) => {
+                self.dropped.fetch_add(1, Ordering::Relaxed);
+            }
+            // The task is gone for good, which `Status::pipeline_dead` reports.
+            Err(TrySendError::Closed(())) => {
+                if !self.closed_warned.swap(true, Ordering::Relaxed) {
+                    self.diagnostics.report(
+                        DiagnosticKind::PipelineClosed,
+                        "background task is gone, log messages are no longer delivered".to_string(),
+                    );
+                }
+            }
+        }
+    }
+
+    pub(crate) fn flush(&self) {
+        let _ = self.control.send(Command::Flush);
+    }
+
+    fn command(&self, command: Command) {
+        let _ = self.control.send(command);
+    }
+
+    async fn drain(&self) {
+        let (done, wait) = oneshot::channel();
+        if self.control.send(Command::Drain(done)).is_ok() {
+            // An error means the task is gone, so there is nothing to wait for.
+            let _ = wait.await;
+        }
+    }
+
+    async fn purge(&self, predicate: PurgePredicate) -> usize {
+        let (done, wait) = oneshot::channel();
+        if self.control.send(Command::Purge(predicate, done)).is_err() {
+            return 0;
+        }
+        wait.await.unwrap_or(0)
+    }
+}
+
+/// The name of the background upload task, as shown by `tokio-console` and,
+/// with the `tracing` feature, as the name of the span it runs in.
+pub const UPLOADER_TASK_NAME: &str = "betterstack-uploader";
+
+/// Spawns the background task, with the custom spawner if there is one. The
+/// task is only named with the `tracing` feature and
+/// `RUSTFLAGS="--cfg tokio_unstable"`, as `tokio-console` needs.
+fn spawn_uploader<F>(task: F, spawner: Option<&Spawner>) -> UploaderTask
+where
+    F: Future<Output = ()> + Send + 'static,
+{
+    #[cfg(feature = "tracing")]
+    let task = tracing::Instrument::instrument(task, tracing::info_span!(UPLOADER_TASK_NAME));
+
+    if let Some(spawner) = spawner {
+        let (done, finished) = oneshot::channel::<()>();
+        spawner(Box::pin(async move {
+            task.await;
+            drop(done);
+        }));
+        return UploaderTask::Custom(finished);
+    }
+    #[cfg(all(tokio_unstable, feature = "tracing"))]
+    return UploaderTask::Tokio(
+        tokio::task::Builder::new()
+            .name(UPLOADER_TASK_NAME)
+            .spawn(task)
+            .expect("failed to spawn the background task"),
+    );
+    #[cfg(not(all(tokio_unstable, feature = "tracing")))]
+    UploaderTask::Tokio(tokio::spawn(task))
+}
+
+/// Runs the background tasks, see [`BetterStackAppenderBuilder::spawner`].
+type Spawner = Arc<dyn Fn(BoxFuture<'static, ()>) + Send + Sync>;
+
+/// A running background task.
+enum UploaderTask {
+    Tokio(JoinHandle<()>),
+    /// Started by a custom spawner. The sender is dropped when the task
+    /// finishes, or when the executor drops it.
+    Custom(oneshot::Receiver<()>),
+}
+
+impl UploaderTask {
+    fn id(&self) -> Option<task::Id> {
+        match self {
+            UploaderTask::Tokio(task) => Some(task.id()),
+            UploaderTask::Custom(_) => None,
+        }
+    }
+
+    async fn join(self) -> Result<(), JoinError> {
+        match self {
+            UploaderTask::Tokio(task) => task.await,
+            UploaderTask::Custom(finished) => {
+                let _ = finished.await;
+                Ok(())
+            }
+        }
+    }
+}
+
+/// Sends matching records to a separate audit source, e.g. one with a longer
+/// retention. Audit records are never sampled.
+pub struct AuditRoute {
+    ingest_url: String,
+    source_token: String,
+    predicate: Box<dyn Fn(&Record) -> bool + Send + Sync>,
+    exclusive: bool,
+}
+
+impl AuditRoute {
+    pub fn new(
+        ingest_url: String,
+        source_token: String,
+        predicate: impl Fn(&Record) -> bool + Send + Sync + 'static,
+    ) -> AuditRoute {
+        AuditRoute {
+            ingest_url,
+            source_token,
+            predicate: Box::new(predicate),
+            exclusive: false,
+        }
+    }
+
+    /// Send matching records only to the audit source instead of to both
+    /// sources. Defaults to `false`.
+    pub fn exclusive(mut self, exclusive: bool) -> Self {
+        self.exclusive = exclusive;
+        self
+    }
+}
+
+type TenantSelector = Box<dyn Fn(&Record) -> Option<String> + Send + Sync>;
+
+/// Sends the records of each tenant of a multi-tenant application to the
+/// tenant's own source. Records of unknown tenants, or without one, go to
+/// the appender's source.
+///
+/// ```no_run
+/// use betterstack_logger::logger::{BetterStackAppender, TenantRouting};
+///
+/// let routing = TenantRouting::by_key("tenant_id")
+///     .tenant("acme", "https://in.logs.betterstack.com".to_string(), "acme-token".to_string())
+///     .tenant("globex", "https://in.logs.betterstack.com".to_string(), "globex-token".to_string());
+/// let appender = BetterStackAppender::builder(
+///     "https://in.logs.betterstack.com".to_string(),
+///     "default-token".to_string(),
+/// )
+/// .tenant_routing(routing)
+/// .build();
+///
+/// log::info!(tenant_id = "acme"; "sent to acme's source");
+/// ```
+pub struct TenantRouting {
+    selector: TenantSelector,
+    tenants: HashMap<String, (String, String)>,
+}
+
+impl TenantRouting {
+    /// Routes by the tenant id `selector` returns for a record, e.g. one
+    /// taken from a thread-local set per request.
+    pub fn new(
+        selector: impl Fn(&Record) -> Option<String> + Send + Sync + 'static,
+    ) -> TenantRouting {
+        TenantRouting {
+            selector: Box::new(selector),
+            tenants: HashMap::new(),
+        }
+    }
+
+    /// Routes by the value of the record key-value `key`.
+    pub fn by_key(key: impl Into<String>) -> TenantRouting {
+        let key = key.into();
+        TenantRouting::new(move |record| {
+            let value = record.key_values().get(kv::Key::from_str(&key))?;
+            Some(value.to_string())
+        })
+    }
+
+    /// Sends the records of tenant `id` to the source with this ingest URL
+    /// and token.
+    pub fn tenant(
+        mut self,
+        id: impl Into<String>,
+        ingest_url: String,
+        source_token: String,
+    ) -> Self {
+        self.tenants.insert(id.into(), (ingest_url, source_token));
+        self
+    }
+}
+
+#[derive(serde::Serialize)]
+struct ThreadInfo {
+    id: String,
+    name: Option<String>,
+}
+
+impl fmt::Debug for BetterStackAppender {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("BetterStackAppender")
+            .field("sender", &self.pipeline.sender)
+            .finish()
+    }
+}
+
+impl Append for BetterStackAppender {
+    fn append(&self, record: &Record) -> anyhow::Result<()> {
+        // What a diagnostics callback logs would only lead to more diagnostics.
+        if diagnostics::in_callback() {
+            return Ok(());
+        }
+        if let Some((route, audit)) = &self.audit {
+            if (route.predicate)(record) {
+                audit.enqueue_with(|| build_log_message(record, &self.options));
+                if route.exclusive {
+                    return Ok(());
+                }
+            }
+        }
+
+        if !self.gate.admits(record.target(), record.level()) {
+            return Ok(());
+        }
+
+        let pipeline = self
+            .tenants
+            .as_ref()
+            .and_then(|(selector, tenants)| tenants.get(&selector(record)?))
+            .unwrap_or(&self.pipeline);
+        // Formatting is the expensive part, so it comes after every gate.
+        let Some(format) = &self.options.console_timestamp_format else {
+            pipeline.enqueue_with(|| build_log_message(record, &self.options));
+            return Ok(());
+        };
+        // The console copy is written even when the channel is full.
+        let message = build_log_message(record, &self.options);
+        write_to_console(&message, format);
+        pipeline.enqueue(message);
+        Ok(())
+    }
+
+    fn flush(&self) {
+        self.each_pipeline(Pipeline::flush);
+    }
+}
+
+/// A snapshot of the appender's health counters, see [`BetterStackAppender::status`].
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Status {
+    /// Messages skipped because they could not be serialized.
+    pub serialization_errors: u64,
+    /// Messages dropped because the channel was full.
+    pub dropped: u64,
+    /// Whether the ingest endpoint rejected the source token with `401` or
+    /// `403`. Only set with `fail_on_auth_error`, and stays set.
+    pub unhealthy: bool,
+    /// Whether a background task has exited, e.g. after a panic or a
+    /// shutdown, so that messages are no longer delivered. Unlike messages
+    /// dropped to backpressure, those are not counted in `dropped`.
+    pub pipeline_dead: bool,
+}
+
+/// Counters shared between the appender and its background tasks.
+#[derive(Default)]
+pub(crate) struct Counters {
+    pub(crate) serialization_errors: AtomicU64,
+    /// Milliseconds since the Unix epoch of the last successful send, or 0.
+    last_success_ms: AtomicU64,
+    unhealthy: AtomicBool,
+}
+
+impl Counters {
+    fn status(&self, dropped: u64, pipeline_dead: bool) -> Status {
+        Status {
+            serialization_errors: self.serialization_errors.load(Ordering::Relaxed),
+            dropped,
+            unhealthy: self.unhealthy.load(Ordering::Relaxed),
+            pipeline_dead,
+        }
+    }
+
+    fn last_success_at(&self) -> Option<SystemTime> {
+        match self.last_success_ms.load(Ordering::Relaxed) {
+            0 => None,
+            ms => Some(UNIX_EPOCH + Duration::from_millis(ms)),
+        }
+    }
+}
+
+/// How the source token is passed to the ingest endpoint.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum AuthScheme {
+    /// An `Authorization: Bearer <token>` header.
+    #[default]
+    Bearer,
+    /// A `?<name>=<token>` query parameter, for proxies that strip the
+    /// `Authorization` header.
+    QueryParam { name: String },
+}
+
+/// Commands sent to the background task alongside the log messages.
+enum Command {
+    Flush,
+    /// Send everything queued so far, then reply.
+    Drain(oneshot::Sender<()>),
+    /// Stop accepting messages, send everything still queued and exit.
+    Shutdown,
+    /// Hold messages instead of sending them.
+    Pause,
+    Resume,
+    /// Drop every message created up to the given time that is not sent yet.
+    Discard(Instant),
+    SetBatchSize(usize),
+    SetFlushInterval(Duration),
+    /// Send the pending batch if its oldest message is a flush interval old.
+    FlushIfDue,
+    /// Drop the unsent messages matching the predicate, replying how many.
+    Purge(PurgePredicate, oneshot::Sender<usize>),
+}
+
+/// Changes the batch size and flush interval of a running
+/// [`BetterStackAppender`], see [`BetterStackAppender::config_handle`].
+///
+/// The changes are picked up by the background tasks right away and last
+/// until the next change.
+#[derive(Clone)]
+pub struct ConfigHandle {
+    controls: Vec<mpsc::UnboundedSender<Command>>,
+}
+
+impl ConfigHandle {
+    /// Sends a batch once it holds this many messages, like
+    /// [`BetterStackAppenderBuilder::batch_size`]. A pending batch that is
+    /// already that large is sent right away.
+    pub fn set_max_batch_size(&self, batch_size: usize) {
+        for control in &self.controls {
+            let _ = control.send(Command::SetBatchSize(batch_size.max(1)));
+        }
+    }
+
+    /// Sends the pending batch at this interval, like
+    /// [`BetterStackAppenderBuilder::flush_interval`], with the first flush
+    /// one interval from now. With an adaptive flush the interval is clamped
+    /// to its bounds and adapts from there. Has no effect if periodic
+    /// flushing is disabled.
+    pub fn set_flush_interval(&self, interval: Duration) {
+        let interval = interval.max(Duration::from_millis(1));
+        for control in &self.controls {
+            let _ = control.send(Command::SetFlushInterval(interval));
+        }
+    }
+}
+
+/// Waits for the messages of a [`BetterStackAppender`] to be sent, see
+/// [`BetterStackAppender::drain_handle`].
+#[derive(Clone)]
+pub struct DrainHandle {
+    controls: Vec<mpsc::UnboundedSender<Command>>,
+}
+
+impl DrainHandle {
+    /// Like [`BetterStackAppender::drain`].
+    pub async fn drain(&self) {
+        for control in &self.controls {
+            let (done, wait) = oneshot::channel();
+            if control.send(Command::Drain(done)).is_ok() {
+                let _ = wait.await;
+            }
+        }
+    }
+}
+
+/// Checks on and controls a [`BetterStackAppender`] after it was handed to
+/// log4rs, which hides the appender's own methods, see
+/// [`BetterStackAppender::control_handle`].
+#[derive(Clone)]
+pub struct ControlHandle {
+    controls: Vec<mpsc::UnboundedSender<Command>>,
+    dropped: Vec<Arc<AtomicU64>>,
+    counters: Arc<Counters>,
+}
+
+impl ControlHandle {
+    /// Like [`BetterStackAppender::status`].
+    pub fn status(&self) -> Status {
+        let dropped = self
+            .dropped
+            .iter()
+            .map(|dropped| dropped.load(Ordering::Relaxed))
+            .sum();
+        // The control channels close when their tasks exit.
+        let pipeline_dead = self.controls.iter().any(|control| control.is_closed());
+        self.counters.status(dropped, pipeline_dead)
+    }
+
+    /// Like [`BetterStackAppender::last_success_at`].
+    pub fn last_success_at(&self) -> Option<SystemTime> {
+        self.counters.last_success_at()
+    }
+
+    /// Like [`BetterStackAppender::poll_flush_if_due`].
+    pub fn poll_flush_if_due(&self) {
+        self.command(|| Command::FlushIfDue);
+    }
+
+    /// Like [`BetterStackAppender::pause`].
+    pub fn pause(&self) {
+        self.command(|| Command::Pause);
+    }
+
+    /// Like [`BetterStackAppender::resume`].
+    pub fn resume(&self) {
+        self.command(|| Command::Resume);
+    }
+
+    /// Like [`BetterStackAppender::discard_buffered`].
+    pub fn discard_buffered(&self) {
+        let now = Instant::now();
+        self.command(|| Command::Discard(now));
+    }
+
+    /// Like [`BetterStackAppender::purge`].
+    pub async fn purge(
+        &self,
+        predicate: impl Fn(&LogMessage) -> bool + Send + Sync + 'static,
+    ) -> usize {
+        let predicate: PurgePredicate = Arc::new(predicate);
+        let mut purged = 0;
+        for control in &self.controls {
+            let (done, wait) = oneshot::channel();
+            if control
+                .send(Command::Purge(predicate.clone(), done))
+                .is_ok()
+            {
+                purged += wait.await.unwrap_or(0);
+            }
+        }
+        purged
+    }
+
+    fn command(&self, command: impl Fn() -> Command) {
+        for control in &self.controls {
+            let _ = control.send(command());
+        }
+    }
+}
+
+/// Stops the background tasks of a [`BetterStackAppender`], see
+/// [`BetterStackAppenderBuilder::spawn`].
+pub struct ShutdownHandle {
+    tasks: Vec<(mpsc::UnboundedSender<Command>, UploaderTask)>,
+}
+
+impl ShutdownHandle {
+    /// Sends all buffered messages and waits for the background tasks to
+    /// finish. Messages logged afterwards are dropped.
+    pub async fn shutdown(self) -> Result<(), JoinError> {
+        for (control, _) in &self.tasks {
+            let _ = control.send(Command::Shutdown);
+        }
+        for (_, task) in self.tasks {
+            task.join().await?;
+        }
+        Ok(())
+    }
+}
+
+type Validator = Arc<dyn Fn(&LogMessage) -> Result<(), String> + Send + Sync>;
+type AuthErrorHook = Arc<dyn Fn(&SendError) + Send + Sync>;
+type PurgePredicate = Arc<dyn Fn(&LogMessage) -> bool + Send + Sync>;
+
+struct Config {
+    ingest_url: String,
+    method: Method,
+    path: Option<String>,
+    source_token: String,
+    auth_scheme: AuthScheme,
+    compression: Compression,
+    compressor: Option<Arc<dyn Compressor>>,
+    compression_min_bytes: usize,
+    accepted_encodings: Option<Vec<String>>,
+    max_request_bytes: usize,
+    max_messages_per_request: Option<usize>,
+    stream_bodies: bool,
+    format: Format,
+    envelope: Option<Envelope>,
+    timestamp_field_name: Option<String>,
+    key_casing: Casing,
+    level_as_object: bool,
+    missing_line_policy: MissingLinePolicy,
+    on_batch_sent: Option<BatchStatsCallback>,
+    batch_transform: Option<BatchTransform>,
+    max_retries: u32,
+    retry_backoff: Duration,
+    retry_on: RetryCondition,
+    max_retry_duration: Option<Duration>,
+    idempotency_header: Option<String>,
+    #[cfg(feature = "signing")]
+    hmac_signing: Option<(Vec<u8>, String)>,
+    clock_skew_threshold: Option<Duration>,
+    flush_interval: Duration,
+    periodic_flush: bool,
+    flush_on_start: bool,
+    missed_tick_behavior: MissedTickBehavior,
+    batch_size: usize,
+    channel_capacity: usize,
+    message: MessageOptions,
+    client: Option<Client>,
+    http: HttpOptions,
+    filter: Option<TargetFilter>,
+    /// Reported once the diagnostics sink is known, on build.
+    invalid_filter_directives: Vec<String>,
+    sample_rates: [f64; 5],
+    always_ship: LevelFilter,
+    sampling_rng: Option<Box<dyn RngCore + Send + Sync>>,
+    restart_on_panic: bool,
+    flush_jitter: Option<Duration>,
+    adaptive_flush: Option<AdaptiveFlush>,
+    drop_summary_interval: Option<Duration>,
+    heartbeat: Option<(Duration, String)>,
+    coalescing: Option<Coalescing>,
+    dedupe_within_batch: bool,
+    sort_batch_by_timestamp: bool,
+    ordered_delivery: bool,
+    validator: Option<Validator>,
+    fail_on_auth_error: bool,
+    on_auth_error: Option<AuthErrorHook>,
+    diagnostics: DiagnosticsSink,
+    spawner: Option<Spawner>,
+    audit_route: Option<AuditRoute>,
+    tenant_routing: Option<TenantRouting>,
+    error_channel_capacity: Option<usize>,
+    error_sender: Option<mpsc::Sender<SendError>>,
+    counters: Arc<Counters>,
+    flush_window: Arc<Mutex<FlushWindow>>,
+    sink: Option<Box<dyn Sink>>,
+}
+
+impl Config {
+    fn new(ingest_url: String, source_token: String) -> Config {
+        Config {
+            ingest_url,
+            method: Method::POST,
+            path: None,
+            source_token,
+            auth_scheme: AuthScheme::default(),
+            compression: Compression::default(),
+            compressor: None,
+            compression_min_bytes: 0,
+            accepted_encodings: None,
+            max_request_bytes: DEFAULT_MAX_REQUEST_BYTES,
+            max_messages_per_request: None,
+            stream_bodies: false,
+            format: Format::default(),
+            envelope: None,
+            timestamp_field_name: None,
+            key_casing: Casing::default(),
+            level_as_object: false,
+            missing_line_policy: MissingLinePolicy::default(),
+            on_batch_sent: None,
+            batch_transform: None,
+            max_retries: 0,
+            retry_backoff: Duration::from_millis(500),
+            retry_on: RetryCondition::default(),
+            max_retry_duration: None,
+            idempotency_header: None,
+            #[cfg(feature = "signing")]
+            hmac_signing: None,
+            clock_skew_threshold: None,
+            flush_interval: Duration::from_secs(3),
+            periodic_flush: true,
+            flush_on_start: false,
+            missed_tick_behavior: MissedTickBehavior::Burst,
+            batch_size: 1000,
+            channel_capacity: 100,
+            message: MessageOptions::default(),
+            client: None,
+            http: HttpOptions::default(),
+            filter: None,
+            invalid_filter_directives: Vec::new(),
+            sample_rates: [1.0; 5],
+            always_ship: LevelFilter::Error,
+            sampling_rng: None,
+            restart_on_panic: false,
+            flush_jitter: None,
+            adaptive_flush: None,
+            drop_summary_interval: None,
+            heartbeat: None,
+            coalescing: None,
+            dedupe_within_batch: false,
+            sort_batch_by_timestamp: false,
+            ordered_delivery: false,
+            validator: None,
+            fail_on_auth_error: false,
+            on_auth_error: None,
+            diagnostics: DiagnosticsSink::default(),
+            spawner: None,
+            audit_route: None,
+            tenant_routing: None,
+            error_channel_capacity: None,
+            error_sender: None,
+            counters: Arc::default(),
+            flush_window: Arc::default(),
+            sink: None,
+        }
+    }
+
+    fn http_sink(&self, client: Client) -> HttpSink {
+        let ingest_url = match &self.path {
+            Some(path) => {
+                let url = join_path(&self.ingest_url, path);
+                if let Err(error) = reqwest::Url::parse(&url) {
+                    panic!("invalid ingest URL {url:?}: {error}");
+                }
+                url
+            }
+            None => self.ingest_url.clone(),
+        };
+        let mut sink = HttpSink::new(client, ingest_url, self.source_token.clone())
+            .method(self.method.clone())
+            .auth_scheme(self.auth_scheme.clone())
+            .compression(self.compression)
+            .compressor_arc(self.compressor.clone())
+            .compression_min_bytes(self.compression_min_bytes)
+            .max_request_bytes(self.max_request_bytes)
+            .stream_bodies(self.stream_bodies)
+            .format(self.format)
+            .key_casing(self.key_casing)
+            .level_as_object(self.level_as_object)
+            .missing_line_policy(self.missing_line_policy)
+            .max_retries(self.max_retries)
+            .retry_backoff(self.retry_backoff)
+            .retry_on(self.retry_on)
+            .stop_after_failed_request(self.ordered_delivery)
+            .on_batch_sent_arc(self.on_batch_sent.clone())
+            .batch_transform_arc(self.batch_transform.clone())
+            .counters(self.counters.clone())
+            .diagnostics_sink(self.diagnostics.clone());
+        if let Some(envelope) = &self.envelope {
+            sink = sink.envelope(envelope.clone());
+        }
+        if let Some(encodings) = &self.accepted_encodings {
+            sink = sink.accepted_encodings(encodings.clone());
+        }
+        if let Some(name) = &self.timestamp_field_name {
+            sink = sink.timestamp_field_name(name.clone());
+        }
+        if let Some(max) = self.max_messages_per_request {
+            sink = sink.max_messages_per_request(max);
+        }
+        if let Some(budget) = self.max_retry_duration {
+            sink = sink.max_retry_duration(budget);
+        }
+        if let Some(header) = &self.idempotency_header {
+            sink = sink.idempotency_header(header.clone());
+        }
+        #[cfg(feature = "signing")]
+        if let Some((key, header)) = &self.hmac_signing {
+            sink = sink.hmac_signing(key, header.clone());
+        }
+        if let Some(threshold) = self.clock_skew_threshold {
+            sink = sink.warn_on_clock_skew(threshold);
+        }
+        sink
+    }
+}
+
+/// The plain-data settings of a [`BetterStackAppender`], for keeping the
+/// configuration in one place, cloning it and layering it per environment.
+///
+/// Convert it with [`BetterStackAppender::from_config`] or
+/// [`BetterStackAppenderBuilder::from_config`]. Settings that are not plain
+/// data, like callbacks, custom sinks and filters, are only set on the
+/// builder. The fields correspond to the builder methods of the same name.
+///
+/// ```
+/// use betterstack_logger::logger::BetterStackConfig;
+///
+/// let config = BetterStackConfig {
+///     ingest_url: "https://in.logs.betterstack.com".to_string(),
+///     source_token: "my-source-token".to_string(),
+///     batch_size: 500,
+///     ..BetterStackConfig::default()
+/// };
+/// let staging = BetterStackConfig {
+///     source_token: "staging-token".to_string(),
+///     ..config.clone()
+/// };
+/// # let _ = staging;
+/// ```
+#[derive(Debug, Clone, PartialEq)]
+pub struct BetterStackConfig {
+    pub ingest_url: String,
+    pub source_token: String,
+    pub method: Method,
+    pub path: Option<String>,
+    pub auth_scheme: AuthScheme,
+    pub compression: Compression,
+    pub compression_min_bytes: usize,
+    pub accepted_encodings: Option<Vec<String>>,
+    pub max_request_bytes: usize,
+    pub max_messages_per_request: Option<usize>,
+    pub stream_bodies: bool,
+    pub format: Format,
+    pub envelope: Option<Envelope>,
+    pub timestamp_field_name: Option<String>,
+    pub key_casing: Casing,
+    pub level_as_object: bool,
+    pub missing_line_policy: MissingLinePolicy,
+    pub max_retries: u32,
+    pub retry_backoff: Duration,
+    pub retry_on: RetryCondition,
+    pub max_retry_duration: Option<Duration>,
+    pub idempotency_header: Option<String>,
+    /// The key and header of [`BetterStackAppenderBuilder::with_hmac_signing`].
+    #[cfg(feature = "signing")]
+    pub hmac_signing: Option<(Vec<u8>, String)>,
+    pub clock_skew_threshold: Option<Duration>,
+    pub flush_interval: Duration,
+    pub periodic_flush: bool,
+    pub flush_on_start: bool,
+    pub missed_tick_behavior: MissedTickBehavior,
+    pub flush_jitter: Option<Duration>,
+    pub adaptive_flush: Option<AdaptiveFlush>,
+    pub batch_size: usize,
+    pub channel_capacity: usize,
+    pub sample_rates: [f64; 5],
+    pub always_ship: LevelFilter,
+    pub restart_on_panic: bool,
+    pub drop_summary_interval: Option<Duration>,
+    pub heartbeat: Option<(Duration, String)>,
+    pub coalescing: Option<Coalescing>,
+    pub dedupe_within_batch: bool,
+    pub sort_batch_by_timestamp: bool,
+    pub ordered_delivery: bool,
+    pub fail_on_auth_error: bool,
+    pub sanitize_control_chars: bool,
+    pub thread_info: bool,
+    pub uptime: bool,
+    pub unnamed_thread_label: Option<String>,
+    pub static_fields: Map<String, Value>,
+    pub max_fields: Option<usize>,
+    pub max_field_value_len: Option<usize>,
+    pub max_message_len: Option<usize>,
+    pub timestamp_key: Option<String>,
+    pub default_target: Option<String>,
+    pub tee_to_console: Option<String>,
+    #[cfg(feature = "timestamps")]
+    pub timestamps: Timestamps,
+    #[cfg(feature = "timestamps")]
+    pub timestamp_format: Option<TimestampFormat>,
+}
+
+impl Default for BetterStackConfig {
+    /// The builder's defaults, with an empty ingest URL and source token.
+    fn default() -> Self {
+        BetterStackAppender::builder(String::new(), String::new()).to_config()
+    }
+}
+
+/// The target filter and sampling, which records at the always-ship levels
+/// skip. Shared with the tracing layer.
+pub(crate) struct Gate {
+    filter: Option<TargetFilter>,
+    sampler: Sampler,
+    always_ship: LevelFilter,
+}
+
+impl Gate {
+    pub(crate) fn admits(&self, target: &str, level: Level) -> bool {
+        if level <= self.always_ship {
+            return true;
+        }
+        if let Some(filter) = &self.filter {
+            if !filter.enabled(target, level) {
+                return false;
+            }
+        }
+        self.sampler.keep(level)
+    }
+}
+
+/// Randomly drops records according to a per-level keep rate.
+struct Sampler {
+    rates: [f64; 5],
+    rng: Mutex<Box<dyn RngCore + Send + Sync>>,
+}
+
+impl Sampler {
+    fn keep(&self, level: Level) -> bool {
+        let rate = self.rates[level as usize - 1];
+        if rate >= 1.0 {
+            return true;
+        }
+        if rate <= 0.0 {
+            return false;
+        }
+        self.rng.lock().unwrap().random::<f64>() < rate
+    }
+}
+
+/// Settings for the internally built HTTP client.
+#[derive(Default)]
+struct HttpOptions {
+    http2_keep_alive_interval: Option<Duration>,
+    http2_adaptive_window: bool,
+    pool_max_idle_per_host: Option<usize>,
+    pool_idle_timeout: Option<Duration>,
+    tcp_keepalive: Option<Duration>,
+    danger_accept_invalid_certs: bool,
+}
+
+impl HttpOptions {
+    fn build_client(&self) -> Client {
+        let mut builder = Client::builder()
+            .http2_adaptive_window(self.http2_adaptive_window)
+            .danger_accept_invalid_certs(self.danger_accept_invalid_certs);
+        if let Some(interval) = self.http2_keep_alive_interval {
+            builder = builder.http2_keep_alive_interval(interval);
+        }
+        if let Some(max) = self.pool_max_idle_per_host {
+            builder = builder.pool_max_idle_per_host(max);
+        }
+        if let Some(timeout) = self.pool_idle_timeout {
+            builder = builder.pool_idle_timeout(timeout);
+        }
+        if let Some(interval) = self.tcp_keepalive {
+            builder = builder.tcp_keepalive(interval);
+        }
+        builder.build().expect("failed to build the HTTP client")
+    }
+}
+
+/// Settings applied while turning a record into a [`LogMessage`] in `append`,
+/// and by the writer and the tracing layer.
+#[derive(Clone)]
+pub(crate) struct MessageOptions {
+    sanitize_control_chars: bool,
+    #[cfg_attr(not(feature = "threads"), allow(dead_code))]
+    thread_info: bool,
+    #[cfg_attr(not(feature = "threads"), allow(dead_code))]
+    unnamed_thread_label: Option<String>,
+    uptime: bool,
+    /// When the appender was built, for the `uptime_ms` field.
+    started: Instant,
+    pub(crate) static_fields: Map<String, Value>,
+    max_fields: Option<usize>,
+    max_field_value_len: Option<usize>,
+    max_message_len: Option<usize>,
+    timestamp_key: Option<String>,
+    default_target: Option<String>,
+    encoder: Option<Arc<dyn Encode>>,
+    console_timestamp_format: Option<String>,
+    #[cfg(feature = "timestamps")]
+    timestamps: Timestamps,
+    #[cfg(feature = "timestamps")]
+    timestamp_format: Option<TimestampFormat>,
+    diagnostics: DiagnosticsSink,
+}
+
+impl Default for MessageOptions {
+    fn default() -> Self {
+        MessageOptions {
+            sanitize_control_chars: false,
+            thread_info: true,
+            unnamed_thread_label: Some("?".to_string()),
+            uptime: false,
+            started: Instant::now(),
+            static_fields: Map::new(),
+            max_fields: None,
+            max_field_value_len: None,
+            max_message_len: None,
+            timestamp_key: None,
+            default_target: None,
+            encoder: None,
+            console_timestamp_format: None,
+            #[cfg(feature = "timestamps")]
+            timestamps: Timestamps::default(),
+            #[cfg(feature = "timestamps")]
+            timestamp_format: None,
+            diagnostics: DiagnosticsSink::default(),
+        }
+    }
+}
+
+/// A contradictory combination of settings, found by
+/// [`BetterStackAppenderBuilder::try_build`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ConfigError {
+    /// An [`Envelope`] was set with [`Format::Ndjson`], which has no
+    /// top-level object to wrap the messages in.
+    EnvelopeWithNdjson,
+    /// An [`Envelope`] was set with a batch transform, which builds the body
+    /// instead.
+    EnvelopeWithBatchTransform,
+    /// Both a built-in [`Compression`] and a custom [`Compressor`] were set.
+    CompressionWithCompressor,
+    /// The idempotency header is one the sink sets itself, e.g.
+    /// `Content-Type`, which it would override.
+    IdempotencyHeaderConflict { header: String },
+    /// The method, `GET` or `HEAD`, does not carry a request body.
+    MethodWithoutBody { method: Method },
+    /// The ingest URL joined with the path is not a valid URL.
+    InvalidIngestUrl { url: String, reason: String },
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::EnvelopeWithNdjson => {
+                f.write_str("an envelope only applies to the JSON format, not to NDJSON")
+            }
+            ConfigError::EnvelopeWithBatchTransform => {
+                f.write_str("an envelope does not apply to bodies built by a batch transform")
+            }
+            ConfigError::CompressionWithCompressor => {
+                f.write_str("a built-in compression and a custom compressor are both set")
+            }
+            ConfigError::IdempotencyHeaderConflict { header } => write!(
+                f,
+                "the idempotency header {header:?} conflicts with a header set for the format, \
+                 compression or authentication"
+            ),
+            ConfigError::MethodWithoutBody { method } => {
+                write!(f, "the method {method} does not carry the request body")
+            }
+            ConfigError::InvalidIngestUrl { url, reason } => {
+                write!(f, "invalid ingest URL {url:?}: {reason}")
+            }
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+/// Builder for a [`BetterStackAppender`] with non-default settings.
+pub struct BetterStackAppenderBuilder {
+    config: Config,
+}
+
+impl BetterStackAppenderBuilder {
+    /// The HTTP method of each request, e.g. `PUT` for receivers that expect
+    /// it. Defaults to `POST`.
+    pub fn method(mut self, method: Method) -> Self {
+        self.config.method = method;
+        self
+    }
+
+    /// Append `path`, e.g. `/v1/logs`, to the ingest URL (and the audit
+    /// route's), with a single `/` between them.
+    ///
+    /// # Panics
+    ///
+    /// [`try_build`](Self::try_build) fails, and [`build`](Self::build)
+    /// panics, if the joined URL is not valid.
+    pub fn path(mut self, path: impl Into<String>) -> Self {
+        self.config.path = Some(path.into());
+        self
+    }
+
+    /// How the source token is sent. Defaults to [`AuthScheme::Bearer`].
+    pub fn auth_scheme(mut self, scheme: AuthScheme) -> Self {
+        self.config.auth_scheme = scheme;
+        self
+    }
+
+    /// Compress request bodies. Off by default.
+    pub fn compression(mut self, compression: Compression) -> Self {
+        self.config.compression = compression;
+        self
+    }
+
+    /// Compress request bodies with a custom [`Compressor`] instead of the
+    /// built-in [`compression`](Self::compression).
+    pub fn with_compressor(mut self, compressor: Box<dyn Compressor>) -> Self {
+        self.config.compressor = Some(compressor.into());
+        self
+    }
+
+    /// Only compress request bodies larger than this many bytes; smaller
+    /// ones are sent as is. Defaults to 0, compressing every body.
+    pub fn compression_min_bytes(mut self, min_bytes: usize) -> Self {
+        self.config.compression_min_bytes = min_bytes;
+        self
+    }
+
+    /// The `Content-Encoding`s the ingest endpoint accepts, e.g. `["gzip"]`.
+    /// If the configured compression is not among them, bodies are sent
+    /// uncompressed. Without this, compression is turned off after the first
+    /// `415 Unsupported Media Type` response, and that batch is resent
+    /// uncompressed.
+    pub fn accepted_encodings<S: Into<String>>(
+        mut self,
+        encodings: impl IntoIterator<Item = S>,
+    ) -> Self {
+        self.config.accepted_encodings = Some(encodings.into_iter().map(Into::into).collect());
+        self
+    }
+
+    /// The largest request body to send, before compression. Larger batches
+    /// are split across several requests, and single messages that are too
+    /// large have their message truncated. Defaults to Better Stack's limit
+    /// of 10 MiB.
+    pub fn max_request_bytes(mut self, max_bytes: usize) -> Self {
+        self.config.max_request_bytes = max_bytes;
+        self
+    }
+
+    /// The most messages to send in one request, however the flush was
+    /// triggered. Larger batches are split across several requests, each
+    /// also within [`max_request_bytes`](Self::max_request_bytes). Values
+    /// below 1 are treated as 1. Off by default.
+    pub fn max_messages_per_request(mut self, max: usize) -> Self {
+        self.config.max_messages_per_request = Some(max.max(1));
+        self
+    }
+
+    /// Serialize and compress each request body while it is being uploaded,
+    /// instead of building the whole body, and its compressed copy, in memory
+    /// first. Useful for large batches on memory-constrained hosts. Streamed
+    /// bodies are compressed regardless of `compression_min_bytes`, and are
+    /// not streamed with a custom [`Compressor`]. Off by default.
+    pub fn stream_bodies(mut self, stream: bool) -> Self {
+        self.config.stream_bodies = stream;
+        self
+    }
+
+    /// The request body format. Defaults to [`Format::Json`].
+    pub fn format(mut self, format: Format) -> Self {
+        self.config.format = format;
+        self
+    }
+
+    /// Wrap the JSON array of messages in an envelope object. Sent as a bare
+    /// array by default.
+    pub fn envelope(mut self, envelope: Envelope) -> Self {
+        self.config.envelope = Some(envelope);
+        self
+    }
+
+    /// Serialize the timestamp under `name`, e.g. `dt` or `@timestamp`,
+    /// leaving the other keys alone. Defaults to `timestamp`.
+    pub fn timestamp_field_name(mut self, name: impl Into<String>) -> Self {
+        self.config.timestamp_field_name = Some(name.into());
+        self
+    }
+
+    /// Spell every serialized key, including those of key-value fields and
+    /// static fields, in `casing`, e.g. `modulePath` for [`Casing::Camel`].
+    /// A custom [`timestamp_field_name`](Self::timestamp_field_name) is used
+    /// as given. Defaults to [`Casing::Snake`].
+    pub fn key_casing(mut self, casing: Casing) -> Self {
+        self.config.key_casing = casing;
+        self
+    }
+
+    /// Serialize the level as an object with its name and syslog severity,
+    /// `{"name": "ERROR", "severity": 3}`, instead of a string, for schemas
+    /// that filter on severity ranges. Off by default.
+    pub fn level_as_object(mut self, enabled: bool) -> Self {
+        self.config.level_as_object = enabled;
+        self
+    }
+
+    /// How messages without a source line, like the ones from
+    /// [`writer`](BetterStackAppender::writer), serialize `line`: as `null`,
+    /// without the key, or as `0`, for schemas that reject one of the
+    /// others. Defaults to [`MissingLinePolicy::Null`].
+    pub fn missing_line_policy(mut self, policy: MissingLinePolicy) -> Self {
+        self.config.missing_line_policy = policy;
+        self
+    }
+
+    /// Retry a failed request up to `max_retries` times, waiting
+    /// `retry_backoff` before the first retry and twice as long before each
+    /// further one. A `Retry-After` header in seconds replaces the backoff
+    /// for that retry. Defaults to no retries and 500ms.
+    pub fn retries(mut self, max_retries: u32, retry_backoff: Duration) -> Self {
+        self.config.max_retries = max_retries;
+        self.config.retry_backoff = retry_backoff;
+        self
+    }
+
+    /// Which failures are retried. Defaults to [`RetryCondition::Safe`], which
+    /// leaves out timeouts since the batch may already have been received.
+    pub fn retry_on(mut self, condition: RetryCondition) -> Self {
+        self.config.retry_on = condition;
+        self
+    }
+
+    /// Stop retrying a request once the next delay, whether from the backoff
+    /// or a `Retry-After` header, would take the time since its first attempt
+    /// past `budget`. Unlimited by default.
+    pub fn max_retry_duration(mut self, budget: Duration) -> Self {
+        self.config.max_retry_duration = Some(budget);
+        self
+    }
+
+    /// Send a random key per request in `header`, e.g. `Idempotency-Key`.
+    /// Retries of a request reuse its key, so a server that honors it can
+    /// drop duplicates. Off by default.
+    pub fn idempotency_header(mut self, header: impl Into<String>) -> Self {
+        self.config.idempotency_header = Some(header.into());
+        self
+    }
+
+    /// Sign each request body with HMAC-SHA256 under `key` and send the
+    /// signature, as lowercase hex, in `header`, e.g. `X-Signature`, so that
+    /// the receiver can verify the upload. The signature covers the bytes as
+    /// sent, after compression, so bodies are not streamed with
+    /// [`stream_bodies`](Self::stream_bodies). Requires the `signing` feature.
+    /// Off by default.
+    #[cfg(feature = "signing")]
+    pub fn with_hmac_signing(mut self, key: impl Into<Vec<u8>>, header: impl Into<String>) -> Self {
+        self.config.hmac_signing = Some((key.into(), header.into()));
+        self
+    }
+
+    /// Warn on stderr when the host clock is more than `threshold` off from
+    /// the ingest server's, going by the `Date` header of its responses, as
+    /// the logs would land at the wrong time. Checked on the first response
+    /// and then hourly. Off by default.
+    pub fn warn_on_clock_skew(mut self, threshold: Duration) -> Self {
+        self.config.clock_skew_threshold = Some(threshold);
+        self
+    }
+
+    /// Call `callback` with the message count, body sizes and upload latency
+    /// of every request, e.g. to feed capacity-planning metrics. It runs on
+    /// the background task, so keep it cheap.
+    pub fn on_batch_sent(mut self, callback: impl Fn(&BatchStats) + Send + Sync + 'static) -> Self {
+        self.config.on_batch_sent = Some(Arc::new(callback));
+        self
+    }
+
+    /// Build each request body from the whole batch with `transform`, whose
+    /// JSON output is sent as is, instead of serializing the messages in the
+    /// configured format, e.g. to wrap them in an envelope computed from the
+    /// batch. It runs once per request on the background task, which it
+    /// holds up while it runs, so it should be reasonably fast. Bodies built
+    /// this way are sent as `application/json`, are compressed as
+    /// configured and are split at
+    /// [`max_messages_per_request`](Self::max_messages_per_request) but not
+    /// at [`max_request_bytes`](Self::max_request_bytes). Serialized with
+    /// `serde`, the messages have their default keys, whatever the key
+    /// settings.
+    ///
+    /// ```no_run
+    /// use betterstack_logger::logger::BetterStackAppender;
+    /// use serde_json::json;
+    ///
+    /// let appender = BetterStackAppender::builder(
+    ///     "https://in.logs.betterstack.com".to_string(),
+    ///     "token".to_string(),
+    /// )
+    /// .with_batch_transform(|batch| json!({ "count": batch.len(), "logs": batch }))
+    /// .build();
+    /// ```
+    pub fn with_batch_transform(
+        mut self,
+        transform: impl Fn(Vec<LogMessage>) -> Value + Send + Sync + 'static,
+    ) -> Self {
+        self.config.batch_transform = Some(Arc::new(transform));
+        self
+    }
+
+    /// How often the pending batch is sent regardless of its size. Defaults to 3 seconds.
+    pub fn flush_interval(mut self, interval: Duration) -> Self {
+        self.config.flush_interval = interval;
+        self
+    }
+
+    /// Never send on a timer. Batches are only sent once they are full, when
+    /// [`Append::flush`] is called, or when the appender is dropped.
+    pub fn disable_periodic_flush(mut self) -> Self {
+        self.config.periodic_flush = false;
+        self
+    }
+
+    /// Also flush right when the background task starts, as
+    /// [`tokio::time::interval`] ticks, instead of one interval later. Off by
+    /// default. Has no effect with [`flush_jitter`](Self::flush_jitter),
+    /// which randomizes the first flush too.
+    pub fn flush_on_start(mut self, enabled: bool) -> Self {
+        self.config.flush_on_start = enabled;
+        self
+    }
+
+    /// What the flush timer does when it falls behind, e.g. after a send that
+    /// took longer than the interval. Defaults to
+    /// [`MissedTickBehavior::Burst`], which catches up with flushes right
+    /// after one another; [`MissedTickBehavior::Delay`] keeps a full interval
+    /// between flushes instead.
+    pub fn missed_tick_behavior(mut self, behavior: MissedTickBehavior) -> Self {
+        self.config.missed_tick_behavior = behavior;
+        self
+    }
+
+    /// Randomize each periodic flush by up to `jitter` in either direction, so
+    /// a fleet of instances started together does not flush in lockstep.
+    pub fn flush_jitter(mut self, jitter: Duration) -> Self {
+        self.config.flush_jitter = Some(jitter);
+        self
+    }
+
+    /// Let the flush interval adapt to the load within `bounds`, starting from
+    /// the configured interval: it is halved after a periodic flush that found
+    /// at least half a batch waiting and doubled after one that found nothing.
+    /// Off by default.
+    pub fn adaptive_flush(mut self, bounds: AdaptiveFlush) -> Self {
+        self.config.adaptive_flush = Some(bounds);
+        self
+    }
+
+    /// Number of messages that triggers a send as soon as it is reached. Defaults to 1000.
+    pub fn batch_size(mut self, batch_size: usize) -> Self {
+        self.config.batch_size = batch_size.max(1);
+        self
+    }
+
+    /// Number of messages that can wait for the background task before new
+    /// ones are dropped. Defaults to 100.
+    pub fn channel_capacity(mut self, capacity: usize) -> Self {
+        self.config.channel_capacity = capacity.max(1);
+        self
+    }
+
+    /// Escape non-printable control characters (other than `\n`, `\r` and `\t`)
+    /// in the message as `\u{..}` before it is queued. Off by default.
+    pub fn sanitize_control_chars(mut self, sanitize: bool) -> Self {
+        self.config.message.sanitize_control_chars = sanitize;
+        self
+    }
+
+    /// Also print every record sent to the main source to stdout, with the
+    /// local time in the `chrono` strftime `timestamp_format`, e.g.
+    /// `%H:%M:%S%.3f`. The console time is independent of the `timestamp`
+    /// sent to Better Stack. Off by default.
+    pub fn tee_to_console(mut self, timestamp_format: impl Into<String>) -> Self {
+        self.config.message.console_timestamp_format = Some(timestamp_format.into());
+        self
+    }
+
+    /// Render the `message` field with a log4rs encoder, e.g. the
+    /// `PatternEncoder` of an existing console appender, instead of using
+    /// the record's arguments as is.
+    pub fn message_encoder(mut self, encoder: Box<dyn Encode>) -> Self {
+        self.config.message.encoder = Some(encoder.into());
+        self
+    }
+
+    /// Whether this appender collects the current thread's name. Only has an
+    /// effect when the `threads` feature is enabled. Defaults to `true`.
+    pub fn with_thread_info(mut self, enabled: bool) -> Self {
+        self.config.message.thread_info = enabled;
+        self
+    }
+
+    /// Add an `uptime_ms` field to each record with the milliseconds since
+    /// the appender was built, to see the relative timing of records without
+    /// comparing timestamps. Off by default.
+    pub fn with_uptime(mut self, enabled: bool) -> Self {
+        self.config.message.uptime = enabled;
+        self
+    }
+
+    /// Which clock message timestamps are taken from. Requires the
+    /// `timestamps` feature. Defaults to [`Timestamps::Utc`].
+    #[cfg(feature = "timestamps")]
+    pub fn timestamps(mut self, timestamps: Timestamps) -> Self {
+        self.config.message.timestamps = timestamps;
+        self
+    }
+
+    /// The format of message timestamps, e.g. made with
+    /// `time::macros::format_description!`. Requires the `timestamps`
+    /// feature. Defaults to RFC 3339 with milliseconds.
+    #[cfg(feature = "timestamps")]
+    pub fn timestamp_format(mut self, format: TimestampFormat) -> Self {
+        self.config.message.timestamp_format = Some(format);
+        self
+    }
+
+    /// The thread name sent for threads without a name, or `None` to leave
+    /// `thread` out for them. Only has an effect with the `threads` feature.
+    /// Defaults to `"?"`.
+    pub fn unnamed_thread_label(mut self, label: Option<String>) -> Self {
+        self.config.message.unnamed_thread_label = label;
+        self
+    }
+
+    /// Add a field with a fixed value to every message. A key-value pair on
+    /// the record with the same key takes precedence.
+    pub fn field(mut self, key: impl Into<String>, value: impl Into<Value>) -> Self {
+        self.config
+            .message
+            .static_fields
+            .insert(key.into(), value.into());
+        self
+    }
+
+    /// Add an `instance_id` field with a random UUID generated once per
+    /// process, to tell apart restarts of the same service. Off by default.
+    pub fn with_instance_id(mut self, enabled: bool) -> Self {
+        let fields = &mut self.config.message.static_fields;
+        if enabled {
+            fields.insert("instance_id".to_string(), instance_id().into());
+        } else {
+            fields.remove("instance_id");
+        }
+        self
+    }
+
+    /// Add an `instance_id` field with the given value instead of a random
+    /// one.
+    pub fn with_instance_id_value(self, id: String) -> Self {
+        self.field("instance_id", id)
+    }
+
+    /// Add an `appender` field with the given name, to tell apart the
+    /// messages of several Better Stack appenders that ship to the same
+    /// source, e.g. from different logger trees of a log4rs configuration.
+    pub fn with_appender_name(self, name: String) -> Self {
+        self.field("appender", name)
+    }
+
+    /// Add a `schema_version` field with the given number, to tell which
+    /// version of the payload produced a record while dashboards migrate to
+    /// a new one. Omitted by default.
+    pub fn schema_version(self, version: u32) -> Self {
+        self.field("schema_version", version)
+    }
+
+    /// Cap the number of extra fields (static fields and record key-values)
+    /// per message. Fields beyond the cap are dropped and the message gets a
+    /// `fields_truncated: true` marker. Unlimited by default.
+    pub fn max_fields(mut self, max: usize) -> Self {
+        self.config.message.max_fields = Some(max);
+        self
+    }
+
+    /// Cut messages to at most `max_len` bytes, ending them with `…` and adding
+    /// a `message_truncated: true` field. The limit holds for the message
+    /// after [`sanitize_control_chars`](Self::sanitize_control_chars) escaped
+    /// it. Formatting stops at the limit, so a huge message is never fully
+    /// built on the logging thread. This is best effort: a `Display` impl of
+    /// an argument still runs and may do all of its own work before its output
+    /// is discarded. Unlimited by default.
+    pub fn max_message_len(mut self, max_len: usize) -> Self {
+        self.config.message.max_message_len = Some(max_len);
+        self
+    }
+
+    /// Cut the target, the message, the correlation id and scope, and every
+    /// string value captured from a record to at most `max_len` bytes, ending
+    /// shortened ones with `…`.
+    /// Static fields are left alone. Unlimited by default.
+    pub fn max_field_value_len(mut self, max_len: usize) -> Self {
+        self.config.message.max_field_value_len = Some(max_len);
+        self
+    }
+
+    /// Target used for records with an empty target, instead of falling back
+    /// to the module path.
+    pub fn default_target(mut self, target: impl Into<String>) -> Self {
+        self.config.message.default_target = Some(target.into());
+        self
+    }
+
+    /// Use the record's `key` key-value as the message timestamp, e.g. when
+    /// forwarding or backfilling historical events. The value must be an
+    /// RFC 3339 timestamp; otherwise, or when the key is absent, the current
+    /// time is used.
+    pub fn timestamp_from_kv(mut self, key: impl Into<String>) -> Self {
+        self.config.message.timestamp_key = Some(key.into());
+        self
+    }
+
+    /// Use an existing HTTP client instead of building one. The HTTP tuning
+    /// options below are ignored when a client is provided.
+    pub fn client(mut self, client: Client) -> Self {
+        self.config.client = Some(client);
+        self
+    }
+
+    /// Send HTTP/2 keep-alive pings at this interval. Off by default.
+    pub fn http2_keep_alive_interval(mut self, interval: Duration) -> Self {
+        self.config.http.http2_keep_alive_interval = Some(interval);
+        self
+    }
+
+    /// Let HTTP/2 flow control grow the window with the observed throughput. Off by default.
+    pub fn http2_adaptive_window(mut self, enabled: bool) -> Self {
+        self.config.http.http2_adaptive_window = enabled;
+        self
+    }
+
+    /// Maximum number of idle connections kept per host. Unlimited by default.
+    pub fn pool_max_idle_per_host(mut self, max: usize) -> Self {
+        self.config.http.pool_max_idle_per_host = Some(max);
+        self
+    }
+
+    /// Close connections that have been idle for this long instead of
+    /// reusing them. Set it below the idle timeout of load balancers in front
+    /// of the ingest endpoint, which may drop idle connections without
+    /// closing them. Defaults to reqwest's 90 seconds.
+    pub fn pool_idle_timeout(mut self, timeout: Duration) -> Self {
+        self.config.http.pool_idle_timeout = Some(timeout);
+        self
+    }
+
+    /// Send TCP keep-alive probes on idle connections at this interval, so
+    /// that middleboxes keep them open. Off by default.
+    pub fn tcp_keepalive(mut self, interval: Duration) -> Self {
+        self.config.http.tcp_keepalive = Some(interval);
+        self
+    }
+
+    /// Accept any TLS certificate, including self-signed and expired ones.
+    ///
+    /// **Never use this in production.** It disables the protection against
+    /// man-in-the-middle attacks and is only meant for testing against a
+    /// local mock server. Has no effect when a custom client is provided.
+    pub fn danger_accept_invalid_certs(mut self, accept: bool) -> Self {
+        self.config.http.danger_accept_invalid_certs = accept;
+        self
+    }
+
+    /// Only ship records allowed by a `RUST_LOG`-style filter spec, e.g.
+    /// `myapp=debug,hyper=warn`, applied on top of the log4rs thresholds.
+    /// See [`TargetFilter`] for the syntax. Directives that cannot be parsed
+    /// are ignored and reported to the
+    /// [`diagnostics_sink`](Self::diagnostics_sink) on build.
+    pub fn filter_from_str(mut self, spec: &str) -> Self {
+        let (filter, invalid) = TargetFilter::parse_with_invalid(spec);
+        self.config.filter = Some(filter);
+        self.config.invalid_filter_directives = invalid;
+        self
+    }
+
+    /// Like [`filter_from_str`](Self::filter_from_str), reading the spec from
+    /// the environment variable `var`. Nothing is filtered if it is unset.
+    pub fn filter_from_env(self, var: &str) -> Self {
+        match std::env::var(var) {
+            Ok(spec) => self.filter_from_str(&spec),
+            Err(_) => self,
+        }
+    }
+
+    /// Only ship records at or above the level of the longest target prefix
+    /// in `levels` that matches, or `default` if none does, e.g. `DEBUG` for
+    /// `myapp` and `WARN` for everything else. Replaces any other filter.
+    pub fn target_levels(
+        mut self,
+        levels: HashMap<String, LevelFilter>,
+        default: LevelFilter,
+    ) -> Self {
+        self.config.filter = Some(TargetFilter::from_levels(levels, default));
+        self.config.invalid_filter_directives.clear();
+        self
+    }
+
+    /// Keep only this fraction (`0.0..=1.0`) of the records at `level`. All
+    /// records are kept by default.
+    pub fn sample_rate(mut self, level: Level, rate: f64) -> Self {
+        self.config.sample_rates[level as usize - 1] = rate;
+        self
+    }
+
+    /// Ship records at or above `level` even if the filter or sampling would
+    /// drop them. Pass `LevelFilter::Off` to apply those to every record.
+    /// Floods of them are still subject to
+    /// [`coalesce_repeated`](Self::coalesce_repeated), which counts every
+    /// copy it holds back. Defaults to `ERROR`.
+    pub fn always_ship(mut self, level: LevelFilter) -> Self {
+        self.config.always_ship = level;
+        self
+    }
+
+    /// Seed the sampling RNG so the kept records are reproducible.
+    pub fn sampling_seed(self, seed: u64) -> Self {
+        self.sampling_rng(SmallRng::seed_from_u64(seed))
+    }
+
+    /// Use a custom RNG for sampling. Defaults to a fast, non-cryptographic
+    /// RNG seeded from the operating system.
+    pub fn sampling_rng(mut self, rng: impl RngCore + Send + Sync + 'static) -> Self {
+        self.config.sampling_rng = Some(Box::new(rng));
+        self
+    }
+
+    /// Restart the background task, with exponential backoff, if it panics.
+    /// The batch pending at the time of the panic is lost. Off by default, in
+    /// which case the panic is reported to stderr and logging stops.
+    pub fn restart_on_panic(mut self, restart: bool) -> Self {
+        self.config.restart_on_panic = restart;
+        self
+    }
+
+    /// Every `interval`, send a warning with the number of messages dropped
+    /// because the channel was full since the last one, if any were. Off by
+    /// default.
+    pub fn drop_summary(mut self, interval: Duration) -> Self {
+        self.config.drop_summary_interval = Some(interval);
+        self
+    }
+
+    /// Send an info message with a `heartbeat: true` field every `interval`,
+    /// even when nothing else is logged, so that silence can be alerted on.
+    /// Only sent to the main source. Off by default.
+    pub fn heartbeat(mut self, interval: Duration, message: impl Into<String>) -> Self {
+        self.config.heartbeat = Some((interval, message.into()));
+        self
+    }
+
+    /// Rate-limit floods of identical messages at `level` or more severe,
+    /// e.g. the same error logged for every request while a dependency is
+    /// down. Of the messages with the same level and text, the first
+    /// `threshold` in each `window` are sent, and the rest are counted and
+    /// reported in one summary at the end of the window, with a `coalesced`
+    /// field holding the number not sent. Once a message exceeds the
+    /// threshold only summaries are sent for it, until a window in which it
+    /// stays within the threshold. Unlike the filter and sampling, this also
+    /// applies to the levels of [`always_ship`](Self::always_ship), since
+    /// floods of errors are what it is for. Not applied to the audit route.
+    /// Off by default.
+    pub fn coalesce_repeated(
+        mut self,
+        level: LevelFilter,
+        threshold: u32,
+        window: Duration,
+    ) -> Self {
+        self.config.coalescing = Some(Coalescing {
+            level,
+            threshold,
+            window,
+        });
+        self
+    }
+
+    /// Collapse identical messages within a batch into the first one, which
+    /// gets a `duplicates` field with the total number of copies. Off by
+    /// default.
+    pub fn dedupe_within_batch(mut self, dedupe: bool) -> Self {
+        self.config.dedupe_within_batch = dedupe;
+        self
+    }
+
+    /// Sort each batch by the time its messages were logged before sending
+    /// it, as messages logged concurrently from several threads can be
+    /// enqueued out of order. Off by default.
+    pub fn sort_batch_by_timestamp(mut self, sort: bool) -> Self {
+        self.config.sort_batch_by_timestamp = sort;
+        self
+    }
+
+    /// Keep a batch that failed after its [`retries`](Self::retries) and send
+    /// it again, after the retry backoff doubling up to a minute, and send no
+    /// newer messages until it is delivered, so that they never arrive before it.
+    /// Newer messages join the held batch up to the batch size and then wait
+    /// in the channel, where they are dropped once it is full, and no
+    /// heartbeats are sent meanwhile. An outage thus delays every message
+    /// behind the failed batch instead of only the failed one. Off by default.
+    ///
+    /// Batches rejected with a 4xx status other than 408 and 429 are dropped,
+    /// as are held batches that still fail on shutdown. The order is kept
+    /// within each of the pipelines, not between the main one and the audit
+    /// and tenant routes.
+    pub fn ordered_delivery(mut self, ordered: bool) -> Self {
+        self.config.ordered_delivery = ordered;
+        self
+    }
+
+    /// Check every message before it is sent. Messages for which `validate`
+    /// returns an error are dropped with a warning on stderr, so a single bad
+    /// message cannot get the whole batch rejected.
+    pub fn validate(
+        mut self,
+        validate: impl Fn(&LogMessage) -> Result<(), String> + Send + Sync + 'static,
+    ) -> Self {
+        self.config.validator = Some(Arc::new(validate));
+        self
+    }
+
+    /// Mark the appender unhealthy in [`Status::unhealthy`] when a batch is
+    /// rejected with `401 Unauthorized` or `403 Forbidden`, so that a wrong
+    /// source token fails health checks instead of silently losing logs.
+    /// Off by default.
+    pub fn fail_on_auth_error(mut self, enabled: bool) -> Self {
+        self.config.fail_on_auth_error = enabled;
+        self
+    }
+
+    /// Call `hook` with the error the first time the appender is marked
+    /// unhealthy, e.g. to exit the process. Requires
+    /// [`fail_on_auth_error`](Self::fail_on_auth_error).
+    pub fn on_auth_error(mut self, hook: impl Fn(&SendError) + Send + Sync + 'static) -> Self {
+        self.config.on_auth_error = Some(Arc::new(hook));
+        self
+    }
+
+    /// Report the logger's own problems, like a panicked upload task or a
+    /// rejected source token, to `diagnostics` instead of stderr, e.g. to
+    /// collect them as structured events. Defaults to
+    /// [`DiagnosticsSink::Stderr`].
+    pub fn diagnostics_sink(mut self, diagnostics: DiagnosticsSink) -> Self {
+        self.config.message.diagnostics = diagnostics.clone();
+        self.config.diagnostics = diagnostics;
+        self
+    }
+
+    /// Start the background tasks with `spawner` instead of `tokio::spawn`,
+    /// e.g. to run them on an own executor. The tasks still use Tokio's
+    /// timers and channels, and the HTTP sink Tokio's I/O, so the executor
+    /// must poll them with a multi-threaded Tokio runtime entered, see
+    /// `tokio::runtime::Handle::enter`, whose worker threads drive the
+    /// timers and I/O. The hook of [`install_panic_hook`] does not wait for
+    /// tasks started this way.
+    ///
+    /// To keep the uploads off the application's runtime, e.g.:
+    ///
+    /// ```no_run
+    /// use betterstack_logger::logger::BetterStackAppender;
+    ///
+    /// let uploads = tokio::runtime::Builder::new_multi_thread()
+    ///     .worker_threads(1)
+    ///     .enable_all()
+    ///     .build()
+    ///     .unwrap();
+    /// let handle = uploads.handle().clone();
+    /// let appender = BetterStackAppender::builder(
+    ///     "https://in.logs.betterstack.com".to_string(),
+    ///     "token".to_string(),
+    /// )
+    /// .spawner(move |task| {
+    ///     handle.spawn(task);
+    /// })
+    /// .build();
+    /// ```
+    pub fn spawner(
+        mut self,
+        spawner: impl Fn(BoxFuture<'static, ()>) + Send + Sync + 'static,
+    ) -> Self {
+        self.config.spawner = Some(Arc::new(spawner));
+        self
+    }
+
+    /// Deliver batches to `sink` instead of sending them to the ingest URL
+    /// with an [`HttpSink`]. The audit route, if any, is still sent over HTTP.
+    pub fn with_sink(mut self, sink: Box<dyn Sink>) -> Self {
+        self.config.sink = Some(sink);
+        self
+    }
+
+    /// Duplicate (or divert) matching records to a separate audit source.
+    pub fn audit_route(mut self, route: AuditRoute) -> Self {
+        self.config.audit_route = Some(route);
+        self
+    }
+
+    /// Send the records of each tenant to its own source, see
+    /// [`TenantRouting`]. Each tenant gets its own background task and
+    /// batches, with the settings of the main source, and is sent over
+    /// HTTP even with [`with_sink`](Self::with_sink). Records sent to an
+    /// exclusive audit route are not routed.
+    pub fn tenant_routing(mut self, routing: TenantRouting) -> Self {
+        self.config.tenant_routing = Some(routing);
+        self
+    }
+
+    /// Report failed batches on a bounded channel, see
+    /// [`BetterStackAppender::error_receiver`]. Errors are dropped while the
+    /// channel is full.
+    pub fn error_channel(mut self, capacity: usize) -> Self {
+        self.config.error_channel_capacity = Some(capacity.max(1));
+        self
+    }
+
+    /// The plain-data settings of this builder.
+    pub fn to_config(&self) -> BetterStackConfig {
+        BetterStackConfig {
+            ingest_url: self.config.ingest_url.clone(),
+            source_token: self.config.source_token.clone(),
+            method: self.config.method.clone(),
+            path: self.config.path.clone(),
+            auth_scheme: self.config.auth_scheme.clone(),
+            compression: self.config.compression,
+            compression_min_bytes: self.config.compression_min_bytes,
+            accepted_encodings: self.config.accepted_encodings.clone(),
+            max_request_bytes: self.config.max_request_bytes,
+            max_messages_per_request: self.config.max_messages_per_request,
+            stream_bodies: self.config.stream_bodies,
+            format: self.config.format,
+            envelope: self.config.envelope.clone(),
+            timestamp_field_name: self.config.timestamp_field_name.clone(),
+            key_casing: self.config.key_casing,
+            level_as_object: self.config.level_as_object,
+            missing_line_policy: self.config.missing_line_policy,
+            max_retries: self.config.max_retries,
+            retry_backoff: self.config.retry_backoff,
+            retry_on: self.config.retry_on,
+            max_retry_duration: self.config.max_retry_duration,
+            idempotency_header: self.config.idempotency_header.clone(),
+            #[cfg(feature = "signing")]
+            hmac_signing: self.config.hmac_signing.clone(),
+            clock_skew_threshold: self.config.clock_skew_threshold,
+            flush_interval: self.config.flush_interval,
+            periodic_flush: self.config.periodic_flush,
+            flush_on_start: self.config.flush_on_start,
+            missed_tick_behavior: self.config.missed_tick_behavior,
+            flush_jitter: self.config.flush_jitter,
+            adaptive_flush: self.config.adaptive_flush,
+            batch_size: self.config.batch_size,
+            channel_capacity: self.config.channel_capacity,
+            sample_rates: self.config.sample_rates,
+            always_ship: self.config.always_ship,
+            restart_on_panic: self.config.restart_on_panic,
+            drop_summary_interval: self.config.drop_summary_interval,
+            heartbeat: self.config.heartbeat.clone(),
+            coalescing: self.config.coalescing.clone(),
+            dedupe_within_batch: self.config.dedupe_within_batch,
+            sort_batch_by_timestamp: self.config.sort_batch_by_timestamp,
+            ordered_delivery: self.config.ordered_delivery,
+            fail_on_auth_error: self.config.fail_on_auth_error,
+            sanitize_control_chars: self.config.message.sanitize_control_chars,
+            thread_info: self.config.message.thread_info,
+            uptime: self.config.message.uptime,
+            unnamed_thread_label: self.config.message.unnamed_thread_label.clone(),
+            static_fields: self.config.message.static_fields.clone(),
+            max_fields: self.config.message.max_fields,
+            max_field_value_len: self.config.message.max_field_value_len,
+            max_message_len: self.config.message.max_message_len,
+            timestamp_key: self.config.message.timestamp_key.clone(),
+            default_target: self.config.message.default_target.clone(),
+            tee_to_console: self.config.message.console_timestamp_format.clone(),
+            #[cfg(feature = "timestamps")]
+            timestamps: self.config.message.timestamps,
+            #[cfg(feature = "timestamps")]
+            timestamp_format: self.config.message.timestamp_format,
+        }
+    }
+
+    /// A builder with the given settings.
+    pub fn from_config(config: BetterStackConfig) -> Self {
+        let mut builder = BetterStackAppender::builder(config.ingest_url, config.source_token);
+        builder.config.method = config.method;
+        builder.config.path = config.path;
+        builder.config.auth_scheme = config.auth_scheme;
+        builder.config.compression = config.compression;
+        builder.config.compression_min_bytes = config.compression_min_bytes;
+        builder.config.accepted_encodings = config.accepted_encodings;
+        builder.config.max_request_bytes = config.max_request_bytes;
+        builder.config.max_messages_per_request =
+            config.max_messages_per_request.map(|max| max.max(1));
+        builder.config.stream_bodies = config.stream_bodies;
+        builder.config.format = config.format;
+        builder.config.envelope = config.envelope;
+        builder.config.timestamp_field_name = config.timestamp_field_name;
+        builder.config.key_casing = config.key_casing;
+        builder.config.level_as_object = config.level_as_object;
+        builder.config.missing_line_policy = config.missing_line_policy;
+        builder.config.max_retries = config.max_retries;
+        builder.config.retry_backoff = config.retry_backoff;
+        builder.config.retry_on = config.retry_on;
+        builder.config.max_retry_duration = config.max_retry_duration;
+        builder.config.idempotency_header = config.idempotency_header;
+        #[cfg(feature = "signing")]
+        {
+            builder.config.hmac_signing = config.hmac_signing;
+        }
+        builder.config.clock_skew_threshold = config.clock_skew_threshold;
+        builder.config.flush_interval = config.flush_interval;
+        builder.config.periodic_flush = config.periodic_flush;
+        builder.config.flush_on_start = config.flush_on_start;
+        builder.config.missed_tick_behavior = config.missed_tick_behavior;
+        builder.config.flush_jitter = config.flush_jitter;
+        builder.config.adaptive_flush = config.adaptive_flush;
+        builder.config.batch_size = config.batch_size.max(1);
+        builder.config.channel_capacity = config.channel_capacity.max(1);
+        builder.config.sample_rates = config.sample_rates;
+        builder.config.always_ship = config.always_ship;
+        builder.config.restart_on_panic = config.restart_on_panic;
+        builder.config.drop_summary_interval = config.drop_summary_interval;
+        builder.config.heartbeat = config.heartbeat;
+        builder.config.coalescing = config.coalescing;
+        builder.config.dedupe_within_batch = config.dedupe_within_batch;
+        builder.config.sort_batch_by_timestamp = config.sort_batch_by_timestamp;
+        builder.config.ordered_delivery = config.ordered_delivery;
+        builder.config.fail_on_auth_error = config.fail_on_auth_error;
+        builder.config.message.sanitize_control_chars = config.sanitize_control_chars;
+        builder.config.message.thread_info = config.thread_info;
+        builder.config.message.uptime = config.uptime;
+        builder.config.message.unnamed_thread_label = config.unnamed_thread_label;
+        builder.config.message.static_fields = config.static_fields;
+        builder.config.message.max_fields = config.max_fields;
+        builder.config.message.max_field_value_len = config.max_field_value_len;
+        builder.config.message.max_message_len = config.max_message_len;
+        builder.config.message.timestamp_key = config.timestamp_key;
+        builder.config.message.default_target = config.default_target;
+        builder.config.message.console_timestamp_format = config.tee_to_console;
+        #[cfg(feature = "timestamps")]
+        {
+            builder.config.message.timestamps = config.timestamps;
+            builder.config.message.timestamp_format = config.timestamp_format;
+        }
+        builder
+    }
+
+    /// Builds the appender and spawns its background tasks.
+    ///
+    /// # Panics
+    ///
+    /// If the settings contradict each other, see [`try_build`](Self::try_build).
+    pub fn build(self) -> BetterStackAppender {
+        self.spawn().0
+    }
+
+    /// Like [`build`](Self::build), but returns an error instead of
+    /// panicking if the settings contradict each other, see [`ConfigError`].
+    pub fn try_build(self) -> Result<BetterStackAppender, ConfigError> {
+        self.check()?;
+        Ok(self.build())
+    }
+
+    /// Checks the settings for contradictory combinations, like an
+    /// [`Envelope`] with [`Format::Ndjson`], that would otherwise produce
+    /// requests the server rejects or settings that are silently ignored.
+    pub fn check(&self) -> Result<(), ConfigError> {
+        let config = &self.config;
+        if config.envelope.is_some() && config.format == Format::Ndjson {
+            return Err(ConfigError::EnvelopeWithNdjson);
+        }
+        if config.envelope.is_some() && config.batch_transform.is_some() {
+            return Err(ConfigError::EnvelopeWithBatchTransform);
+        }
+        if config.compression != Compression::None && config.compressor.is_some() {
+            return Err(ConfigError::CompressionWithCompressor);
+        }
+        if let Some(header) = &config.idempotency_header {
+            let mut reserved = vec![CONTENT_TYPE, CONTENT_LENGTH, CONTENT_ENCODING];
+            if config.auth_scheme == AuthScheme::Bearer {
+                reserved.push(AUTHORIZATION);
+            }
+            if reserved
+                .iter()
+                .any(|name| name.as_str().eq_ignore_ascii_case(header))
+            {
+                return Err(ConfigError::IdempotencyHeaderConflict {
+                    header: header.clone(),
+                });
+            }
+        }
+        if config.method == Method::GET || config.method == Method::HEAD {
+            return Err(ConfigError::MethodWithoutBody {
+                method: config.method.clone(),
+            });
+        }
+        if let Some(path) = &config.path {
+            let audit = config.audit_route.iter().map(|route| &route.ingest_url);
+            let tenants = config
+                .tenant_routing
+                .iter()
+                .flat_map(|routing| routing.tenants.values().map(|(url, _)| url));
+            for base in std::iter::once(&config.ingest_url)
+                .chain(audit)
+                .chain(tenants)
+            {
+                let url = join_path(base, path);
+                if let Err(error) = reqwest::Url::parse(&url) {
+                    return Err(ConfigError::InvalidIngestUrl {
+                        url,
+                        reason: error.to_string(),
+                    });
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// The [`HttpSink`] that [`build`](Self::build) sends with, e.g. to
+    /// benchmark [`HttpSink::serialize_batch`] with the configured format and
+    /// compression. Ignores [`with_sink`](Self::with_sink).
+    pub fn build_http_sink(&self) -> HttpSink {
+        let client = self
+            .config
+            .client
+            .clone()
+            .unwrap_or_else(|| self.config.http.build_client());
+        self.config.http_sink(client)
+    }
+
+    /// The configuration of a further source, the audit route or a tenant,
+    /// with the settings of the main one.
+    fn route_config(&self, ingest_url: String, source_token: String) -> Config {
+        Config {
+            flush_interval: self.config.flush_interval,
+            periodic_flush: self.config.periodic_flush,
+            flush_on_start: self.config.flush_on_start,
+            missed_tick_behavior: self.config.missed_tick_behavior,
+            batch_size: self.config.batch_size,
+            channel_capacity: self.config.channel_capacity,
+            restart_on_panic: self.config.restart_on_panic,
+            method: self.config.method.clone(),
+            path: self.config.path.clone(),
+            auth_scheme: self.config.auth_scheme.clone(),
+            compression: self.config.compression,
+            compressor: self.config.compressor.clone(),
+            compression_min_bytes: self.config.compression_min_bytes,
+            accepted_encodings: self.config.accepted_encodings.clone(),
+            max_request_bytes: self.config.max_request_bytes,
+            max_messages_per_request: self.config.max_messages_per_request,
+            stream_bodies: self.config.stream_bodies,
+            format: self.config.format,
+            envelope: self.config.envelope.clone(),
+            timestamp_field_name: self.config.timestamp_field_name.clone(),
+            key_casing: self.config.key_casing,
+            level_as_object: self.config.level_as_object,
+            missing_line_policy: self.config.missing_line_policy,
+            on_batch_sent: self.config.on_batch_sent.clone(),
+            batch_transform: self.config.batch_transform.clone(),
+            max_retries: self.config.max_retries,
+            retry_backoff: self.config.retry_backoff,
+            retry_on: self.config.retry_on,
+            max_retry_duration: self.config.max_retry_duration,
+            idempotency_header: self.config.idempotency_header.clone(),
+            #[cfg(feature = "signing")]
+            hmac_signing: self.config.hmac_signing.clone(),
+            clock_skew_threshold: self.config.clock_skew_threshold,
+            flush_jitter: self.config.flush_jitter,
+            adaptive_flush: self.config.adaptive_flush,
+            drop_summary_interval: self.config.drop_summary_interval,
+            dedupe_within_batch: self.config.dedupe_within_batch,
+            sort_batch_by_timestamp: self.config.sort_batch_by_timestamp,
+            ordered_delivery: self.config.ordered_delivery,
+            validator: self.config.validator.clone(),
+            fail_on_auth_error: self.config.fail_on_auth_error,
+            on_auth_error: self.config.on_auth_error.clone(),
+            diagnostics: self.config.diagnostics.clone(),
+            spawner: self.config.spawner.clone(),
+            error_sender: self.config.error_sender.clone(),
+            counters: self.config.counters.clone(),
+            ..Config::new(ingest_url, source_token)
+        }
+    }
+
+    /// Like [`build`](Self::build), but also returns a handle to shut the
+    /// background tasks down gracefully.
+    ///
+    /// # Panics
+    ///
+    /// If the settings contradict each other, see [`try_build`](Self::try_build).
+    pub fn spawn(mut self) -> (BetterStackAppender, ShutdownHandle) {
+        if let Err(error) = self.check() {
+            panic!("invalid BetterStackAppender settings: {error}");
+        }
+        for directive in std::mem::take(&mut self.config.invalid_filter_directives) {
+            self.config.diagnostics.report(
+                DiagnosticKind::InvalidFilterDirective,
+                format!("ignoring invalid log filter directive `{directive}`"),
+            );
+        }
+        let error_receiver = self.config.error_channel_capacity.map(|capacity| {
+            let (sender, receiver) = mpsc::channel(capacity);
+            self.config.error_sender = Some(sender);
+            receiver
+        });
+        self.config.message.started = Instant::now();
+        let options = self.config.message.clone();
+        let client = self
+            .config
+            .client
+            .take()
+            .unwrap_or_else(|| self.config.http.build_client());
+        let sampler = Sampler {
+            rates: self.config.sample_rates,
+            rng: Mutex::new(
+                self.config
+                    .sampling_rng
+                    .take()
+                    .unwrap_or_else(|| Box::new(SmallRng::from_os_rng())),
+            ),
+        };
+
+        let mut tasks = Vec::new();
+        let counters = self.config.counters.clone();
+        let filter = self.config.filter.take();
+        let audit = self.config.audit_route.take().map(|route| {
+            let config = self.route_config(route.ingest_url.clone(), route.source_token.clone());
+            let sink = config.http_sink(client.clone());
+            let (pipeline, task) = Pipeline::spawn(Box::new(sink), config);
+            tasks.push((pipeline.control.clone(), task));
+            (route, pipeline)
+        });
+        let tenants = self.config.tenant_routing.take().map(|routing| {
+            let mut pipelines = HashMap::new();
+            for (id, (ingest_url, source_token)) in routing.tenants {
+                let mut config = self.route_config(ingest_url, source_token);
+                config.coalescing = self.config.coalescing.clone();
+                let sink = config.http_sink(client.clone());
+                let (pipeline, task) = Pipeline::spawn(Box::new(sink), config);
+                tasks.push((pipeline.control.clone(), task));
+                pipelines.insert(id, pipeline);
+            }
+            (routing.selector, pipelines)
+        });
+
+        let sink = self
+            .config
+            .sink
+            .take()
+            .unwrap_or_else(|| Box::new(self.config.http_sink(client)));
+
+        let always_ship = self.config.always_ship;
+        let (pipeline, task) = Pipeline::spawn(sink, self.config);
+        tasks.push((pipeline.control.clone(), task));
+
+        let appender = BetterStackAppender {
+            pipeline: Arc::new(pipeline),
+            audit,
+            tenants,
+            options,
+            gate: Arc::new(Gate {
+                filter,
+                sampler,
+                always_ship,
+            }),
+            error_receiver: Mutex::new(error_receiver),
+            counters,
+        };
+        (appender, ShutdownHandle { tasks })
+    }
+}
+
+impl BetterStackAppender {
+    pub fn new(ingest_url: String, source_token: String) -> BetterStackAppender {
+        Self::builder(ingest_url, source_token).build()
+    }
+
+    /// Create an appender from a [`BetterStackConfig`].
+    pub fn from_config(config: BetterStackConfig) -> BetterStackAppender {
+        BetterStackAppenderBuilder::from_config(config).build()
+    }
+
+    pub fn builder(ingest_url: String, source_token: String) -> BetterStackAppenderBuilder {
+        BetterStackAppenderBuilder {
+            config: Config::new(ingest_url, source_token),
+        }
+    }
+
+    /// A `tracing` layer that ships events through this appender's uploader,
+    /// with the same filter, sampling and message limits as `log` records,
+    /// see [`BetterStackLayer`](crate::layer::BetterStackLayer).
+    #[cfg(feature = "tracing")]
+    pub fn tracing_layer(&self) -> crate::layer::BetterStackLayer {
+        crate::layer::BetterStackLayer::new(
+            self.pipeline.clone(),
+            self.options.clone(),
+            self.gate.clone(),
+        )
+    }
+
+    /// An [`io::Write`](std::io::Write) sink that ships each written line
+    /// through this appender's uploader, with the static fields added and
+    /// timestamped like records.
+    pub fn writer(&self) -> BetterStackWriter {
+        BetterStackWriter::new(self.pipeline.clone(), self.options.clone())
+    }
+
+    /// Returns a snapshot of the appender's health counters.
+    pub fn status(&self) -> Status {
+        let mut dropped = 0;
+        let mut pipeline_dead = false;
+        self.each_pipeline(|pipeline| {
+            dropped += pipeline.dropped.load(Ordering::Relaxed);
+            pipeline_dead |= pipeline.sender.is_closed();
+        });
+        self.counters.status(dropped, pipeline_dead)
+    }
+
+    /// When a batch was last delivered successfully, to any source. `None`
+    /// until the first success.
+    pub fn last_success_at(&self) -> Option<SystemTime> {
+        self.counters.last_success_at()
+    }
+
+    /// Batch sizes, fill times and flush reasons of the last flushes of the
+    /// main source, for tuning the batch size and flush interval.
+    pub fn recent_stats(&self) -> RecentStats {
+        self.pipeline.flush_window.lock().unwrap().stats()
+    }
+
+    /// Takes the receiving end of the error channel configured with
+    /// [`BetterStackAppenderBuilder::error_channel`]. Returns `None` if no
+    /// channel was configured or it was already taken.
+    pub fn error_receiver(&self) -> Option<mpsc::Receiver<SendError>> {
+        self.error_receiver.lock().unwrap().take()
+    }
+
+    /// Sends the pending messages if the oldest of them has waited for the
+    /// flush interval, for driving the periodic flush from an own loop
+    /// together with
+    /// [`disable_periodic_flush`](BetterStackAppenderBuilder::disable_periodic_flush).
+    /// Full batches are still sent right away. Does not wait for the send.
+    pub fn poll_flush_if_due(&self) {
+        self.each_pipeline(|pipeline| pipeline.command(Command::FlushIfDue));
+    }
+
+    /// Waits until every message logged before the call has been sent, without
+    /// shutting the appender down. Returns early if a background task is gone.
+    pub async fn drain(&self) {
+        for pipeline in self.pipelines() {
+            pipeline.drain().await;
+        }
+    }
+
+    /// Holds messages instead of sending them until [`resume`](Self::resume),
+    /// e.g. around a noisy operation or to capture the logs of a test.
+    /// Messages keep being accepted, but only up to the channel capacity;
+    /// beyond that they are dropped as with any backpressure. While paused,
+    /// flushing does nothing and [`drain`](Self::drain) returns right away.
+    /// Shutting down sends the held messages.
+    pub fn pause(&self) {
+        self.each_pipeline(|pipeline| pipeline.command(Command::Pause));
+    }
+
+    /// Sends messages again after [`pause`](Self::pause), starting with the
+    /// held ones at the next flush.
+    pub fn resume(&self) {
+        self.each_pipeline(|pipeline| pipeline.command(Command::Resume));
+    }
+
+    /// Drops every message logged so far that has not been sent yet, e.g.
+    /// the ones held since [`pause`](Self::pause).
+    pub fn discard_buffered(&self) {
+        let now = Instant::now();
+        self.each_pipeline(|pipeline| pipeline.command(Command::Discard(now)));
+    }
+
+    /// Drops the messages that are not sent yet and match `predicate`, e.g.
+    /// one that was just logged with data it should not contain, and returns
+    /// how many were dropped. Returns 0 if a background task is gone.
+    ///
+    /// This is best-effort: messages that were sent already cannot be
+    /// recalled. A batch whose send is in progress when the call is made,
+    /// including its retries, is not purged, as the background task only
+    /// picks up the purge once the send returns. Messages logged after the
+    /// background task picked it up are not purged either. Messages held
+    /// since [`pause`](Self::pause) are purged.
+    ///
+    /// ```no_run
+    /// # async fn example(appender: &betterstack_logger::logger::BetterStackAppender) {
+    /// let purged = appender
+    ///     .purge(|message| message.message.contains("password="))
+    ///     .await;
+    /// # }
+    /// ```
+    pub async fn purge(
+        &self,
+        predicate: impl Fn(&LogMessage) -> bool + Send + Sync + 'static,
+    ) -> usize {
+        let predicate: PurgePredicate = Arc::new(predicate);
+        let mut purged = 0;
+        for pipeline in self.pipelines() {
+            purged += pipeline.purge(predicate.clone()).await;
+        }
+        purged
+    }
+
+    /// A handle to change the batch size and flush interval while the
+    /// appender runs, e.g. to send smaller batches more often during peak
+    /// hours.
+    pub fn config_handle(&self) -> ConfigHandle {
+        let mut controls = Vec::new();
+        self.each_pipeline(|pipeline| controls.push(pipeline.control.clone()));
+        ConfigHandle { controls }
+    }
+
+    /// A handle to [`drain`](Self::drain) the appender after it was handed
+    /// to log4rs, e.g. at the end of each request of a short-lived process.
+    pub fn drain_handle(&self) -> DrainHandle {
+        let mut controls = Vec::new();
+        self.each_pipeline(|pipeline| controls.push(pipeline.control.clone()));
+        DrainHandle { controls }
+    }
+
+    /// A handle to check on and control the appender after it was handed to
+    /// log4rs, e.g. for a health check endpoint.
+    pub fn control_handle(&self) -> ControlHandle {
+        let (mut controls, mut dropped) = (Vec::new(), Vec::new());
+        self.each_pipeline(|pipeline| {
+            controls.push(pipeline.control.clone());
+            dropped.push(pipeline.dropped.clone());
+        });
+        ControlHandle {
+            controls,
+            dropped,
+            counters: self.counters.clone(),
+        }
+    }
+
+    fn each_pipeline(&self, f: impl FnMut(&Pipeline)) {
+        self.pipelines().for_each(f);
+    }
+
+    /// The main pipeline, then the audit and tenant ones.
+    fn pipelines(&self) -> impl Iterator<Item = &Pipeline> {
+        let audit = self.audit.as_ref().map(|(_, audit)| audit);
+        let tenants = self
+            .tenants
+            .iter()
+            .flat_map(|(_, tenants)| tenants.values());
+        std::iter::once(&*self.pipeline).chain(audit).chain(tenants)
+    }
+
+    async fn supervise(
+        mut receiver: mpsc::Receiver<LogMessage>,
+        mut control: mpsc::UnboundedReceiver<Command>,
+        sink: Box<dyn Sink>,
+        mut config: Config,
+        dropped: Arc<AtomicU64>,
+    ) {
+        let mut backoff = Duration::from_secs(1);
+        let mut reported_drops = 0;
+
+        loop {
+            let run = Self::run(
+                &mut receiver,
+                &mut control,
+                sink.as_ref(),
+                &mut config,
+                &dropped,
+                &mut reported_drops,
+            );
+            let Err(panic) = AssertUnwindSafe(run).catch_unwind().await else {
+                break;
+            };
+
+            config.diagnostics.report(
+                DiagnosticKind::TaskPanicked,
+                format!("background task panicked: {}", panic_message(&*panic)),
+            );
+            if !config.restart_on_panic {
+                break;
+            }
+            time::sleep(backoff).await;
+            backoff = (backoff * 2).min(Duration::from_secs(60));
+        }
+    }
+
+    async fn run(
+        receiver: &mut mpsc::Receiver<LogMessage>,
+        control: &mut mpsc::UnboundedReceiver<Command>,
+        sink: &dyn Sink,
+        config: &mut Config,
+        dropped: &AtomicU64,
+        reported_drops: &mut u64,
+    ) {
+        let mut timer = FlushTimer::new(config);
+        let mut batch = Batch::default();
+        let mut drop_summary = config
+            .drop_summary_interval
+            .map(|period| time::interval_at(time::Instant::now() + period, period));
+        let mut heartbeat = config
+            .heartbeat
+            .as_ref()
+            .map(|(period, _)| time::interval_at(time::Instant::now() + *period, *period));
+        let mut coalescer = config.coalescing.clone().map(Coalescer::new);
+        let mut coalescing_window = config
+            .coalescing
+            .as_ref()
+            .map(|rule| time::interval_at(time::Instant::now() + rule.window, rule.window));
+
+        let mut paused = false;
+        loop {
+            tokio::select! {
+                // Held messages wait in the channel while paused or behind a
+                // full batch held for ordered delivery, until the appender
+                // shuts down or is dropped.
+                msg = receiver.recv(), if !(paused || batch.is_held_full(config)) || receiver.is_closed() => match msg {
+                    Some(msg) => {
+                        if coalescer.as_mut().is_none_or(|coalescer| coalescer.admit(&msg)) {
+                            batch.push(msg);
+                        }
+                        if batch.messages.len() >= config.batch_size {
+                            Self::flush_batch(sink, config, &mut batch, FlushReason::BatchFull).await;
+                        }
+                    }
+                    None => {
+                        if let Some(coalescer) = &mut coalescer {
+                            coalescer.end_window(&config.message, &mut batch);
+                        }
+                        Self::flush_batch(sink, config, &mut batch, FlushReason::Shutdown).await;
+                        break;
+                    }
+                },
+                Some(command) = control.recv() => match command {
+                    Command::Flush if !paused => {
+                        Self::flush_batch(sink, config, &mut batch, FlushReason::Explicit).await
+                    }
+                    Command::Flush => {}
+                    Command::Drain(done) if paused => {
+                        let _ = done.send(());
+                    }
+                    Command::Drain(done) => loop {
+                        Self::take_queued(receiver, sink, config, &mut batch, &mut coalescer).await;
+                        let was_held = batch.held.is_some();
+                        Self::flush_batch(sink, config, &mut batch, FlushReason::Explicit).await;
+                        // Once a held batch is delivered, the messages waiting
+                        // behind it are next.
+                        if !was_held || batch.held.is_some() {
+                            let _ = done.send(());
+                            break;
+                        }
+                    },
+                    Command::FlushIfDue if !paused => {
+                        Self::take_queued(receiver, sink, config, &mut batch, &mut coalescer).await;
+                        let due = batch
+                            .started
+                            .is_some_and(|started| started.elapsed() >= config.flush_interval);
+                        if due {
+                            Self::flush_batch(sink, config, &mut batch, FlushReason::Interval).await;
+                        }
+                    }
+                    Command::FlushIfDue => {}
+                    // The receiver yields the queued messages, then `None`.
+                    Command::Shutdown => receiver.close(),
+                    Command::Pause => paused = true,
+                    Command::Resume => paused = false,
+                    Command::Discard(until) => {
+                        // Messages logged after the call may already be queued.
+                        batch.messages.retain(|message| message.enqueued_at > until);
+                        while let Ok(msg) = receiver.try_recv() {
+                            if msg.enqueued_at > until {
+                                batch.push(msg);
+                            }
+                        }
+                    }
+                    Command::Purge(predicate, done) => {
+                        let before = batch.messages.len();
+                        batch.messages.retain(|message| !predicate(message));
+                        let mut purged = before - batch.messages.len();
+                        // Refilling may exceed the batch size, which the
+                        // next message or tick flushes.
+                        while let Ok(msg) = receiver.try_recv() {
+                            if predicate(&msg) {
+                                purged += 1;
+                            } else {
+                                batch.push(msg);
+                            }
+                        }
+                        let _ = done.send(purged);
+                    }
+                    // Kept in the config so that they survive a restart after a panic.
+                    Command::SetBatchSize(batch_size) => {
+                        config.batch_size = batch_size;
+                        if batch.messages.len() >= batch_size && !paused {
+                            Self::flush_batch(sink, config, &mut batch, FlushReason::BatchFull).await;
+                        }
+                    }
+                    Command::SetFlushInterval(interval) => {
+                        config.flush_interval = interval;
+                        timer.set_period(interval);
+                    }
+                },
+                _ = timer.tick(), if !paused => {
+                    timer.adapt(batch.messages.len() + receiver.len(), config.batch_size);
+                    // An idle tick only moves the timer: no request, no flush
+                    // window entry, and the send statistics stay as they are.
+                    if !batch.messages.is_empty() {
+                        Self::flush_batch(sink, config, &mut batch, FlushReason::Interval).await
+                    }
+                }
+                _ = retry_due(&batch), if !paused => {
+                    Self::flush_batch(sink, config, &mut batch, FlushReason::Retry).await
+                }
+                _ = next_tick(&mut heartbeat), if !paused => {
+                    // A held batch takes no heartbeats.
+                    if batch.held.is_some() {
+                        continue;
+                    }
+                    let message = config.heartbeat.as_ref().map(|(_, message)| message.clone());
+                    batch.push(internal_message(
+                        &config.message,
+                        Level::Info,
+                        message.unwrap_or_default(),
+                        "heartbeat",
+                        Value::Bool(true),
+                    ));
+                    Self::flush_batch(sink, config, &mut batch, FlushReason::Heartbeat).await;
+                }
+                _ = next_tick(&mut coalescing_window) => {
+                    if let Some(coalescer) = &mut coalescer {
+                        coalescer.end_window(&config.message, &mut batch);
+                    }
+                    if batch.messages.len() >= config.batch_size && !paused {
+                        Self::flush_batch(sink, config, &mut batch, FlushReason::BatchFull).await;
+                    }
+                }
+                _ = next_tick(&mut drop_summary) => {
+                    let total = dropped.load(Ordering::Relaxed);
+                    if total > *reported_drops {
+                        let dropped = total - *reported_drops;
+                        let interval = config.drop_summary_interval.unwrap_or_default();
+                        batch.push(internal_message(
+                            &config.message,
+                            Level::Warn,
+                            format!(
+                                "dropped {dropped} log messages in the last {interval:?} due to backpressure"
+                            ),
+                            "dropped",
+                            dropped.into(),
+                        ));
+                        *reported_drops = total;
+                        if batch.messages.len() >= config.batch_size && !paused {
+                            Self::flush_batch(sink, config, &mut batch, FlushReason::BatchFull).await;
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    /// Moves the messages waiting in the channel to the batch, sending it
+    /// whenever it is full, until a held batch is full.
+    async fn take_queued(
+        receiver: &mut mpsc::Receiver<LogMessage>,
+        sink: &dyn Sink,
+        config: &Config,
+        batch: &mut Batch,
+        coalescer: &mut Option<Coalescer>,
+    ) {
+        while !batch.is_held_full(config) {
+            let Ok(msg) = receiver.try_recv() else {
+                break;
+            };
+            if coalescer
+                .as_mut()
+                .is_some_and(|coalescer| !coalescer.admit(&msg))
+            {
+                continue;
+            }
+            batch.push(msg);
+            if batch.messages.len() >= config.batch_size {
+                Self::flush_batch(sink, config, batch, FlushReason::BatchFull).await;
+            }
+        }
+    }
+
+    async fn flush_batch(sink: &dyn Sink, config: &Config, batch: &mut Batch, reason: FlushReason) {
+        // A held batch is only sent when its retry is due, or on request.
+        if batch.held.is_some()
+            && matches!(
+                reason,
+                FlushReason::BatchFull | FlushReason::Interval | FlushReason::Heartbeat
+            )
+        {
+            return;
+        }
+        let fill_time = batch.started.take().map(|started| started.elapsed());
+        if let Some(validate) = &config.validator {
+            batch.messages.retain(|message| match validate(message) {
+                Ok(()) => true,
+                Err(reason) => {
+                    config.diagnostics.report(
+                        DiagnosticKind::InvalidMessage,
+                        format!("dropping invalid log message: {reason}"),
+                    );
+                    false
+                }
+            });
+        }
+        if config.dedupe_within_batch {
+            dedupe(&mut batch.messages);
+        }
+        if config.sort_batch_by_timestamp {
+            batch.messages.sort_by_key(|message| message.enqueued_at);
+        }
+        if batch.messages.is_empty() {
+            batch.held = None;
+            return;
+        }
+        config.flush_window.lock().unwrap().record(FlushEntry {
+            reason,
+            size: batch.messages.len(),
+            fill_time: fill_time.unwrap_or_default(),
+        });
+
+        match sink.send(&batch.messages).await {
+            Ok(()) => {
+                let now = SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .unwrap_or_default();
+                config
+                    .counters
+                    .last_success_ms
+                    .store(now.as_millis() as u64, Ordering::Relaxed);
+            }
+            Err(error) => {
+                if config.fail_on_auth_error
+                    && matches!(
+                        error.status,
+                        Some(StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN)
+                    )
+                    && !config.counters.unhealthy.swap(true, Ordering::Relaxed)
+                {
+                    config.diagnostics.report(
+                        DiagnosticKind::AuthRejected,
+                        format!("the source token was rejected: {error}"),
+                    );
+                    if let Some(hook) = &config.on_auth_error {
+                        hook(&error);
+                    }
+                }
+                let hold = config.ordered_delivery
+                    && reason != FlushReason::Shutdown
+                    && error.status.is_none_or(|status| {
+                        !status.is_client_error()
+                            || matches!(
+                                status,
+                                StatusCode::REQUEST_TIMEOUT | StatusCode::TOO_MANY_REQUESTS
+                            )
+                    });
+                if hold {
+                    batch.hold(error.delivered, config.retry_backoff);
+                }
+                if let Some(errors) = &config.error_sender {
+                    let _ = errors.try_send(error);
+                }
+                if hold {
+                    return;
+                }
+            }
+        }
+
+        batch.held = None;
+        batch.messages.clear();
+    }
 }
 
-#[derive(Debug, serde::Serialize)]
-struct LogMessage {
-    timestamp: String,
-    level: String,
-    target: String,
-    thread: Option<String>,
-    message: String,
-    module_path: Option<String>,
-    file: Option<String>,
-    line: Option<u32>,
+fn panic_message(panic: &(dyn Any + Send)) -> &str {
+    if let Some(message) = panic.downcast_ref::<&str>() {
+        message
+    } else if let Some(message) = panic.downcast_ref::<String>() {
+        message
+    } else {
+        "unknown panic"
+    }
 }
 
-#[derive(serde::Serialize)]
-struct ThreadInfo {
-    id: String,
-    name: Option<String>,
+/// The configuration of a [`BetterStackAppender`] in a log4rs config file.
+#[derive(Clone, Eq, PartialEq, Hash, Debug, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct BetterStackAppenderConfig {
+    url: String,
+    token: String,
+    batch_size: Option<usize>,
+    channel_capacity: Option<usize>,
+    flush_interval_ms: Option<u64>,
+    periodic_flush: Option<bool>,
+    sanitize_control_chars: Option<bool>,
+    thread_info: Option<bool>,
+    appender_name: Option<String>,
+    encoder: Option<EncoderConfig>,
 }
 
-impl fmt::Debug for BetterStackAppender {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        f.debug_struct("BetterStackAppender")
-            .field("sender", &self.sender)
-            .finish()
+/// A deserializer for the `BetterStackAppender`, registered with
+/// `deserializers.insert("betterstack", BetterStackAppenderDeserializer)`.
+///
+/// The appender spawns its background task on deserialization, so the config
+/// must be loaded from within a Tokio runtime.
+///
+/// # Configuration
+///
+/// ```yaml
+/// kind: betterstack
+///
+/// # The ingest URL and source token of the Better Stack source. Required.
+/// url: https://in.logs.betterstack.com
+/// token: my-source-token
+///
+/// # Number of messages that triggers a send. Defaults to 1000.
+/// batch_size: 1000
+///
+/// # Number of messages waiting for the background task before new ones are
+/// # dropped. Defaults to 100.
+/// channel_capacity: 100
+///
+/// # How often the pending batch is sent, in milliseconds. Defaults to 3000.
+/// flush_interval_ms: 3000
+///
+/// # Set to false to only send full batches and on explicit flushes. Defaults to true.
+/// periodic_flush: true
+///
+/// # Escape control characters in messages. Defaults to false.
+/// sanitize_control_chars: false
+///
+/// # Include the thread name (requires the `threads` feature). Defaults to true.
+/// thread_info: true
+///
+/// # Added to every message as the `appender` field. Not set by default.
+/// appender_name: betterstack
+///
+/// # The encoder used to render the message. Defaults to the record's
+/// # arguments as is.
+/// encoder:
+///   pattern: "[{X(request_id)}] {m}"
+/// ```
+#[derive(Copy, Clone, Eq, PartialEq, Hash, Debug, Default)]
+pub struct BetterStackAppenderDeserializer;
+
+impl Deserialize for BetterStackAppenderDeserializer {
+    type Trait = dyn Append;
+
+    type Config = BetterStackAppenderConfig;
+
+    fn deserialize(
+        &self,
+        config: BetterStackAppenderConfig,
+        deserializers: &Deserializers,
+    ) -> anyhow::Result<Box<dyn Append>> {
+        let mut appender = BetterStackAppender::builder(config.url, config.token);
+        if let Some(batch_size) = config.batch_size {
+            appender = appender.batch_size(batch_size);
+        }
+        if let Some(capacity) = config.channel_capacity {
+            appender = appender.channel_capacity(capacity);
+        }
+        if let Some(interval) = config.flush_interval_ms {
+            appender = appender.flush_interval(Duration::from_millis(interval));
+        }
+        if config.periodic_flush == Some(false) {
+            appender = appender.disable_periodic_flush();
+        }
+        if let Some(sanitize) = config.sanitize_control_chars {
+            appender = appender.sanitize_control_chars(sanitize);
+        }
+        if let Some(enabled) = config.thread_info {
+            appender = appender.with_thread_info(enabled);
+        }
+        if let Some(name) = config.appender_name {
+            appender = appender.with_appender_name(name);
+        }
+        if let Some(encoder) = config.encoder {
+            appender =
+                appender.message_encoder(deserializers.deserialize(&encoder.kind, encoder.config)?);
+        }
+        Ok(Box::new(appender.try_build()?))
     }
 }
 
-impl Append for BetterStackAppender {
-    fn append(&self, record: &Record) -> anyhow::Result<()> {
-        let log_message = build_log_message(record);
+/// The messages waiting to be sent by the background task.
+#[derive(Default)]
+struct Batch {
+    messages: Vec<LogMessage>,
+    /// When the oldest of the messages was logged.
+    started: Option<Instant>,
+    /// When the batch, which failed, is sent again with ordered delivery,
+    /// and the delay before that attempt.
+    held: Option<(time::Instant, Duration)>,
+}
 
-        let _ = self.sender.try_send(log_message).ok(); // TODO Proper error handling
-        Ok(())
+impl Batch {
+    fn push(&mut self, message: LogMessage) {
+        let enqueued_at = message.enqueued_at;
+        self.started = Some(
+            self.started
+                .map_or(enqueued_at, |started| started.min(enqueued_at)),
+        );
+        self.messages.push(message);
     }
 
-    fn flush(&self) {
-        // Handle flushing if necessary
+    /// Keeps the undelivered messages after a failed send, to retry them
+    /// after a backoff that doubles with every failure.
+    fn hold(&mut self, delivered: usize, retry_backoff: Duration) {
+        self.messages.drain(..delivered.min(self.messages.len()));
+        self.started = self
+            .messages
+            .iter()
+            .map(|message| message.enqueued_at)
+            .min();
+        let backoff = match self.held {
+            Some((_, backoff)) => backoff.saturating_mul(2).min(MAX_HELD_BACKOFF),
+            None => retry_backoff,
+        };
+        let backoff = backoff.max(Duration::from_millis(1));
+        self.held = Some((time::Instant::now() + backoff, backoff));
+    }
+
+    /// Whether the batch is held for ordered delivery and takes no more
+    /// messages.
+    fn is_held_full(&self, config: &Config) -> bool {
+        self.held.is_some() && self.messages.len() >= config.batch_size
     }
 }
 
-impl BetterStackAppender {
-    pub fn new(ingest_url: String, source_token: String) -> BetterStackAppender {
-        let (sender, mut receiver) = mpsc::channel(100); // TODO should this be configurable?
-        let client = Client::new();
-
-        tokio::spawn(async move {
-            let mut interval = time::interval(Duration::from_secs(3));
-            let mut batch: Vec<LogMessage> = Vec::new();
-
-            loop {
-                tokio::select! {
-                    Some(msg) = receiver.recv() => {
-                        batch.push(msg);
-                        if batch.len() >= 1000 { // TODO should this be configurable?
-                            Self::send_batch(&client, &ingest_url, &source_token, &mut batch).await;
-                        }
-                    }
-                    _ = interval.tick() => {
-                        if !batch.is_empty() {
-                            Self::send_batch(&client, &ingest_url, &source_token, &mut batch).await;
-                        }
-                    }
-                }
+/// The longest delay between the attempts to send a held batch.
+const MAX_HELD_BACKOFF: Duration = Duration::from_secs(60);
+
+/// Completes when a batch held for ordered delivery is due to be sent again.
+async fn retry_due(batch: &Batch) {
+    match batch.held {
+        Some((at, _)) => time::sleep_until(at).await,
+        None => std::future::pending().await,
+    }
+}
+
+/// Why the background task sent a batch.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum FlushReason {
+    /// The batch reached the batch size.
+    BatchFull,
+    /// The flush interval elapsed.
+    Interval,
+    /// `flush` or `drain` was called.
+    Explicit,
+    /// The appender was shut down or dropped.
+    Shutdown,
+    /// A heartbeat was sent, see [`BetterStackAppenderBuilder::heartbeat`].
+    Heartbeat,
+    /// A batch that failed was sent again, see
+    /// [`BetterStackAppenderBuilder::ordered_delivery`].
+    Retry,
+}
+
+/// Aggregates over the most recent flushes, see
+/// [`BetterStackAppender::recent_stats`].
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct RecentStats {
+    /// Number of flushes covered, at most 64.
+    pub flushes: usize,
+    /// Average number of messages per flush.
+    pub average_batch_size: f64,
+    /// Average time from the oldest message of a batch being logged until
+    /// the batch was sent.
+    pub average_fill_time: Duration,
+    /// Number of flushes per reason.
+    pub reasons: HashMap<FlushReason, usize>,
+}
+
+struct FlushEntry {
+    reason: FlushReason,
+    size: usize,
+    fill_time: Duration,
+}
+
+/// A ring buffer of the most recent flushes of one background task.
+#[derive(Default)]
+pub(crate) struct FlushWindow {
+    entries: VecDeque<FlushEntry>,
+}
+
+impl FlushWindow {
+    const CAPACITY: usize = 64;
+
+    fn record(&mut self, entry: FlushEntry) {
+        if self.entries.len() == Self::CAPACITY {
+            self.entries.pop_front();
+        }
+        self.entries.push_back(entry);
+    }
+
+    fn stats(&self) -> RecentStats {
+        let flushes = self.entries.len();
+        let mut stats = RecentStats {
+            flushes,
+            ..RecentStats::default()
+        };
+        if flushes == 0 {
+            return stats;
+        }
+        let mut fill_time = Duration::ZERO;
+        for entry in &self.entries {
+            stats.average_batch_size += entry.size as f64;
+            fill_time += entry.fill_time;
+            *stats.reasons.entry(entry.reason).or_default() += 1;
+        }
+        stats.average_batch_size /= flushes as f64;
+        stats.average_fill_time = fill_time / flushes as u32;
+        stats
+    }
+}
+
+/// Bounds for a flush interval that adapts to the load, see
+/// [`BetterStackAppenderBuilder::adaptive_flush`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AdaptiveFlush {
+    pub min: Duration,
+    pub max: Duration,
+}
+
+/// Drives the periodic flush of the background task.
+struct FlushTimer {
+    interval: Option<time::Interval>,
+    period: Duration,
+    jitter: Option<(Duration, SmallRng)>,
+    adaptive: Option<AdaptiveFlush>,
+    missed_tick_behavior: MissedTickBehavior,
+}
+
+impl FlushTimer {
+    fn new(config: &Config) -> FlushTimer {
+        let mut timer = FlushTimer {
+            interval: None,
+            period: clamp_period(config.flush_interval, config.adaptive_flush),
+            jitter: config
+                .flush_jitter
+                .map(|jitter| (jitter, SmallRng::from_os_rng())),
+            adaptive: config.adaptive_flush,
+            missed_tick_behavior: config.missed_tick_behavior,
+        };
+        if config.periodic_flush {
+            let first_tick = if config.flush_on_start {
+                Duration::ZERO
+            } else {
+                timer.period
+            };
+            timer.start(first_tick, timer.period);
+        }
+        // Desynchronize instances that start at the same time right away.
+        timer.reset();
+        timer
+    }
+
+    /// Switches to a new flush interval, with the next tick one interval
+    /// from now.
+    fn set_period(&mut self, period: Duration) {
+        self.period = clamp_period(period, self.adaptive);
+        if self.interval.is_some() {
+            self.start(self.period, self.period);
+        }
+        self.reset();
+    }
+
+    fn start(&mut self, first_tick: Duration, period: Duration) {
+        let mut interval = time::interval_at(time::Instant::now() + first_tick, period);
+        interval.set_missed_tick_behavior(self.missed_tick_behavior);
+        self.interval = Some(interval);
+    }
+
+    /// Waits for the next tick, or forever when periodic flushing is disabled.
+    async fn tick(&mut self) {
+        next_tick(&mut self.interval).await;
+        self.reset();
+    }
+
+    /// Adjusts the period of an adaptive timer after a tick, given the number
+    /// of messages that were waiting: halved while at least half a batch is
+    /// pending, doubled when nothing is.
+    fn adapt(&mut self, pending: usize, batch_size: usize) {
+        let Some(AdaptiveFlush { min, max }) = self.adaptive else {
+            return;
+        };
+        if pending == 0 {
+            self.period = (self.period * 2).min(max);
+        } else if pending >= batch_size.div_ceil(2) {
+            self.period = (self.period / 2).max(min);
+        }
+        match (&mut self.interval, self.jitter.is_some()) {
+            (_, true) => self.reset(),
+            (Some(interval), false) => interval.reset_after(self.period),
+            (None, false) => {}
+        }
+    }
+
+    fn reset(&mut self) {
+        if let (Some(interval), Some((jitter, rng))) = (&mut self.interval, &mut self.jitter) {
+            let min = self
+                .period
+                .saturating_sub(*jitter)
+                .max(Duration::from_millis(1));
+            let max = self.period + *jitter;
+            interval.reset_after(rng.random_range(min..=max));
+        }
+    }
+}
+
+fn clamp_period(period: Duration, adaptive: Option<AdaptiveFlush>) -> Duration {
+    match adaptive {
+        Some(AdaptiveFlush { min, max }) => period.clamp(min, max.max(min)),
+        None => period,
+    }
+}
+
+/// Waits for the next tick of `interval`, or forever if there is none.
+async fn next_tick(interval: &mut Option<time::Interval>) {
+    match interval {
+        Some(interval) => {
+            interval.tick().await;
+        }
+        None => std::future::pending().await,
+    }
+}
+
+/// Rate limiting of repeated messages, see
+/// [`BetterStackAppenderBuilder::coalesce_repeated`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Coalescing {
+    /// The least severe level that is rate-limited.
+    pub level: LevelFilter,
+    /// How many copies of a message are sent per window.
+    pub threshold: u32,
+    pub window: Duration,
+}
+
+/// Counts the copies of each message in the current coalescing window.
+struct Coalescer {
+    rule: Coalescing,
+    seen: HashMap<(String, String), Occurrences>,
+}
+
+#[derive(Default)]
+struct Occurrences {
+    count: u64,
+    suppressed: u64,
+    /// Whether the message exceeded the threshold in the previous window.
+    flooding: bool,
+    target: String,
+}
+
+impl Coalescer {
+    /// Distinct messages tracked per window. Further ones are not limited.
+    const MAX_TRACKED: usize = 1024;
+
+    fn new(rule: Coalescing) -> Coalescer {
+        Coalescer {
+            rule,
+            seen: HashMap::new(),
+        }
+    }
+
+    /// Counts the message, returning whether it should be sent.
+    fn admit(&mut self, message: &LogMessage) -> bool {
+        let limited = message
+            .level
+            .trim_end()
+            .parse::<Level>()
+            .is_ok_and(|level| level <= self.rule.level);
+        if !limited {
+            return true;
+        }
+        let key = (message.level.clone(), message.message.to_string());
+        if self.seen.len() >= Self::MAX_TRACKED && !self.seen.contains_key(&key) {
+            return true;
+        }
+        let entry = self.seen.entry(key).or_default();
+        entry.count += 1;
+        if !entry.flooding && entry.count <= u64::from(self.rule.threshold) {
+            return true;
+        }
+        if entry.suppressed == 0 {
+            entry.target = message.target.clone();
+        }
+        entry.suppressed += 1;
+        false
+    }
+
+    /// Adds a summary for each message that was not sent in full during the
+    /// window, and starts the next one.
+    fn end_window(&mut self, options: &MessageOptions, batch: &mut Batch) {
+        let window = self.rule.window;
+        let threshold = u64::from(self.rule.threshold);
+        self.seen.retain(|(level, message), entry| {
+            if entry.suppressed > 0 {
+                let mut summary = internal_message(
+                    options,
+                    level.trim_end().parse().unwrap_or(Level::Error),
+                    format!(
+                        "{message} (logged {} times in the last {window:?}, {} not sent)",
+                        entry.count, entry.suppressed
+                    ),
+                    "coalesced",
+                    entry.suppressed.into(),
+                );
+                summary.target = std::mem::take(&mut entry.target);
+                batch.push(summary);
             }
+            entry.flooding = entry.count > threshold;
+            entry.count = 0;
+            entry.suppressed = 0;
+            entry.flooding
         });
+    }
+}
+
+/// Removes messages that serialize identically to an earlier one in the batch,
+/// counting the copies on the one that is kept.
+fn dedupe(messages: &mut Vec<LogMessage>) {
+    let mut seen: HashMap<Vec<u8>, usize> = HashMap::new();
+    // The number of copies of each message that is kept.
+    let mut counts: Vec<usize> = Vec::with_capacity(messages.len());
+    messages.retain(|message| {
+        // Messages that fail to serialize are kept for the sink to report.
+        if let Ok(key) = serde_json::to_vec(message) {
+            if let Some(&index) = seen.get(&key) {
+                counts[index] += 1;
+                return false;
+            }
+            seen.insert(key, counts.len());
+        }
+        counts.push(1);
+        true
+    });
+    for (message, count) in messages.iter_mut().zip(counts) {
+        if count > 1 {
+            message
+                .fields
+                .insert("duplicates".to_string(), count.into());
+        }
+    }
+}
+
+/// The random id of this process, see
+/// [`BetterStackAppenderBuilder::with_instance_id`].
+pub fn instance_id() -> &'static str {
+    static INSTANCE_ID: OnceLock<String> = OnceLock::new();
+    INSTANCE_ID.get_or_init(|| uuid::Uuid::new_v4().to_string())
+}
+
+/// How long the hook of [`install_panic_hook`] waits for the panic message
+/// to be sent.
+pub const PANIC_FLUSH_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Sends panics to `appender` as error messages with the panic location and,
+/// if `RUST_BACKTRACE` enables it, a `backtrace` field, then calls the
+/// previously installed hook.
+///
+/// As the process may be about to exit, the hook sends the message right away
+/// and waits up to [`PANIC_FLUSH_TIMEOUT`] for it. It does not wait on a
+/// current-thread runtime, where the upload task cannot run until the
+/// panicking thread is done, nor for panics of the upload task itself, nor
+/// for an upload task started by a custom
+/// [`spawner`](BetterStackAppenderBuilder::spawner).
+pub fn install_panic_hook(appender: &BetterStackAppender) {
+    let pipeline = appender.pipeline.clone();
+    let options = appender.options.clone();
+    let previous = std::panic::take_hook();
+    std::panic::set_hook(Box::new(move |info| {
+        let thread = std::thread::current();
+        let location = info
+            .location()
+            .map_or_else(String::new, |location| format!(" at {location}"));
+        let mut message = internal_message(
+            &options,
+            Level::Error,
+            format!(
+                "thread '{}' panicked{location}:\n{}",
+                thread.name().unwrap_or("<unnamed>"),
+                panic_message(info.payload())
+            ),
+            "panic",
+            Value::Bool(true),
+        );
+        if let Some(location) = info.location() {
+            message.file = Some(location.file().to_string());
+            message.line = Some(location.line());
+        }
+        let backtrace = std::backtrace::Backtrace::capture();
+        if backtrace.status() == std::backtrace::BacktraceStatus::Captured {
+            message
+                .fields
+                .insert("backtrace".to_string(), backtrace.to_string().into());
+        }
+        pipeline.enqueue(message);
+
+        let (done, mut wait) = oneshot::channel();
+        let can_wait = pipeline
+            .task_id
+            .is_some_and(|task_id| task::try_id() != Some(task_id))
+            && tokio::runtime::Handle::try_current().map_or(true, |runtime| {
+                runtime.runtime_flavor() != tokio::runtime::RuntimeFlavor::CurrentThread
+            });
+        if can_wait && pipeline.control.send(Command::Drain(done)).is_ok() {
+            let deadline = Instant::now() + PANIC_FLUSH_TIMEOUT;
+            while wait.try_recv() == Err(oneshot::error::TryRecvError::Empty)
+                && Instant::now() < deadline
+            {
+                std::thread::sleep(Duration::from_millis(10));
+            }
+        } else {
+            pipeline.flush();
+        }
+        previous(info);
+    }));
+}
 
-        BetterStackAppender { sender }
+/// A message generated by the logger itself, with the static fields and one
+/// extra field that identifies its kind.
+fn internal_message(
+    options: &MessageOptions,
+    level: Level,
+    message: String,
+    key: &str,
+    value: Value,
+) -> LogMessage {
+    let mut fields = options.static_fields.clone();
+    fields.insert(key.to_string(), value);
+    LogMessage {
+        timestamp: current_timestamp(options),
+        level: format!("{:<5}", level.to_string()),
+        target: "betterstack_logger".to_string(),
+        thread: None,
+        message: message.into(),
+        module_path: None,
+        file: None,
+        line: None,
+        function: None,
+        fields,
+        enqueued_at: Instant::now(),
     }
+}
+
+/// Which clock message timestamps are taken from, see
+/// [`BetterStackAppenderBuilder::timestamps`].
+#[cfg(feature = "timestamps")]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Timestamps {
+    /// Leave the timestamp empty, so Better Stack uses the time of arrival.
+    None,
+    /// Local time, or UTC if the local offset cannot be determined.
+    Local,
+    #[default]
+    Utc,
+    /// UTC shifted by a fixed offset.
+    UtcOffset(::time::UtcOffset),
+}
+
+/// A format for message timestamps, see
+/// [`BetterStackAppenderBuilder::timestamp_format`].
+#[cfg(feature = "timestamps")]
+pub type TimestampFormat = &'static [BorrowedFormatItem<'static>];
+
+#[cfg(feature = "timestamps")]
+const TIMESTAMP_FORMAT_OFFSET: TimestampFormat = format_description!(
+    "[year]-[month]-[day]T[hour]:[minute]:[second].[subsecond digits:3][offset_hour sign:mandatory]:[offset_minute]"
+);
 
-    async fn send_batch(client: &Client, url: &str, token: &str, batch: &mut Vec<LogMessage>) {
-        let json = json!(batch);
-        let _ = client.post(url).bearer_auth(token).json(&json).send().await;
+#[cfg(feature = "timestamps")]
+const TIMESTAMP_FORMAT_UTC: TimestampFormat =
+    format_description!("[year]-[month]-[day]T[hour]:[minute]:[second].[subsecond digits:3]Z");
 
-        batch.clear();
+/// The timestamp of a message logged now, empty without the `timestamps`
+/// feature.
+#[cfg_attr(not(feature = "timestamps"), allow(unused_variables))]
+pub(crate) fn current_timestamp(options: &MessageOptions) -> String {
+    #[cfg(feature = "timestamps")]
+    {
+        let (now, default_format) = match options.timestamps {
+            Timestamps::None => return String::new(),
+            Timestamps::Local => (
+                OffsetDateTime::now_local().unwrap_or_else(|_| OffsetDateTime::now_utc()),
+                TIMESTAMP_FORMAT_OFFSET,
+            ),
+            Timestamps::Utc => (OffsetDateTime::now_utc(), TIMESTAMP_FORMAT_UTC),
+            Timestamps::UtcOffset(offset) => (
+                OffsetDateTime::now_utc().to_offset(offset),
+                TIMESTAMP_FORMAT_OFFSET,
+            ),
+        };
+        now.format(options.timestamp_format.unwrap_or(default_format))
+            .unwrap_or_default()
     }
+    #[cfg(not(feature = "timestamps"))]
+    String::new()
 }
 
-fn build_log_message(record: &Record) -> LogMessage {
+fn build_log_message(record: &Record, options: &MessageOptions) -> LogMessage {
+    let enqueued_at = Instant::now();
     let level_string = format!("{:<5}", record.level().to_string());
 
     let target = if !record.target().is_empty() {
-        record.target().to_string()
+        record.target()
+    } else if let Some(default_target) = &options.default_target {
+        default_target
     } else {
-        record.module_path().unwrap_or_default().to_string()
+        record.module_path().unwrap_or_default()
+    };
+    let target = match options.max_field_value_len {
+        Some(max) => truncate_value(Cow::Borrowed(target), max).into_owned(),
+        None => target.to_string(),
     };
 
     let thread_info: Option<ThreadInfo> = {
         #[cfg(feature = "threads")]
         {
-            let thread = std::thread::current();
-            let thread_name = {
-                #[cfg(feature = "nightly")]
-                {
-                    thread
-                        .name()
-                        .unwrap_or(&format!("{}", thread.id().as_u64()))
-                }
-                #[cfg(not(feature = "nightly"))]
-                {
-                    thread.name().unwrap_or("?").to_string()
-                }
-            };
-            Some(ThreadInfo {
-                id: format!("{:?}", thread.id()),
-                name: Some(thread_name),
-            })
+            if options.thread_info {
+                let thread = std::thread::current();
+                let thread_name =
+                    {
+                        #[cfg(feature = "nightly")]
+                        {
+                            Some(thread.name().map_or_else(
+                                || thread.id().as_u64().to_string(),
+                                ToString::to_string,
+                            ))
+                        }
+                        #[cfg(not(feature = "nightly"))]
+                        {
+                            thread
+                                .name()
+                                .map(ToString::to_string)
+                                .or_else(|| options.unnamed_thread_label.clone())
+                        }
+                    };
+                Some(ThreadInfo {
+                    id: format!("{:?}", thread.id()),
+                    name: thread_name,
+                })
+            } else {
+                None
+            }
         }
         #[cfg(not(feature = "threads"))]
         None
     };
 
-    let timestamp: String = {
-        #[cfg(feature = "timestamps")]
-        match self.timestamps {
-            Timestamps::None => "".to_string(),
-            Timestamps::Local => format!(
-                "{} ",
-                OffsetDateTime::now_local()
-                    .unwrap()
-                    .format(&self.timestamps_format.unwrap_or(TIMESTAMP_FORMAT_OFFSET))
-                    .unwrap()
-            ),
-            Timestamps::Utc => format!(
-                "{} ",
-                OffsetDateTime::now_utc()
-                    .format(&self.timestamps_format.unwrap_or(TIMESTAMP_FORMAT_UTC))
-                    .unwrap()
-            ),
-            Timestamps::UtcOffset(offset) => format!(
-                "{} ",
-                OffsetDateTime::now_utc()
-                    .to_offset(offset)
-                    .format(&self.timestamps_format.unwrap_or(TIMESTAMP_FORMAT_OFFSET))
-                    .unwrap()
-            ),
-        }
-        #[cfg(not(feature = "timestamps"))]
-        "".to_string()
+    let timestamp = current_timestamp(options);
+
+    let mut message_truncated = false;
+    let message = match (&options.encoder, record.args().as_str()) {
+        (Some(encoder), _) => Cow::Owned(encode_message(
+            encoder.as_ref(),
+            record,
+            &options.diagnostics,
+        )),
+        (None, Some(message)) => Cow::Borrowed(message),
+        (None, None) => match options.max_message_len {
+            Some(max) => {
+                let (message, truncated) = format_bounded(record.args(), max);
+                message_truncated = truncated;
+                Cow::Owned(message)
+            }
+            None => Cow::Owned(record.args().to_string()),
+        },
+    };
+    let (message, truncated) = limit_message(message, options);
+    message_truncated |= truncated;
+
+    let kv_timestamp = options
+        .timestamp_key
+        .as_deref()
+        .and_then(|key| record.key_values().get(kv::Key::from_str(key)))
+        .map(|value| value.to_string())
+        .filter(|value| DateTime::parse_from_rfc3339(value).is_ok());
+    let timestamp_key = kv_timestamp.as_ref().and(options.timestamp_key.as_deref());
+    let timestamp = kv_timestamp.unwrap_or(timestamp);
+
+    let mut fields = FieldCollector {
+        fields: Map::new(),
+        max: options.max_fields.unwrap_or(usize::MAX),
+        truncated: false,
+        max_value_len: options.max_field_value_len,
+        skip: timestamp_key,
     };
+    for (key, value) in &options.static_fields {
+        fields.insert(key.clone(), value.clone());
+    }
+    if let Some(id) = context::correlation_id() {
+        fields.insert_captured(CORRELATION_FIELD.to_string(), id.into());
+    }
+    if let Some(scope) = context::scope() {
+        fields.insert_captured(SCOPE_FIELD.to_string(), scope.into());
+    }
+    if options.uptime {
+        let uptime = enqueued_at.duration_since(options.started).as_millis() as u64;
+        fields.insert("uptime_ms".to_string(), uptime.into());
+    }
+    let _ = record.key_values().visit(&mut fields);
+    if fields.truncated {
+        fields
+            .fields
+            .insert("fields_truncated".to_string(), Value::Bool(true));
+    }
+    if message_truncated {
+        fields
+            .fields
+            .insert("message_truncated".to_string(), Value::Bool(true));
+    }
 
     LogMessage {
         timestamp,
         level: level_string,
         target,
-        thread: thread_info.map(|ti| ti.name.unwrap_or_default()),
-        message: format!("{}", record.args()),
+        thread: thread_info.and_then(|ti| ti.name),
+        message,
         module_path: record.module_path().map(ToString::to_string),
         file: record.file().map(ToString::to_string),
         line: record.line(),
+        function: {
+            #[cfg(feature = "function-names")]
+            {
+                caller_function(record)
+            }
+            #[cfg(not(feature = "function-names"))]
+            None
+        },
+        fields: fields.fields,
+        enqueued_at,
+    }
+}
+
+/// Escapes control characters and applies the length limits to a formatted
+/// message, returning whether it was cut to `max_message_len`.
+pub(crate) fn limit_message(
+    mut message: Cow<'static, str>,
+    options: &MessageOptions,
+) -> (Cow<'static, str>, bool) {
+    let mut truncated = false;
+    // Escaping grows the message, so it goes first for the limits to hold
+    // for what is actually sent.
+    if options.sanitize_control_chars {
+        message = sanitize_control_chars(message);
+    }
+    if let Some(max) = options.max_message_len {
+        if message.len() > max {
+            message = truncate_value(message, max);
+            truncated = true;
+        }
+    }
+    if let Some(max) = options.max_field_value_len {
+        message = truncate_value(message, max);
+    }
+    (message, truncated)
+}
+
+/// Finds the function that logged `record` by looking for the frame at its
+/// file and line in the current backtrace.
+#[cfg(feature = "function-names")]
+fn caller_function(record: &Record) -> Option<String> {
+    let (file, line) = (record.file()?, record.line()?);
+    let mut function = None;
+    backtrace::trace(|frame| {
+        backtrace::resolve_frame(frame, |symbol| {
+            let at_call_site = symbol.lineno() == Some(line)
+                && symbol.filename().is_some_and(|path| path.ends_with(file));
+            if at_call_site && function.is_none() {
+                // The alternate format leaves out the trailing hash.
+                function = symbol.name().map(|name| format!("{name:#}"));
+            }
+        });
+        function.is_none()
+    });
+    function
+}
+
+/// Merges static fields and record key-values, honoring `max_fields`.
+struct FieldCollector<'a> {
+    fields: Map<String, Value>,
+    max: usize,
+    truncated: bool,
+    max_value_len: Option<usize>,
+    /// A record key that is used for something else, like the timestamp.
+    skip: Option<&'a str>,
+}
+
+impl FieldCollector<'_> {
+    fn insert(&mut self, key: String, value: Value) {
+        if self.fields.len() < self.max || self.fields.contains_key(&key) {
+            self.fields.insert(key, value);
+        } else {
+            self.truncated = true;
+        }
+    }
+
+    /// Inserts a value taken from the record or its context, cutting strings
+    /// to `max_value_len`.
+    fn insert_captured(&mut self, key: String, mut value: Value) {
+        if let (Value::String(string), Some(max)) = (&mut value, self.max_value_len) {
+            if let Cow::Owned(truncated) = truncate_value(Cow::Borrowed(string), max) {
+                *string = truncated;
+            }
+        }
+        self.insert(key, value);
+    }
+}
+
+impl<'kvs> VisitSource<'kvs> for FieldCollector<'_> {
+    fn visit_pair(&mut self, key: kv::Key<'kvs>, value: kv::Value<'kvs>) -> Result<(), kv::Error> {
+        if self.skip == Some(key.as_str()) {
+            return Ok(());
+        }
+        let value =
+            serde_json::to_value(&value).unwrap_or_else(|_| Value::String(value.to_string()));
+        self.insert_captured(key.to_string(), value);
+        Ok(())
+    }
+}
+
+/// Prints `message` as a human-readable line for the console tee.
+fn write_to_console(message: &LogMessage, timestamp_format: &str) {
+    use std::io::Write as _;
+
+    let now = chrono::Local::now();
+    let _ = writeln!(
+        std::io::stdout().lock(),
+        "{} {} {} - {}",
+        now.format(timestamp_format),
+        message.level,
+        message.target,
+        message.message
+    );
+}
+
+/// Renders `record` with `encoder`, falling back to its arguments if that fails.
+fn encode_message(encoder: &dyn Encode, record: &Record, diagnostics: &DiagnosticsSink) -> String {
+    let mut writer = SimpleWriter(Vec::new());
+    match encoder.encode(&mut writer, record) {
+        Ok(()) => String::from_utf8_lossy(&writer.0).into_owned(),
+        Err(error) => {
+            diagnostics.report(
+                DiagnosticKind::EncodeFailed,
+                format!("failed to encode log message: {error}"),
+            );
+            record.args().to_string()
+        }
+    }
+}
+
+/// Ends values shortened by [`truncate_value`] and [`format_bounded`].
+const TRUNCATION_MARKER: &str = "…";
+
+/// Cuts `value` to at most `max` bytes on a character boundary, ending it with
+/// `…` if anything was removed.
+fn truncate_value<'a>(value: Cow<'a, str>, max: usize) -> Cow<'a, str> {
+    if value.len() <= max {
+        return value;
+    }
+    let (end, marker) = truncation_point(&value, max);
+    Cow::Owned(format!("{}{marker}", &value[..end]))
+}
+
+/// Where to cut a value that is too long for `max` bytes, and the marker to
+/// end it with, which is left out when even it doesn't fit.
+fn truncation_point(value: &str, max: usize) -> (usize, &'static str) {
+    let marker = if max >= TRUNCATION_MARKER.len() {
+        TRUNCATION_MARKER
+    } else {
+        ""
+    };
+    let mut end = (max - marker.len()).min(value.len());
+    while !value.is_char_boundary(end) {
+        end -= 1;
+    }
+    (end, marker)
+}
+
+/// Formats `args` into at most `max` bytes, ending with `…` if it was cut, and
+/// returns whether it was.
+/// Formatting stops as soon as the limit is hit, so the full message is never
+/// built, although the arguments themselves may still do work for it.
+fn format_bounded(args: &fmt::Arguments<'_>, max: usize) -> (String, bool) {
+    struct Bounded {
+        buf: String,
+        max: usize,
+        overflowed: bool,
+    }
+
+    impl Write for Bounded {
+        fn write_str(&mut self, s: &str) -> fmt::Result {
+            let room = self.max - self.buf.len();
+            if s.len() <= room {
+                self.buf.push_str(s);
+                return Ok(());
+            }
+            let mut end = room;
+            while !s.is_char_boundary(end) {
+                end -= 1;
+            }
+            self.buf.push_str(&s[..end]);
+            self.overflowed = true;
+            // Makes `write_fmt` give up on the remaining arguments.
+            Err(fmt::Error)
+        }
+    }
+
+    let mut bounded = Bounded {
+        buf: String::new(),
+        max,
+        overflowed: false,
+    };
+    let _ = bounded.write_fmt(*args);
+    if bounded.overflowed {
+        // Only now that the message is known to be too long does the marker
+        // take the place of its last characters.
+        let (end, marker) = truncation_point(&bounded.buf, max);
+        bounded.buf.truncate(end);
+        bounded.buf.push_str(marker);
+    }
+    (bounded.buf, bounded.overflowed)
+}
+
+fn is_unwanted_control(c: char) -> bool {
+    c.is_control() && !matches!(c, '\n' | '\r' | '\t')
+}
+
+fn sanitize_control_chars(message: Cow<'static, str>) -> Cow<'static, str> {
+    if !message.chars().any(is_unwanted_control) {
+        return message;
+    }
+
+    let mut sanitized = String::with_capacity(message.len());
+    for c in message.chars() {
+        if is_unwanted_control(c) {
+            let _ = write!(sanitized, "\\u{{{:04x}}}", c as u32);
+        } else {
+            sanitized.push(c);
+        }
     }
+    Cow::Owned(sanitized)
 }
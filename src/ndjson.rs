@@ -0,0 +1,128 @@
+//! A hand-written encoder for the fixed [`LogMessage`] schema, used for the
+//! NDJSON format when the `lean-ndjson` feature is enabled. Its output must
+//! stay identical to what `serde_json` produces for the derived `Serialize`.
+
+use crate::sink::{syslog_severity, KeyStyle, LogMessage, MissingLinePolicy};
+use std::io::Write;
+
+pub(crate) fn write_message(
+    out: &mut Vec<u8>,
+    message: &LogMessage,
+    keys: &KeyStyle,
+) -> serde_json::Result<()> {
+    out.push(b'{');
+    write_key(out, &keys.timestamp(), true);
+    write_str(out, &message.timestamp);
+    write_key(out, &keys.key("level"), false);
+    if keys.level_as_object {
+        out.push(b'{');
+        write_key(out, &keys.key("name"), true);
+        write_str(out, message.level.trim_end());
+        write_key(out, &keys.key("severity"), false);
+        match syslog_severity(&message.level) {
+            Some(severity) => {
+                let _ = write!(out, "{severity}");
+            }
+            None => out.extend_from_slice(b"null"),
+        }
+        out.push(b'}');
+    } else {
+        write_str(out, &message.level);
+    }
+    write_key(out, &keys.key("target"), false);
+    write_str(out, &message.target);
+    if let Some(thread) = &message.thread {
+        write_key(out, &keys.key("thread"), false);
+        write_str(out, thread);
+    }
+    write_key(out, &keys.key("message"), false);
+    write_str(out, &message.message);
+    write_key(out, &keys.key("module_path"), false);
+    write_opt_str(out, message.module_path.as_deref());
+    write_key(out, &keys.key("file"), false);
+    write_opt_str(out, message.file.as_deref());
+    match (message.line, keys.missing_line) {
+        (Some(line), _) => {
+            write_key(out, &keys.key("line"), false);
+            let _ = write!(out, "{line}");
+        }
+        (None, MissingLinePolicy::Null) => {
+            write_key(out, &keys.key("line"), false);
+            out.extend_from_slice(b"null");
+        }
+        (None, MissingLinePolicy::Zero) => {
+            write_key(out, &keys.key("line"), false);
+            out.push(b'0');
+        }
+        (None, MissingLinePolicy::Omit) => {}
+    }
+    if let Some(function) = &message.function {
+        write_key(out, &keys.key("function"), false);
+        write_str(out, function);
+    }
+    for (key, value) in &message.fields {
+        write_key(out, &keys.key(key), false);
+        serde_json::to_writer(&mut *out, value)?;
+    }
+    out.push(b'}');
+    Ok(())
+}
+
+fn write_key(out: &mut Vec<u8>, key: &str, first: bool) {
+    if !first {
+        out.push(b',');
+    }
+    write_str(out, key);
+    out.push(b':');
+}
+
+fn write_opt_str(out: &mut Vec<u8>, value: Option<&str>) {
+    match value {
+        Some(value) => write_str(out, value),
+        None => out.extend_from_slice(b"null"),
+    }
+}
+
+/// How each byte is escaped in a JSON string, like serde_json does: 0 for not
+/// at all, `u` for `\u00XX`, and otherwise the character after the `\`.
+static ESCAPE: [u8; 256] = {
+    let mut table = [0; 256];
+    let mut byte = 0;
+    while byte < 0x20 {
+        table[byte] = b'u';
+        byte += 1;
+    }
+    table[0x08] = b'b';
+    table[0x0c] = b'f';
+    table[b'\n' as usize] = b'n';
+    table[b'\r' as usize] = b'r';
+    table[b'\t' as usize] = b't';
+    table[b'"' as usize] = b'"';
+    table[b'\\' as usize] = b'\\';
+    table
+};
+
+fn write_str(out: &mut Vec<u8>, value: &str) {
+    const HEX: &[u8; 16] = b"0123456789abcdef";
+
+    out.push(b'"');
+    let bytes = value.as_bytes();
+    let mut start = 0;
+    for (i, &byte) in bytes.iter().enumerate() {
+        let escape = ESCAPE[byte as usize];
+        if escape == 0 {
+            continue;
+        }
+        out.extend_from_slice(&bytes[start..i]);
+        if escape == b'u' {
+            out.extend_from_slice(b"\\u00");
+            out.push(HEX[(byte >> 4) as usize]);
+            out.push(HEX[(byte & 0xf) as usize]);
+        } else {
+            out.extend_from_slice(&[b'\\', escape]);
+        }
+        start = i + 1;
+    }
+    out.extend_from_slice(&bytes[start..]);
+    out.push(b'"');
+}
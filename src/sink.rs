@@ -0,0 +1,572 @@
+// The batch encoders are only used by the sinks of the `http` feature.
+#![cfg_attr(not(feature = "http"), allow(dead_code))]
+
+use crate::diagnostics::{DiagnosticKind, DiagnosticsSink};
+#[cfg(feature = "http")]
+pub(crate) use crate::http_sink::{join_path, BatchStatsCallback, BatchTransform};
+#[cfg(feature = "http")]
+pub use crate::http_sink::{
+    BatchStats, Compression, Compressor, HttpSink, RetryCondition, CLOCK_CHECK_INTERVAL,
+    DEFAULT_MAX_REQUEST_BYTES,
+};
+use futures_util::future::BoxFuture;
+use http::StatusCode;
+use serde_json::{Map, Value};
+use std::borrow::Cow;
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+/// A single log entry as it is sent to Better Stack.
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct LogMessage {
+    pub timestamp: String,
+    pub level: String,
+    pub target: String,
+    /// The logging thread's name, only collected with the `threads` feature.
+    /// Omitted when unknown.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub thread: Option<String>,
+    /// Borrowed without allocating for messages without format arguments.
+    pub message: Cow<'static, str>,
+    pub module_path: Option<String>,
+    pub file: Option<String>,
+    pub line: Option<u32>,
+    /// The calling function, only resolved with the `function-names` feature
+    /// and in builds with debug info. Omitted when unknown.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub function: Option<String>,
+    /// Static fields and record key-values, serialized inline.
+    #[serde(flatten)]
+    pub fields: Map<String, Value>,
+    /// When the message was logged. Batches are sorted by it, and their age
+    /// is measured from their oldest message. Not serialized.
+    #[serde(skip)]
+    pub enqueued_at: Instant,
+}
+
+/// A batch that could not be delivered, as reported on the channel returned by
+/// `BetterStackAppender::error_receiver`.
+#[derive(Debug, Clone)]
+pub struct SendError {
+    /// The HTTP status of the final attempt, or `None` if no response was received.
+    pub status: Option<StatusCode>,
+    /// How many times the batch was sent.
+    pub attempts: u32,
+    /// Number of messages in the batch.
+    pub batch_size: usize,
+    /// Number of messages at the start of the batch that were delivered
+    /// before the failure, when it was sent in several requests.
+    pub delivered: usize,
+    /// Human readable description of the failure.
+    pub reason: String,
+}
+
+impl fmt::Display for SendError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "failed to send {} log messages after {} attempt(s): {}",
+            self.batch_size, self.attempts, self.reason
+        )
+    }
+}
+
+impl std::error::Error for SendError {}
+
+/// Where the background task delivers finished batches. It is never called
+/// with an empty batch, so idle flush intervals cost nothing.
+///
+/// Install a custom sink with `BetterStackAppenderBuilder::with_sink`. The
+/// trait and [`LogMessage`] are also available without the default `http`
+/// feature, for crates that bring their own transport:
+///
+/// ```
+/// use betterstack_logger::sink::{LogMessage, SendError, Sink};
+/// use futures_util::future::BoxFuture;
+/// use std::sync::Mutex;
+///
+/// #[derive(Default)]
+/// struct NdjsonBuffer(Mutex<Vec<u8>>);
+///
+/// impl Sink for NdjsonBuffer {
+///     fn send<'a>(&'a self, batch: &'a [LogMessage]) -> BoxFuture<'a, Result<(), SendError>> {
+///         let mut buffer = self.0.lock().unwrap();
+///         for message in batch {
+///             serde_json::to_writer(&mut *buffer, message).unwrap();
+///             buffer.push(b'\n');
+///         }
+///         Box::pin(async { Ok(()) })
+///     }
+/// }
+///
+/// let sink: Box<dyn Sink> = Box::new(NdjsonBuffer::default());
+/// # drop(sink);
+/// ```
+pub trait Sink: Send + Sync {
+    fn send<'a>(&'a self, batch: &'a [LogMessage]) -> BoxFuture<'a, Result<(), SendError>>;
+}
+
+impl<S: Sink + ?Sized> Sink for Arc<S> {
+    fn send<'a>(&'a self, batch: &'a [LogMessage]) -> BoxFuture<'a, Result<(), SendError>> {
+        (**self).send(batch)
+    }
+}
+
+/// A sink that keeps every batch in memory instead of sending it, for
+/// asserting on the logger's output in tests.
+///
+/// ```no_run
+/// # #[cfg(feature = "http")]
+/// # async fn example() {
+/// use betterstack_logger::logger::BetterStackAppender;
+/// use betterstack_logger::sink::RecordingSink;
+/// use std::sync::Arc;
+///
+/// let recorder = Arc::new(RecordingSink::new());
+/// let appender = BetterStackAppender::builder(String::new(), String::new())
+///     .with_sink(Box::new(recorder.clone()))
+///     .build();
+/// // ... log through the appender, flush ...
+/// let batches = recorder.batches();
+/// # }
+/// ```
+#[derive(Debug, Default)]
+pub struct RecordingSink {
+    batches: Mutex<Vec<Vec<LogMessage>>>,
+}
+
+impl RecordingSink {
+    pub fn new() -> RecordingSink {
+        RecordingSink::default()
+    }
+
+    /// Returns a copy of every batch received so far, oldest first.
+    pub fn batches(&self) -> Vec<Vec<LogMessage>> {
+        self.batches.lock().unwrap().clone()
+    }
+}
+
+impl Sink for RecordingSink {
+    fn send<'a>(&'a self, batch: &'a [LogMessage]) -> BoxFuture<'a, Result<(), SendError>> {
+        self.batches.lock().unwrap().push(batch.to_vec());
+        Box::pin(async { Ok(()) })
+    }
+}
+
+/// Writes each batch as NDJSON to a Unix domain socket, e.g. of a log shipper
+/// on the same host. Requires the `unix` feature.
+///
+/// The connection is opened on the first batch and reopened when a write
+/// fails. The batch is written again on the new connection only if none of it
+/// had been written yet, so the reader never gets the start of a batch twice.
+#[cfg(all(unix, feature = "unix"))]
+pub struct UnixSocketSink {
+    path: std::path::PathBuf,
+    stream: tokio::sync::Mutex<Option<tokio::net::UnixStream>>,
+    serialization_errors: AtomicU64,
+    diagnostics: DiagnosticsSink,
+}
+
+#[cfg(all(unix, feature = "unix"))]
+impl UnixSocketSink {
+    pub fn new(path: impl Into<std::path::PathBuf>) -> UnixSocketSink {
+        UnixSocketSink {
+            path: path.into(),
+            stream: tokio::sync::Mutex::default(),
+            serialization_errors: AtomicU64::new(0),
+            diagnostics: DiagnosticsSink::default(),
+        }
+    }
+
+    /// Where messages that fail to serialize are reported. Defaults to stderr.
+    pub fn diagnostics_sink(mut self, diagnostics: DiagnosticsSink) -> Self {
+        self.diagnostics = diagnostics;
+        self
+    }
+
+    async fn send_batch(&self, batch: &[LogMessage]) -> Result<(), SendError> {
+        let body = serialize_batch(
+            batch,
+            Format::Ndjson,
+            None,
+            &KeyStyle::default(),
+            &self.serialization_errors,
+            &self.diagnostics,
+        );
+        let mut stream = self.stream.lock().await;
+        let mut attempts = 0;
+        loop {
+            attempts += 1;
+            let mut written = 0;
+            let result = match stream.as_mut() {
+                Some(connection) => write_counted(connection, &body, &mut written).await,
+                None => match tokio::net::UnixStream::connect(&self.path).await {
+                    Ok(connection) => {
+                        write_counted(stream.insert(connection), &body, &mut written).await
+                    }
+                    Err(error) => Err(error),
+                },
+            };
+            let error = match result {
+                Ok(()) => return Ok(()),
+                Err(error) => error,
+            };
+            // Reconnect on the next attempt, or the next batch.
+            *stream = None;
+            if attempts == 2 || written > 0 {
+                return Err(SendError {
+                    status: None,
+                    attempts,
+                    batch_size: batch.len(),
+                    delivered: 0,
+                    reason: format!("failed to write to {}: {error}", self.path.display()),
+                });
+            }
+        }
+    }
+}
+
+/// Writes all of `body`, adding the number of bytes written to `written` as it
+/// goes, so that it is known even when a write fails.
+#[cfg(all(unix, feature = "unix"))]
+async fn write_counted(
+    connection: &mut tokio::net::UnixStream,
+    body: &[u8],
+    written: &mut usize,
+) -> std::io::Result<()> {
+    use tokio::io::AsyncWriteExt;
+
+    while *written < body.len() {
+        match connection.write(&body[*written..]).await? {
+            0 => return Err(std::io::ErrorKind::WriteZero.into()),
+            n => *written += n,
+        }
+    }
+    Ok(())
+}
+
+#[cfg(all(unix, feature = "unix"))]
+impl Sink for UnixSocketSink {
+    fn send<'a>(&'a self, batch: &'a [LogMessage]) -> BoxFuture<'a, Result<(), SendError>> {
+        Box::pin(self.send_batch(batch))
+    }
+}
+
+/// The request body format.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Format {
+    /// A single JSON array of messages.
+    #[default]
+    Json,
+    /// One JSON object per line. With the `lean-ndjson` feature the fixed
+    /// message fields are written by a small hand-rolled encoder instead of
+    /// going through serde, which avoids the generic serializer machinery on
+    /// the hot path. It produces byte-for-byte the same output; only extra
+    /// fields are still encoded by `serde_json`. It is about as fast as the
+    /// serde path (see `benches/serialize.rs`), so it is only worth it where
+    /// the code size of the derived serializer matters.
+    Ndjson,
+}
+
+impl Format {
+    pub(crate) fn content_type(self) -> &'static str {
+        match self {
+            Format::Json => "application/json",
+            Format::Ndjson => "application/x-ndjson",
+        }
+    }
+}
+
+/// Wraps the batch in an object like `{"source": "x", "logs": [...]}` for
+/// endpoints that do not accept a bare array. Only applies to [`Format::Json`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Envelope {
+    key: String,
+    fields: Map<String, Value>,
+}
+
+impl Envelope {
+    /// Put the messages under `key`.
+    pub fn new(key: impl Into<String>) -> Envelope {
+        Envelope {
+            key: key.into(),
+            fields: Map::new(),
+        }
+    }
+
+    /// Add a fixed top-level field next to the messages.
+    pub fn field(mut self, key: impl Into<String>, value: impl Into<Value>) -> Self {
+        self.fields.insert(key.into(), value.into());
+        self
+    }
+}
+
+/// Serializes the batch in `format`, skipping (and counting) messages that
+/// fail to serialize instead of failing the whole batch.
+pub(crate) fn serialize_batch(
+    batch: &[LogMessage],
+    format: Format,
+    envelope: Option<&Envelope>,
+    keys: &KeyStyle,
+    serialization_errors: &AtomicU64,
+    diagnostics: &DiagnosticsSink,
+) -> Vec<u8> {
+    let mut body = Vec::new();
+    let mut encoder = BatchEncoder::new(
+        format,
+        envelope,
+        keys,
+        Some((serialization_errors, diagnostics)),
+    );
+    encoder.start(&mut body);
+    for message in batch {
+        encoder.message(&mut body, message);
+    }
+    encoder.finish(&mut body);
+    body
+}
+
+/// Writes a batch piece by piece, so that it can also be streamed.
+pub(crate) struct BatchEncoder<'a> {
+    format: Format,
+    envelope: Option<&'a Envelope>,
+    keys: &'a KeyStyle,
+    /// Where to count messages that fail to serialize, or `None` to skip
+    /// them silently, e.g. when they were already reported.
+    serialization_errors: Option<(&'a AtomicU64, &'a DiagnosticsSink)>,
+    empty: bool,
+}
+
+impl<'a> BatchEncoder<'a> {
+    pub(crate) fn new(
+        format: Format,
+        envelope: Option<&'a Envelope>,
+        keys: &'a KeyStyle,
+        serialization_errors: Option<(&'a AtomicU64, &'a DiagnosticsSink)>,
+    ) -> Self {
+        BatchEncoder {
+            format,
+            envelope,
+            keys,
+            serialization_errors,
+            empty: true,
+        }
+    }
+
+    pub(crate) fn start(&self, body: &mut Vec<u8>) {
+        if let (Format::Json, Some(envelope)) = (self.format, self.envelope) {
+            body.push(b'{');
+            for (key, value) in &envelope.fields {
+                let _ = serde_json::to_writer(&mut *body, key);
+                body.push(b':');
+                let _ = serde_json::to_writer(&mut *body, value);
+                body.push(b',');
+            }
+            let _ = serde_json::to_writer(&mut *body, &envelope.key);
+            body.push(b':');
+        }
+        if self.format == Format::Json {
+            body.push(b'[');
+        }
+    }
+
+    pub(crate) fn message(&mut self, body: &mut Vec<u8>, message: &LogMessage) {
+        let start = body.len();
+        if self.format == Format::Json && !self.empty {
+            body.push(b',');
+        }
+        match write_message(body, message, self.format, self.keys) {
+            Ok(()) => {
+                if self.format == Format::Ndjson {
+                    body.push(b'\n');
+                }
+                self.empty = false;
+            }
+            Err(error) => {
+                body.truncate(start);
+                if let Some((errors, diagnostics)) = self.serialization_errors {
+                    errors.fetch_add(1, Ordering::Relaxed);
+                    diagnostics.report(
+                        DiagnosticKind::SerializationFailed,
+                        format!("skipping log message that failed to serialize: {error}"),
+                    );
+                }
+            }
+        }
+    }
+
+    pub(crate) fn finish(&self, body: &mut Vec<u8>) {
+        if self.format == Format::Json {
+            body.push(b']');
+        }
+        if let (Format::Json, Some(_)) = (self.format, self.envelope) {
+            body.push(b'}');
+        }
+    }
+}
+
+/// How the keys of a serialized message are spelled, and the shape of its
+/// level.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub(crate) struct KeyStyle {
+    pub(crate) timestamp: Option<String>,
+    pub(crate) casing: Casing,
+    pub(crate) level_as_object: bool,
+    pub(crate) missing_line: MissingLinePolicy,
+}
+
+impl KeyStyle {
+    /// The key of the timestamp: the custom name as given, or `timestamp` in
+    /// the configured casing.
+    pub(crate) fn timestamp(&self) -> Cow<'_, str> {
+        match &self.timestamp {
+            Some(name) => Cow::Borrowed(name),
+            None => self.key("timestamp"),
+        }
+    }
+
+    /// Converts a (snake_case) key to the configured casing.
+    pub(crate) fn key<'a>(&self, key: &'a str) -> Cow<'a, str> {
+        self.casing.apply(key)
+    }
+}
+
+/// The casing of the keys in serialized messages.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub enum Casing {
+    /// `module_path`, as the fields are named in [`LogMessage`].
+    #[default]
+    Snake,
+    /// `modulePath`.
+    Camel,
+    /// `ModulePath`.
+    Pascal,
+}
+
+/// How a message without a source line, e.g. one logged through
+/// [`BetterStackWriter`](crate::writer::BetterStackWriter), serializes its
+/// `line`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub enum MissingLinePolicy {
+    /// `"line": null`.
+    #[default]
+    Null,
+    /// No `line` key.
+    Omit,
+    /// `"line": 0`, for schemas that type `line` as a number.
+    Zero,
+}
+
+impl Casing {
+    fn apply(self, key: &str) -> Cow<'_, str> {
+        let capitalize_first = match self {
+            Casing::Snake => return Cow::Borrowed(key),
+            Casing::Camel => false,
+            Casing::Pascal => true,
+        };
+        let mut out = String::with_capacity(key.len());
+        let mut upper = capitalize_first;
+        for c in key.chars() {
+            if c == '_' && !out.is_empty() {
+                upper = true;
+            } else if upper {
+                out.extend(c.to_uppercase());
+                upper = false;
+            } else {
+                out.push(c);
+            }
+        }
+        if out == key {
+            Cow::Borrowed(key)
+        } else {
+            Cow::Owned(out)
+        }
+    }
+}
+
+pub(crate) fn write_message(
+    body: &mut Vec<u8>,
+    message: &LogMessage,
+    format: Format,
+    keys: &KeyStyle,
+) -> serde_json::Result<()> {
+    #[cfg(feature = "lean-ndjson")]
+    if format == Format::Ndjson {
+        return crate::ndjson::write_message(body, message, keys);
+    }
+    #[cfg(not(feature = "lean-ndjson"))]
+    let _ = format;
+
+    if *keys == KeyStyle::default() {
+        serde_json::to_writer(body, message)
+    } else {
+        serde_json::to_writer(body, &RenamedKeys(message, keys))
+    }
+}
+
+/// Serializes a message like its derived `Serialize` impl, but with the keys
+/// spelled as configured. A custom timestamp key is used as given.
+struct RenamedKeys<'a>(&'a LogMessage, &'a KeyStyle);
+
+impl serde::Serialize for RenamedKeys<'_> {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        use serde::ser::SerializeMap;
+
+        let RenamedKeys(message, keys) = self;
+        let mut map = serializer.serialize_map(None)?;
+        map.serialize_entry(&keys.timestamp(), &message.timestamp)?;
+        if keys.level_as_object {
+            map.serialize_entry(&keys.key("level"), &LevelObject(&message.level, keys))?;
+        } else {
+            map.serialize_entry(&keys.key("level"), &message.level)?;
+        }
+        map.serialize_entry(&keys.key("target"), &message.target)?;
+        if let Some(thread) = &message.thread {
+            map.serialize_entry(&keys.key("thread"), thread)?;
+        }
+        map.serialize_entry(&keys.key("message"), &message.message)?;
+        map.serialize_entry(&keys.key("module_path"), &message.module_path)?;
+        map.serialize_entry(&keys.key("file"), &message.file)?;
+        match (message.line, keys.missing_line) {
+            (None, MissingLinePolicy::Omit) => {}
+            (None, MissingLinePolicy::Zero) => map.serialize_entry(&keys.key("line"), &0)?,
+            (line, _) => map.serialize_entry(&keys.key("line"), &line)?,
+        }
+        if let Some(function) = &message.function {
+            map.serialize_entry(&keys.key("function"), function)?;
+        }
+        for (key, value) in &message.fields {
+            map.serialize_entry(&keys.key(key), value)?;
+        }
+        map.end()
+    }
+}
+
+/// A level serialized as `{"name": "ERROR", "severity": 3}`.
+struct LevelObject<'a>(&'a str, &'a KeyStyle);
+
+impl serde::Serialize for LevelObject<'_> {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        use serde::ser::SerializeMap;
+
+        let LevelObject(level, keys) = self;
+        let mut map = serializer.serialize_map(Some(2))?;
+        map.serialize_entry(&keys.key("name"), level.trim_end())?;
+        map.serialize_entry(&keys.key("severity"), &syslog_severity(level))?;
+        map.end()
+    }
+}
+
+/// The syslog severity of a level name, from 3 for errors to 7 for debug
+/// and trace, or `None` for names that are not a `log` level.
+pub(crate) fn syslog_severity(level: &str) -> Option<u8> {
+    let severity = match level.trim_end().parse().ok()? {
+        log::Level::Error => 3,
+        log::Level::Warn => 4,
+        log::Level::Info => 6,
+        log::Level::Debug | log::Level::Trace => 7,
+    };
+    Some(severity)
+}
@@ -0,0 +1,100 @@
+use crate::context::{self, CORRELATION_FIELD, SCOPE_FIELD};
+use crate::logger::{current_timestamp, LogMessage, MessageOptions, Pipeline};
+use log::Level;
+use std::io;
+use std::sync::Arc;
+use std::time::Instant;
+
+/// An [`io::Write`] sink that feeds each written line into the same uploader
+/// as the [`BetterStackAppender`](crate::logger::BetterStackAppender) it was
+/// created from, see `BetterStackAppender::writer`.
+///
+/// Bytes are buffered until a newline, so a line split across several writes
+/// becomes a single message. A trailing partial line is sent when the writer
+/// is dropped. Empty lines are skipped.
+pub struct BetterStackWriter {
+    pipeline: Arc<Pipeline>,
+    options: MessageOptions,
+    level: Level,
+    target: String,
+    buffer: Vec<u8>,
+}
+
+impl BetterStackWriter {
+    pub(crate) fn new(pipeline: Arc<Pipeline>, options: MessageOptions) -> Self {
+        BetterStackWriter {
+            pipeline,
+            options,
+            level: Level::Info,
+            target: String::new(),
+            buffer: Vec::new(),
+        }
+    }
+
+    /// The level of every message. Defaults to [`Level::Info`].
+    pub fn level(mut self, level: Level) -> Self {
+        self.level = level;
+        self
+    }
+
+    /// The target of every message. Defaults to an empty target.
+    pub fn target(mut self, target: impl Into<String>) -> Self {
+        self.target = target.into();
+        self
+    }
+
+    fn emit(&self, line: &[u8]) {
+        let line = line.strip_suffix(b"\r").unwrap_or(line);
+        if line.is_empty() {
+            return;
+        }
+        let mut fields = self.options.static_fields.clone();
+        if let Some(id) = context::correlation_id() {
+            fields.insert(CORRELATION_FIELD.to_string(), id.into());
+        }
+        if let Some(scope) = context::scope() {
+            fields.insert(SCOPE_FIELD.to_string(), scope.into());
+        }
+        self.pipeline.enqueue(LogMessage {
+            timestamp: current_timestamp(&self.options),
+            level: format!("{:<5}", self.level.to_string()),
+            target: self.target.clone(),
+            thread: None,
+            message: String::from_utf8_lossy(line).into_owned().into(),
+            module_path: None,
+            file: None,
+            line: None,
+            function: None,
+            fields,
+            enqueued_at: Instant::now(),
+        });
+    }
+}
+
+impl io::Write for BetterStackWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.buffer.extend_from_slice(buf);
+        if let Some(end) = self.buffer.iter().rposition(|&byte| byte == b'\n') {
+            let rest = self.buffer.split_off(end + 1);
+            let complete = std::mem::replace(&mut self.buffer, rest);
+            for line in complete[..end].split(|&byte| byte == b'\n') {
+                self.emit(line);
+            }
+        }
+        Ok(buf.len())
+    }
+
+    /// Asks the background task to send its pending batch. A partial line
+    /// stays buffered until its newline is written.
+    fn flush(&mut self) -> io::Result<()> {
+        self.pipeline.flush();
+        Ok(())
+    }
+}
+
+impl Drop for BetterStackWriter {
+    fn drop(&mut self) {
+        let rest = std::mem::take(&mut self.buffer);
+        self.emit(&rest);
+    }
+}
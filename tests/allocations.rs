@@ -0,0 +1,81 @@
+#![cfg(feature = "http")]
+
+//! Allocations made on the logging thread by `append`, counted per thread so
+//! that the upload task's don't interfere.
+
+use betterstack_logger::logger::{BetterStackAppender, BetterStackAppenderBuilder};
+use betterstack_logger::sink::RecordingSink;
+use log::{Level, LevelFilter, Record};
+use log4rs::append::Append;
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::cell::Cell;
+use std::sync::Arc;
+
+struct CountingAllocator;
+
+thread_local! {
+    static ALLOCATIONS: Cell<u64> = const { Cell::new(0) };
+}
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let _ = ALLOCATIONS.try_with(|count| count.set(count.get() + 1));
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+fn allocations(append: impl Fn()) -> u64 {
+    let before = ALLOCATIONS.with(Cell::get);
+    append();
+    ALLOCATIONS.with(Cell::get) - before
+}
+
+fn builder() -> BetterStackAppenderBuilder {
+    BetterStackAppender::builder("http://127.0.0.1:9".to_string(), "token".to_string())
+        .disable_periodic_flush()
+        .with_sink(Box::new(Arc::new(RecordingSink::new())))
+}
+
+fn append(appender: &BetterStackAppender, args: std::fmt::Arguments<'_>) {
+    appender
+        .append(&Record::builder().args(args).level(Level::Info).build())
+        .unwrap();
+}
+
+#[test]
+fn static_messages_are_not_copied() {
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+    let _guard = runtime.enter();
+    let appender = builder().build();
+    let id = 42;
+    // The first append warms up lazily initialized state.
+    append(&appender, format_args!("warm up"));
+
+    let with_args = allocations(|| append(&appender, format_args!("request {id} handled")));
+    let without = allocations(|| append(&appender, format_args!("request 42 handled")));
+    assert_eq!(without + 1, with_args);
+}
+
+#[test]
+fn sampled_out_messages_are_never_formatted() {
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+    let _guard = runtime.enter();
+    let appender = builder()
+        .sample_rate(Level::Info, 0.0)
+        .always_ship(LevelFilter::Error)
+        .build();
+    append(&appender, format_args!("warm up"));
+
+    let id = 42;
+    assert_eq!(
+        allocations(|| append(&appender, format_args!("request {id} handled"))),
+        0
+    );
+}
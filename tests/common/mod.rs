@@ -0,0 +1,373 @@
+//! Helpers shared by the integration tests: records to log, and a small HTTP
+//! server standing in for the ingest endpoint, over HTTP or HTTPS.
+#![allow(dead_code)]
+
+use betterstack_logger::logger::{BetterStackAppender, BetterStackAppenderBuilder};
+use betterstack_logger::sink::{LogMessage, RecordingSink};
+use futures_util::future::BoxFuture;
+use log::{kv, Level, Record};
+use log4rs::append::Append;
+use serde_json::{Map, Value};
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::TcpListener;
+
+/// A builder pointed at a server that does not exist, for tests that deliver
+/// to a [`RecordingSink`].
+pub fn builder() -> BetterStackAppenderBuilder {
+    BetterStackAppender::builder("http://127.0.0.1:9".to_string(), "token".to_string())
+}
+
+/// Builds an appender that delivers to a new [`RecordingSink`], with periodic
+/// flushing off so that only [`BetterStackAppender::drain`] sends.
+pub fn recording(
+    configure: impl FnOnce(BetterStackAppenderBuilder) -> BetterStackAppenderBuilder,
+) -> (BetterStackAppender, Arc<RecordingSink>) {
+    let sink = Arc::new(RecordingSink::new());
+    let appender = configure(builder().disable_periodic_flush())
+        .with_sink(Box::new(sink.clone()))
+        .build();
+    (appender, sink)
+}
+
+/// Logs `message` at `level` with the target `test`.
+pub fn log(appender: &impl Append, level: Level, message: &str) {
+    log_to(appender, level, "test", message);
+}
+
+pub fn log_to(appender: &impl Append, level: Level, target: &str, message: &str) {
+    log_record(appender, level, target, message, &[] as &[(&str, &str); 0]);
+}
+
+/// Logs `message` at `INFO` with the target `test` and the key-values `kvs`.
+pub fn log_kv(appender: &impl Append, message: &str, kvs: &dyn kv::Source) {
+    log_record(appender, Level::Info, "test", message, kvs);
+}
+
+fn log_record(
+    appender: &impl Append,
+    level: Level,
+    target: &str,
+    message: &str,
+    kvs: &dyn kv::Source,
+) {
+    appender
+        .append(
+            &Record::builder()
+                .args(format_args!("{message}"))
+                .level(level)
+                .target(target)
+                .key_values(kvs)
+                .module_path_static(Some("tests::common"))
+                .file_static(Some("tests/common/mod.rs"))
+                .line(Some(1))
+                .build(),
+        )
+        .unwrap();
+}
+
+/// All messages of all batches sent so far, in order.
+pub fn sent(sink: &RecordingSink) -> Vec<LogMessage> {
+    sink.batches().into_iter().flatten().collect()
+}
+
+/// The message texts of all batches sent so far, in order.
+pub fn texts(sink: &RecordingSink) -> Vec<String> {
+    sent(sink)
+        .into_iter()
+        .map(|message| message.message.into_owned())
+        .collect()
+}
+
+/// A message at `INFO` as the appender would have built it.
+pub fn message(text: &str) -> LogMessage {
+    LogMessage {
+        timestamp: "2024-01-02T03:04:05.678Z".to_string(),
+        level: "INFO ".to_string(),
+        target: "test".to_string(),
+        thread: None,
+        message: text.to_string().into(),
+        module_path: Some("tests::common".to_string()),
+        file: Some("tests/common/mod.rs".to_string()),
+        line: Some(1),
+        function: None,
+        fields: Map::new(),
+        enqueued_at: Instant::now(),
+    }
+}
+
+/// Waits until `condition` holds, failing the test after five seconds.
+pub async fn eventually(mut condition: impl FnMut() -> bool) {
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while !condition() {
+        assert!(
+            Instant::now() < deadline,
+            "timed out waiting for a condition"
+        );
+        tokio::time::sleep(Duration::from_millis(5)).await;
+    }
+}
+
+/// A request received by a [`MockServer`].
+#[derive(Debug, Clone)]
+pub struct Request {
+    pub method: String,
+    /// The path including the query string.
+    pub path: String,
+    /// With lowercase names.
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+    /// The number of the connection it came on, counting from 0.
+    pub connection: usize,
+}
+
+impl Request {
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// The body as JSON, which the default format sends as an array.
+    pub fn json(&self) -> Value {
+        serde_json::from_slice(&self.body).expect("the body is not JSON")
+    }
+
+    /// The `message` of every entry in a JSON array body.
+    pub fn messages(&self) -> Vec<String> {
+        self.json()
+            .as_array()
+            .expect("the body is not a JSON array")
+            .iter()
+            .map(|entry| entry["message"].as_str().unwrap().to_string())
+            .collect()
+    }
+}
+
+/// What a [`MockServer`] answers with.
+#[derive(Debug, Clone)]
+pub struct Response {
+    pub status: u16,
+    pub headers: Vec<(String, String)>,
+    /// Waits this long before answering.
+    pub delay: Duration,
+    /// Closes the connection instead of answering, like a load balancer
+    /// that dropped it while it was idle.
+    pub hang_up: bool,
+}
+
+impl Response {
+    pub fn status(status: u16) -> Response {
+        Response {
+            status,
+            headers: Vec::new(),
+            delay: Duration::ZERO,
+            hang_up: false,
+        }
+    }
+
+    pub fn hang_up() -> Response {
+        Response {
+            hang_up: true,
+            ..Response::status(0)
+        }
+    }
+
+    pub fn header(mut self, name: &str, value: &str) -> Response {
+        self.headers.push((name.to_string(), value.to_string()));
+        self
+    }
+
+    pub fn delay(mut self, delay: Duration) -> Response {
+        self.delay = delay;
+        self
+    }
+}
+
+/// An HTTP/1.1 server on a local port that records every request, and
+/// answers with the queued responses in turn, then with `202 Accepted`.
+pub struct MockServer {
+    pub url: String,
+    requests: Arc<Mutex<Vec<Request>>>,
+    responses: Arc<Mutex<VecDeque<Response>>>,
+    connections: Arc<AtomicUsize>,
+}
+
+impl MockServer {
+    pub async fn start() -> MockServer {
+        let (server, listener) = MockServer::bind("http").await;
+        let accept = server.acceptor();
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                tokio::spawn(accept(stream));
+            }
+        });
+        server
+    }
+
+    /// Like [`MockServer::start`], but serving HTTPS with the self-signed
+    /// certificate in `tests/fixtures`.
+    pub async fn start_tls() -> MockServer {
+        let identity = native_tls::Identity::from_pkcs8(
+            include_bytes!("../fixtures/self_signed.crt"),
+            include_bytes!("../fixtures/self_signed.key"),
+        )
+        .unwrap();
+        let tls =
+            tokio_native_tls::TlsAcceptor::from(native_tls::TlsAcceptor::new(identity).unwrap());
+        let (server, listener) = MockServer::bind("https").await;
+        let accept = server.acceptor();
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                let (tls, accept) = (tls.clone(), accept.clone());
+                // A client that rejects the certificate fails the handshake.
+                tokio::spawn(async move {
+                    if let Ok(stream) = tls.accept(stream).await {
+                        accept(stream).await;
+                    }
+                });
+            }
+        });
+        server
+    }
+
+    async fn bind(scheme: &str) -> (MockServer, TcpListener) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("{scheme}://{}", listener.local_addr().unwrap());
+        let server = MockServer {
+            url,
+            requests: Arc::default(),
+            responses: Arc::default(),
+            connections: Arc::default(),
+        };
+        (server, listener)
+    }
+
+    /// Serves the requests of a connection.
+    fn acceptor<S>(&self) -> impl Fn(S) -> BoxFuture<'static, ()> + Clone + Send + 'static
+    where
+        S: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin + Send + 'static,
+    {
+        let (requests, responses) = (self.requests.clone(), self.responses.clone());
+        let connections = self.connections.clone();
+        move |stream| {
+            let connection = connections.fetch_add(1, Ordering::Relaxed);
+            Box::pin(serve(
+                stream,
+                connection,
+                requests.clone(),
+                responses.clone(),
+            ))
+        }
+    }
+
+    /// Answers the next requests with `responses`, in order.
+    pub fn respond_with(&self, responses: impl IntoIterator<Item = Response>) {
+        self.responses.lock().unwrap().extend(responses);
+    }
+
+    pub fn requests(&self) -> Vec<Request> {
+        self.requests.lock().unwrap().clone()
+    }
+
+    /// Waits until at least `count` requests were received.
+    pub async fn wait_for(&self, count: usize) -> Vec<Request> {
+        eventually(|| self.requests.lock().unwrap().len() >= count).await;
+        self.requests()
+    }
+
+    pub fn builder(&self) -> BetterStackAppenderBuilder {
+        BetterStackAppender::builder(self.url.clone(), "token".to_string())
+            .disable_periodic_flush()
+            .retries(0, Duration::from_millis(1))
+    }
+}
+
+async fn serve<S>(
+    stream: S,
+    connection: usize,
+    requests: Arc<Mutex<Vec<Request>>>,
+    responses: Arc<Mutex<VecDeque<Response>>>,
+) where
+    S: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin,
+{
+    let mut stream = BufReader::new(stream);
+    while let Some(request) = read_request(&mut stream, connection).await {
+        // Taken before the request is recorded, so that a test waiting for
+        // the request sees the response it queued for it used.
+        let response = responses
+            .lock()
+            .unwrap()
+            .pop_front()
+            .unwrap_or_else(|| Response::status(202));
+        requests.lock().unwrap().push(request);
+        if response.hang_up {
+            return;
+        }
+        tokio::time::sleep(response.delay).await;
+        let mut head = format!("HTTP/1.1 {} Mock\r\ncontent-length: 0\r\n", response.status);
+        for (name, value) in &response.headers {
+            head.push_str(&format!("{name}: {value}\r\n"));
+        }
+        head.push_str("\r\n");
+        if stream.get_mut().write_all(head.as_bytes()).await.is_err() {
+            return;
+        }
+    }
+}
+
+async fn read_request<S>(stream: &mut BufReader<S>, connection: usize) -> Option<Request>
+where
+    S: tokio::io::AsyncRead + Unpin,
+{
+    let mut line = String::new();
+    stream.read_line(&mut line).await.ok().filter(|&n| n > 0)?;
+    let mut parts = line.split_whitespace();
+    let method = parts.next()?.to_string();
+    let path = parts.next()?.to_string();
+    let mut headers = Vec::new();
+    loop {
+        line.clear();
+        stream.read_line(&mut line).await.ok()?;
+        let header = line.trim_end();
+        if header.is_empty() {
+            break;
+        }
+        let (name, value) = header.split_once(':')?;
+        headers.push((name.trim().to_ascii_lowercase(), value.trim().to_string()));
+    }
+    let header = |name: &str| {
+        headers
+            .iter()
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| value.clone())
+    };
+    let mut body = Vec::new();
+    if header("transfer-encoding").is_some_and(|value| value.contains("chunked")) {
+        loop {
+            line.clear();
+            stream.read_line(&mut line).await.ok()?;
+            let size = usize::from_str_radix(line.trim(), 16).ok()?;
+            let mut chunk = vec![0; size + 2];
+            stream.read_exact(&mut chunk).await.ok()?;
+            if size == 0 {
+                break;
+            }
+            body.extend_from_slice(&chunk[..size]);
+        }
+    } else if let Some(length) = header("content-length") {
+        body.resize(length.parse().ok()?, 0);
+        stream.read_exact(&mut body).await.ok()?;
+    }
+    Some(Request {
+        method,
+        path,
+        headers,
+        body,
+        connection,
+    })
+}
@@ -0,0 +1,130 @@
+#![cfg(feature = "gzip")]
+
+mod common;
+
+use betterstack_logger::diagnostics::{DiagnosticKind, DiagnosticsSink};
+use betterstack_logger::sink::Compression;
+use common::{log, MockServer, Request, Response};
+use log::Level;
+use std::io::Read;
+use std::time::Duration;
+
+/// The messages of a gzip-compressed JSON body.
+fn gunzipped_messages(request: &Request) -> Vec<String> {
+    let mut body = Vec::new();
+    flate2::read::GzDecoder::new(&request.body[..])
+        .read_to_end(&mut body)
+        .unwrap();
+    Request {
+        body,
+        ..request.clone()
+    }
+    .messages()
+}
+
+#[tokio::test]
+async fn compresses_only_bodies_above_the_threshold() {
+    let server = MockServer::start().await;
+    let appender = server
+        .builder()
+        .compression(Compression::Gzip)
+        .compression_min_bytes(1000)
+        .build();
+    log(&appender, Level::Info, "small");
+    appender.drain().await;
+    log(&appender, Level::Info, &"large ".repeat(200));
+    appender.drain().await;
+
+    let requests = server.wait_for(2).await;
+    assert_eq!(requests[0].header("content-encoding"), None);
+    assert_eq!(requests[0].messages(), ["small"]);
+    assert_eq!(requests[1].header("content-encoding"), Some("gzip"));
+    assert_eq!(gunzipped_messages(&requests[1]), ["large ".repeat(200)]);
+}
+
+#[tokio::test]
+async fn does_not_count_the_uncompressed_resend_as_a_retry() {
+    let server = MockServer::start().await;
+    server.respond_with([Response::status(415), Response::status(503)]);
+    let appender = server
+        .builder()
+        .compression(Compression::Gzip)
+        .retries(1, Duration::from_millis(1))
+        .build();
+    log(&appender, Level::Info, "delivered");
+    appender.drain().await;
+
+    let requests = server.wait_for(3).await;
+    assert_eq!(requests.len(), 3);
+    assert_eq!(requests[0].header("content-encoding"), Some("gzip"));
+    assert_eq!(requests[2].header("content-encoding"), None);
+    assert_eq!(requests[2].messages(), ["delivered"]);
+}
+
+#[tokio::test]
+async fn compresses_only_with_an_accepted_encoding() {
+    let server = MockServer::start().await;
+    for accepted in ["br", "gzip"] {
+        let appender = server
+            .builder()
+            .compression(Compression::Gzip)
+            .accepted_encodings([accepted])
+            .build();
+        log(&appender, Level::Info, accepted);
+        appender.drain().await;
+    }
+    let requests = server.wait_for(2).await;
+    assert_eq!(requests[0].header("content-encoding"), None);
+    assert_eq!(requests[0].messages(), ["br"]);
+    assert_eq!(requests[1].header("content-encoding"), Some("gzip"));
+    assert_eq!(gunzipped_messages(&requests[1]), ["gzip"]);
+}
+
+#[tokio::test]
+async fn stops_compressing_after_the_server_rejects_it() {
+    let server = MockServer::start().await;
+    server.respond_with([Response::status(415)]);
+    let (diagnostics, reports) = DiagnosticsSink::channel(4);
+    let appender = server
+        .builder()
+        .compression(Compression::Gzip)
+        .diagnostics_sink(diagnostics)
+        .build();
+    log(&appender, Level::Info, "resent");
+    appender.drain().await;
+    log(&appender, Level::Info, "uncompressed");
+    appender.drain().await;
+
+    let requests = server.wait_for(3).await;
+    assert_eq!(requests[0].header("content-encoding"), Some("gzip"));
+    assert_eq!(requests[1].header("content-encoding"), None);
+    assert_eq!(requests[1].messages(), ["resent"]);
+    assert_eq!(requests[2].header("content-encoding"), None);
+    assert_eq!(requests[2].messages(), ["uncompressed"]);
+    assert_eq!(
+        reports.try_recv().unwrap().kind,
+        DiagnosticKind::CompressionRejected
+    );
+    assert!(reports.try_recv().is_err());
+}
+
+#[tokio::test]
+async fn streams_compressed_bodies() {
+    let server = MockServer::start().await;
+    let appender = server
+        .builder()
+        .compression(Compression::Gzip)
+        .compression_min_bytes(1_000_000)
+        .stream_bodies(true)
+        .build();
+    let messages: Vec<_> = (0..100).map(|i| format!("streamed {i}")).collect();
+    for message in &messages {
+        log(&appender, Level::Info, message);
+    }
+    appender.drain().await;
+
+    let request = &server.wait_for(1).await[0];
+    assert_eq!(request.header("transfer-encoding"), Some("chunked"));
+    assert_eq!(request.header("content-encoding"), Some("gzip"));
+    assert_eq!(gunzipped_messages(request), messages);
+}
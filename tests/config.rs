@@ -0,0 +1,160 @@
+#![cfg(feature = "http")]
+
+//! Configuring the appender from a log4rs config file or a plain config.
+
+mod common;
+
+use betterstack_logger::logger::{
+    BetterStackAppender, BetterStackAppenderBuilder, BetterStackAppenderDeserializer,
+    BetterStackConfig, ConfigError,
+};
+use betterstack_logger::sink::{Envelope, Format};
+use common::{log, MockServer};
+use log::{Level, Log, Record};
+use log4rs::config::Deserializers;
+
+#[tokio::test]
+async fn loads_the_appender_from_yaml() {
+    let server = MockServer::start().await;
+    let yaml = format!(
+        r#"
+appenders:
+  betterstack:
+    kind: betterstack
+    url: {}
+    token: secret
+    batch_size: 2
+    periodic_flush: false
+    appender_name: from-yaml
+    encoder:
+      pattern: "{{l}}: {{m}}"
+root:
+  level: info
+  appenders:
+    - betterstack
+"#,
+        server.url
+    );
+    let path = std::env::temp_dir().join(format!("betterstack-{}.yaml", std::process::id()));
+    std::fs::write(&path, yaml).unwrap();
+    let mut deserializers = Deserializers::new();
+    deserializers.insert("betterstack", BetterStackAppenderDeserializer);
+    let config = log4rs::config::load_config_file(&path, deserializers);
+    std::fs::remove_file(&path).unwrap();
+    let logger = log4rs::Logger::new(config.unwrap());
+
+    // The batch size of 2 sends without a flush.
+    for message in ["first", "second"] {
+        logger.log(
+            &Record::builder()
+                .args(format_args!("{message}"))
+                .level(Level::Warn)
+                .target("test")
+                .build(),
+        );
+    }
+    let request = &server.wait_for(1).await[0];
+    assert_eq!(request.header("authorization"), Some("Bearer secret"));
+    assert_eq!(request.messages(), ["WARN: first", "WARN: second"]);
+    assert_eq!(request.json()[0]["appender"], "from-yaml");
+}
+
+#[test]
+fn config_round_trips_through_the_builder() {
+    let builder = common::builder()
+        .batch_size(5)
+        .retries(2, std::time::Duration::from_millis(20))
+        .path("/v1/logs")
+        .field("service", "api")
+        .max_message_len(100);
+    let config = builder.to_config();
+    assert_eq!(config.batch_size, 5);
+    assert_eq!(
+        (config.max_retries, config.path.as_deref()),
+        (2, Some("/v1/logs"))
+    );
+    assert_eq!(config.static_fields["service"], "api");
+    assert_eq!(
+        BetterStackAppenderBuilder::from_config(config.clone()).to_config(),
+        config
+    );
+    assert_eq!(
+        BetterStackConfig::default(),
+        BetterStackAppender::builder(String::new(), String::new()).to_config()
+    );
+}
+
+#[tokio::test]
+async fn builds_the_appender_from_a_config() {
+    let server = MockServer::start().await;
+    let appender = BetterStackAppender::from_config(BetterStackConfig {
+        ingest_url: server.url.clone(),
+        source_token: "configured".to_string(),
+        periodic_flush: false,
+        ..BetterStackConfig::default()
+    });
+    log(&appender, Level::Info, "from a config");
+    appender.drain().await;
+
+    let request = &server.wait_for(1).await[0];
+    assert_eq!(request.header("authorization"), Some("Bearer configured"));
+    assert_eq!(request.messages(), ["from a config"]);
+}
+
+#[test]
+fn rejects_contradictory_settings() {
+    let error = |configure: fn(BetterStackAppenderBuilder) -> BetterStackAppenderBuilder| {
+        configure(common::builder()).try_build().unwrap_err()
+    };
+    assert_eq!(
+        error(|builder| builder
+            .envelope(Envelope::new("logs"))
+            .format(Format::Ndjson)),
+        ConfigError::EnvelopeWithNdjson
+    );
+    assert_eq!(
+        error(|builder| {
+            builder
+                .envelope(Envelope::new("logs"))
+                .with_batch_transform(|messages| messages.len().into())
+        }),
+        ConfigError::EnvelopeWithBatchTransform
+    );
+    assert_eq!(
+        error(|builder| builder.idempotency_header("content-type")),
+        ConfigError::IdempotencyHeaderConflict {
+            header: "content-type".to_string()
+        }
+    );
+    assert_eq!(
+        error(|builder| builder.method(reqwest::Method::GET)),
+        ConfigError::MethodWithoutBody {
+            method: reqwest::Method::GET
+        }
+    );
+    let invalid = BetterStackAppender::builder("not a url".to_string(), "token".to_string())
+        .path("/logs")
+        .try_build()
+        .unwrap_err();
+    assert!(
+        matches!(&invalid, ConfigError::InvalidIngestUrl { url, .. } if url.starts_with("not a url")),
+        "{invalid:?}"
+    );
+}
+
+#[tokio::test]
+async fn builds_consistent_settings() {
+    let server = MockServer::start().await;
+    let appender = server
+        .builder()
+        .envelope(Envelope::new("logs"))
+        .idempotency_header("idempotency-key")
+        .path("/logs")
+        .try_build()
+        .unwrap();
+    log(&appender, Level::Info, "consistent");
+    appender.drain().await;
+    let requests = server.wait_for(1).await;
+    assert_eq!(requests[0].path, "/logs");
+    assert_eq!(requests[0].json()["logs"][0]["message"], "consistent");
+}
@@ -0,0 +1,56 @@
+#![cfg(feature = "http")]
+
+//! The console tee, checked in a child process since it writes to stdout.
+
+mod common;
+
+use common::{log_to, recording, texts};
+use log::Level;
+use std::process::Command;
+
+const CHILD: &str = "BETTERSTACK_CONSOLE_CHILD";
+
+fn in_child() -> bool {
+    std::env::var_os(CHILD).is_some()
+}
+
+/// Runs the test `name` of this binary in a child process and returns what
+/// it printed.
+fn child_stdout(name: &str) -> String {
+    let output = Command::new(std::env::current_exe().unwrap())
+        .args(["--exact", name, "--quiet"])
+        .env(CHILD, "1")
+        .output()
+        .unwrap();
+    assert!(output.status.success(), "{output:?}");
+    String::from_utf8(output.stdout).unwrap()
+}
+
+#[tokio::test]
+async fn tees_sent_records_to_the_console() {
+    if in_child() {
+        let (appender, sink) = recording(|builder| builder.tee_to_console("[time]"));
+        log_to(&appender, Level::Warn, "app", "to both");
+        appender.drain().await;
+        assert_eq!(texts(&sink), ["to both"]);
+        return;
+    }
+    let stdout = child_stdout("tees_sent_records_to_the_console");
+    assert!(
+        stdout
+            .lines()
+            .any(|line| line == "[time] WARN  app - to both"),
+        "{stdout}"
+    );
+}
+
+#[tokio::test]
+async fn prints_nothing_by_default() {
+    if in_child() {
+        let (appender, _sink) = recording(|builder| builder);
+        log_to(&appender, Level::Warn, "app", "only sent");
+        appender.drain().await;
+        return;
+    }
+    assert!(!child_stdout("prints_nothing_by_default").contains("only sent"));
+}
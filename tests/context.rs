@@ -0,0 +1,167 @@
+#![cfg(feature = "http")]
+
+//! The correlation id and scope fields set from the logging context.
+
+mod common;
+
+use betterstack_logger::context::{self, CORRELATION_FIELD, SCOPE_FIELD};
+use common::{log, recording, sent};
+use log::Level;
+use reqwest::header::HeaderMap;
+#[cfg(feature = "tracing")]
+use std::io::Write;
+use std::sync::Arc;
+#[cfg(feature = "tracing")]
+use tracing_subscriber::layer::SubscriberExt;
+
+#[tokio::test]
+async fn sync_scopes_nest_and_restore() {
+    let (appender, sink) = recording(|builder| builder);
+    context::with_correlation_scope("outer", || {
+        log(&appender, Level::Info, "outer");
+        context::with_correlation_scope("inner", || log(&appender, Level::Info, "inner"));
+        log(&appender, Level::Info, "outer again");
+    });
+    log(&appender, Level::Info, "outside");
+    appender.drain().await;
+
+    let ids: Vec<_> = sent(&sink)
+        .iter()
+        .map(|message| message.fields.get(CORRELATION_FIELD).cloned())
+        .collect();
+    assert_eq!(
+        ids,
+        [
+            Some("outer".into()),
+            Some("inner".into()),
+            Some("outer".into()),
+            None
+        ]
+    );
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn async_scopes_do_not_leak_between_concurrent_tasks() {
+    let (appender, sink) = recording(|builder| builder);
+    let appender = Arc::new(appender);
+    let tasks: Vec<_> = ["first", "second"]
+        .into_iter()
+        .map(|id| {
+            let appender = appender.clone();
+            tokio::spawn(context::correlation_scope(id, async move {
+                for _ in 0..20 {
+                    log(&*appender, Level::Info, id);
+                    tokio::task::yield_now().await;
+                }
+            }))
+        })
+        .collect();
+    for task in tasks {
+        task.await.unwrap();
+    }
+    log(&*appender, Level::Info, "outside");
+    appender.drain().await;
+
+    let sent = sent(&sink);
+    assert_eq!(sent.len(), 41);
+    for message in &sent[..40] {
+        assert_eq!(message.fields[CORRELATION_FIELD], *message.message);
+    }
+    assert!(!sent[40].fields.contains_key(CORRELATION_FIELD));
+}
+
+#[cfg(feature = "tracing")]
+#[tokio::test]
+async fn tags_tracing_events_and_written_lines_too() {
+    let (appender, sink) = recording(|builder| builder);
+    let subscriber = tracing_subscriber::registry().with(appender.tracing_layer());
+    let mut writer = appender.writer();
+    tracing::subscriber::with_default(subscriber, || {
+        context::with_correlation_scope("request-1", || {
+            tracing::info!("event");
+            writeln!(writer, "line").unwrap();
+        });
+        tracing::info!("outside");
+    });
+    appender.drain().await;
+
+    let ids: Vec<_> = sent(&sink)
+        .iter()
+        .map(|message| message.fields.get(CORRELATION_FIELD).cloned())
+        .collect();
+    assert_eq!(
+        ids,
+        [Some("request-1".into()), Some("request-1".into()), None]
+    );
+}
+
+#[tokio::test]
+async fn tags_messages_with_the_dotted_scope_path() {
+    let (appender, sink) = recording(|builder| builder);
+    let import = context::enter_scope("import");
+    log(&appender, Level::Info, "import");
+    {
+        let _parse = context::enter_scope("parse");
+        log(&appender, Level::Info, "import.parse");
+    }
+    log(&appender, Level::Info, "import");
+    let _validate = context::enter_scope("validate");
+    // Also ends the scopes entered after it.
+    drop(import);
+    log(&appender, Level::Info, "outside");
+    appender.drain().await;
+
+    let sent = sent(&sink);
+    for message in &sent[..3] {
+        assert_eq!(message.fields[SCOPE_FIELD], *message.message);
+    }
+    assert!(!sent[3].fields.contains_key(SCOPE_FIELD));
+    assert_eq!(context::scope(), None);
+}
+
+#[test]
+fn takes_the_id_from_the_first_header_present() {
+    let mut headers = HeaderMap::new();
+    headers.insert("x-correlation-id", "from-correlation".parse().unwrap());
+    headers.insert("x-request-id", "".parse().unwrap());
+    let names = ["x-request-id", "x-correlation-id"];
+    assert_eq!(
+        context::header_correlation_id(&headers, &names).as_deref(),
+        Some("from-correlation")
+    );
+    assert_eq!(
+        context::header_correlation_id(&HeaderMap::new(), &names),
+        None
+    );
+}
+
+#[cfg(feature = "tracing")]
+#[tokio::test]
+async fn scopes_tag_tracing_events_and_written_lines_too() {
+    let (appender, sink) = recording(|builder| builder);
+    let subscriber = tracing_subscriber::registry().with(appender.tracing_layer());
+    let mut writer = appender.writer();
+    tracing::subscriber::with_default(subscriber, || {
+        {
+            let _import = context::enter_scope("import");
+            let _parse = context::enter_scope("parse");
+            tracing::info!("event");
+            writeln!(writer, "line").unwrap();
+        }
+        tracing::info!("outside");
+    });
+    appender.drain().await;
+
+    let scopes: Vec<_> = sent(&sink)
+        .iter()
+        .map(|message| message.fields.get(SCOPE_FIELD).cloned())
+        .collect();
+    assert_eq!(
+        scopes,
+        [
+            Some("import.parse".into()),
+            Some("import.parse".into()),
+            None
+        ]
+    );
+}
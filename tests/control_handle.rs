@@ -0,0 +1,153 @@
+#![cfg(feature = "http")]
+
+//! The handle that reaches an appender after it was boxed into log4rs.
+
+mod common;
+
+use betterstack_logger::logger::{BetterStackAppender, Status};
+use common::{MockServer, Response};
+use log::{Level, Log, Record};
+use log4rs::config::{Appender, Config, Root};
+use std::time::{Duration, SystemTime};
+
+/// Hands `appender` to a log4rs logger, as an application would.
+fn into_logger(appender: BetterStackAppender) -> log4rs::Logger {
+    let config = Config::builder()
+        .appender(Appender::builder().build("betterstack", Box::new(appender)))
+        .build(
+            Root::builder()
+                .appender("betterstack")
+                .build(log::LevelFilter::Info),
+        )
+        .unwrap();
+    log4rs::Logger::new(config)
+}
+
+fn log(logger: &log4rs::Logger, message: &str) {
+    logger.log(
+        &Record::builder()
+            .args(format_args!("{message}"))
+            .level(Level::Info)
+            .target("test")
+            .build(),
+    );
+}
+
+#[tokio::test]
+async fn reports_the_last_success() {
+    let server = MockServer::start().await;
+    let appender = server.builder().build();
+    let (handle, drain) = (appender.control_handle(), appender.drain_handle());
+    let logger = into_logger(appender);
+    assert_eq!(handle.clone().last_success_at(), None);
+
+    server.respond_with([Response::status(500)]);
+    log(&logger, "rejected");
+    drain.drain().await;
+    assert_eq!(handle.last_success_at(), None);
+
+    let before = SystemTime::now();
+    log(&logger, "accepted");
+    drain.drain().await;
+    let success = handle.last_success_at().expect("no success reported");
+    // Stored with millisecond precision.
+    assert!(success + Duration::from_millis(1) >= before);
+    assert!(success <= SystemTime::now());
+}
+
+#[tokio::test]
+async fn reports_the_status() {
+    let server = MockServer::start().await;
+    server.respond_with([Response::status(401).delay(Duration::from_millis(300))]);
+    let (appender, shutdown) = server
+        .builder()
+        .batch_size(1)
+        .channel_capacity(1)
+        .fail_on_auth_error(true)
+        .spawn();
+    let handle = appender.control_handle();
+    let logger = into_logger(appender);
+    assert_eq!(handle.status(), Status::default());
+
+    // The first is being sent, the second waits in the channel, and the
+    // rest are dropped.
+    log(&logger, "sent");
+    server.wait_for(1).await;
+    for _ in 0..4 {
+        log(&logger, "more");
+    }
+    assert_eq!(handle.status().dropped, 3);
+
+    shutdown.shutdown().await.unwrap();
+    let status = handle.status();
+    assert!(status.unhealthy);
+    assert!(status.pipeline_dead);
+}
+
+#[tokio::test]
+async fn pauses_resumes_and_discards() {
+    let server = MockServer::start().await;
+    let appender = server.builder().build();
+    let (handle, drain) = (appender.control_handle(), appender.drain_handle());
+    let logger = into_logger(appender);
+
+    handle.pause();
+    log(&logger, "held");
+    drain.drain().await;
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    assert!(server.requests().is_empty());
+
+    handle.resume();
+    drain.drain().await;
+    assert_eq!(server.wait_for(1).await[0].messages(), ["held"]);
+
+    handle.pause();
+    log(&logger, "discarded");
+    handle.discard_buffered();
+    log(&logger, "kept");
+    handle.resume();
+    drain.drain().await;
+    let requests = server.wait_for(2).await;
+    assert_eq!(requests.len(), 2);
+    assert_eq!(requests[1].messages(), ["kept"]);
+}
+
+#[tokio::test]
+async fn flushes_when_polled_once_due() {
+    let server = MockServer::start().await;
+    let appender = server
+        .builder()
+        .flush_interval(Duration::from_millis(100))
+        .build();
+    let handle = appender.control_handle();
+    let logger = into_logger(appender);
+
+    log(&logger, "due later");
+    handle.poll_flush_if_due();
+    tokio::time::sleep(Duration::from_millis(30)).await;
+    assert!(server.requests().is_empty());
+
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    handle.poll_flush_if_due();
+    assert_eq!(server.wait_for(1).await[0].messages(), ["due later"]);
+}
+
+#[tokio::test]
+async fn purges_unsent_messages() {
+    let server = MockServer::start().await;
+    let appender = server.builder().build();
+    let (handle, drain) = (appender.control_handle(), appender.drain_handle());
+    let logger = into_logger(appender);
+
+    handle.pause();
+    log(&logger, "login password=hunter2");
+    log(&logger, "login ok");
+    let purged = handle
+        .purge(|message| message.message.contains("password="))
+        .await;
+    assert_eq!(purged, 1);
+
+    handle.resume();
+    drain.drain().await;
+    assert_eq!(server.wait_for(1).await[0].messages(), ["login ok"]);
+}
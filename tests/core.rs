@@ -0,0 +1,74 @@
+//! What the crate offers without the `http` feature: the `Sink` trait and the
+//! serialized form of `LogMessage`, for bringing your own transport.
+
+use betterstack_logger::sink::{LogMessage, RecordingSink, SendError, Sink};
+use futures_util::future::BoxFuture;
+use futures_util::FutureExt;
+use serde_json::{json, Map};
+use std::sync::Mutex;
+use std::time::Instant;
+
+/// Writes each batch as NDJSON into a buffer.
+#[derive(Default)]
+struct NdjsonBuffer(Mutex<Vec<u8>>);
+
+impl Sink for NdjsonBuffer {
+    fn send<'a>(&'a self, batch: &'a [LogMessage]) -> BoxFuture<'a, Result<(), SendError>> {
+        let mut buffer = self.0.lock().unwrap();
+        for message in batch {
+            serde_json::to_writer(&mut *buffer, message).unwrap();
+            buffer.push(b'\n');
+        }
+        Box::pin(async { Ok(()) })
+    }
+}
+
+fn message(text: &'static str) -> LogMessage {
+    LogMessage {
+        timestamp: "2024-01-02T03:04:05.678Z".to_string(),
+        level: "INFO ".to_string(),
+        target: "core".to_string(),
+        thread: None,
+        message: text.into(),
+        module_path: None,
+        file: None,
+        line: Some(3),
+        function: None,
+        fields: Map::from_iter([("user".to_string(), json!("alice"))]),
+        enqueued_at: Instant::now(),
+    }
+}
+
+#[test]
+fn a_custom_sink_receives_serializable_messages() {
+    let sink = NdjsonBuffer::default();
+    let batch = [message("first"), message("second")];
+    // Neither sink waits on anything, so no runtime is needed.
+    sink.send(&batch).now_or_never().unwrap().unwrap();
+    let recorder = RecordingSink::new();
+    recorder.send(&batch).now_or_never().unwrap().unwrap();
+
+    let lines: Vec<serde_json::Value> = sink
+        .0
+        .lock()
+        .unwrap()
+        .split(|&byte| byte == b'\n')
+        .filter(|line| !line.is_empty())
+        .map(|line| serde_json::from_slice(line).unwrap())
+        .collect();
+    assert_eq!(
+        lines[0],
+        json!({
+            "timestamp": "2024-01-02T03:04:05.678Z",
+            "level": "INFO ",
+            "target": "core",
+            "message": "first",
+            "module_path": null,
+            "file": null,
+            "line": 3,
+            "user": "alice",
+        })
+    );
+    assert_eq!(lines[1]["message"], "second");
+    assert_eq!(recorder.batches()[0].len(), 2);
+}
@@ -0,0 +1,79 @@
+#![cfg(feature = "http")]
+
+//! The fields each combination of the `threads`, `timestamps` and
+//! `function-names` features puts in a message.
+
+mod common;
+
+use betterstack_logger::sink::LogMessage;
+use common::{log, recording, sent};
+use log::Level;
+use serde_json::Value;
+
+/// A message logged from this thread, and its serialized form.
+async fn logged() -> (LogMessage, Value) {
+    let (appender, sink) = recording(|builder| builder);
+    log(&appender, Level::Info, "featured");
+    appender.drain().await;
+    let message = sent(&sink).remove(0);
+    let serialized = serde_json::to_value(&message).unwrap();
+    (message, serialized)
+}
+
+#[cfg(feature = "timestamps")]
+#[tokio::test]
+async fn timestamps_stamp_the_time_of_logging_in_utc() {
+    let before = chrono::Utc::now();
+    let (message, serialized) = logged().await;
+    let timestamp = chrono::DateTime::parse_from_rfc3339(&message.timestamp).unwrap();
+    assert!(message.timestamp.ends_with('Z'), "{}", message.timestamp);
+    // Milliseconds, so the time of logging might be rounded down.
+    assert!(timestamp >= before - chrono::TimeDelta::milliseconds(1));
+    assert!(timestamp <= chrono::Utc::now());
+    assert_eq!(serialized["timestamp"], message.timestamp);
+}
+
+#[cfg(not(feature = "timestamps"))]
+#[tokio::test]
+async fn without_timestamps_the_server_stamps_messages() {
+    let (message, serialized) = logged().await;
+    assert_eq!(message.timestamp, "");
+    assert_eq!(serialized["timestamp"], "");
+}
+
+#[cfg(all(feature = "threads", not(feature = "nightly")))]
+#[tokio::test]
+async fn threads_send_the_thread_name() {
+    let (message, serialized) = logged().await;
+    let name = std::thread::current().name().map(ToString::to_string);
+    assert!(name.is_some());
+    assert_eq!(message.thread, name);
+    assert_eq!(serialized["thread"].as_str(), name.as_deref());
+}
+
+#[cfg(not(feature = "threads"))]
+#[tokio::test]
+async fn without_threads_there_is_no_thread_key() {
+    let (message, serialized) = logged().await;
+    assert_eq!(message.thread, None);
+    assert!(serialized.get("thread").is_none(), "{serialized}");
+}
+
+#[cfg(all(feature = "threads", feature = "timestamps"))]
+#[tokio::test]
+async fn threads_and_timestamps_compose() {
+    let (message, serialized) = logged().await;
+    assert!(message.thread.is_some());
+    assert!(chrono::DateTime::parse_from_rfc3339(&message.timestamp).is_ok());
+    for key in ["timestamp", "thread", "level", "message"] {
+        assert!(serialized.get(key).is_some(), "{key} in {serialized}");
+    }
+}
+
+#[cfg(not(feature = "function-names"))]
+#[tokio::test]
+async fn without_function_names_there_is_no_function_key() {
+    let (message, serialized) = logged().await;
+    assert_eq!(message.function, None);
+    assert!(serialized.get("function").is_none(), "{serialized}");
+}
@@ -0,0 +1,37 @@
+#![cfg(feature = "function-names")]
+
+//! Logs through the `log` macros, which pass the call site, from the only
+//! test of the binary since it installs the global logger.
+
+mod common;
+
+use common::{recording, sent};
+use log4rs::config::{Appender, Config, Root};
+
+#[inline(never)]
+fn handle_request() {
+    log::info!("handled");
+}
+
+#[tokio::test]
+async fn sends_the_calling_function() {
+    let (appender, sink) = recording(|builder| builder);
+    let handle = appender.drain_handle();
+    let config = Config::builder()
+        .appender(Appender::builder().build("betterstack", Box::new(appender)))
+        .build(
+            Root::builder()
+                .appender("betterstack")
+                .build(log::LevelFilter::Info),
+        )
+        .unwrap();
+    log4rs::init_config(config).unwrap();
+
+    handle_request();
+    handle.drain().await;
+    let function = sent(&sink)[0].function.clone().unwrap();
+    assert!(
+        function.ends_with("function_names::handle_request"),
+        "{function}"
+    );
+}
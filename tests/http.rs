@@ -0,0 +1,421 @@
+#![cfg(feature = "http")]
+
+//! The requests the appender makes to the ingest endpoint.
+
+mod common;
+
+use betterstack_logger::diagnostics::{DiagnosticKind, DiagnosticsSink};
+use betterstack_logger::logger::AuthScheme;
+use betterstack_logger::sink::Envelope;
+use betterstack_logger::sink::{Compressor, Format, RetryCondition};
+use common::{log, MockServer, Request, Response};
+use log::Level;
+use serde_json::json;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+#[tokio::test]
+async fn reuses_pooled_connections() {
+    let server = MockServer::start().await;
+    let appender = server
+        .builder()
+        .http2_keep_alive_interval(Duration::from_secs(10))
+        .http2_adaptive_window(true)
+        .tcp_keepalive(Duration::from_secs(10))
+        .build();
+    for message in ["first", "second"] {
+        log(&appender, Level::Info, message);
+        appender.drain().await;
+    }
+    let requests = server.wait_for(2).await;
+    assert_eq!(requests[0].connection, requests[1].connection);
+}
+
+#[tokio::test]
+async fn keeps_no_idle_connections_when_the_pool_is_empty() {
+    let server = MockServer::start().await;
+    let appender = server.builder().pool_max_idle_per_host(0).build();
+    for message in ["first", "second"] {
+        log(&appender, Level::Info, message);
+        appender.drain().await;
+    }
+    let requests = server.wait_for(2).await;
+    assert_ne!(requests[0].connection, requests[1].connection);
+}
+
+#[tokio::test]
+async fn reports_failed_batches_on_the_error_channel() {
+    let server = MockServer::start().await;
+    server.respond_with([Response::status(503), Response::status(503)]);
+    let appender = server
+        .builder()
+        .retries(1, Duration::from_millis(1))
+        .error_channel(4)
+        .build();
+    let mut errors = appender.error_receiver().unwrap();
+    assert!(appender.error_receiver().is_none());
+
+    log(&appender, Level::Info, "first");
+    log(&appender, Level::Info, "second");
+    appender.drain().await;
+    let error = errors.try_recv().unwrap();
+    assert_eq!(error.status.map(|status| status.as_u16()), Some(503));
+    assert_eq!((error.attempts, error.batch_size), (2, 2));
+
+    log(&appender, Level::Info, "delivered");
+    appender.drain().await;
+    assert!(errors.try_recv().is_err());
+}
+
+#[tokio::test]
+async fn passes_the_token_as_a_query_parameter() {
+    let server = MockServer::start().await;
+    let appender = server
+        .builder()
+        .auth_scheme(AuthScheme::QueryParam {
+            name: "source_token".to_string(),
+        })
+        .build();
+    log(&appender, Level::Info, "authorized");
+    appender.drain().await;
+
+    let request = &server.wait_for(1).await[0];
+    assert_eq!(request.path, "/?source_token=token");
+    assert_eq!(request.header("authorization"), None);
+}
+
+#[tokio::test]
+async fn accepts_invalid_certificates_only_when_asked() {
+    let server = MockServer::start_tls().await;
+    let rejecting = server.builder().error_channel(1).build();
+    let mut errors = rejecting.error_receiver().unwrap();
+    log(&rejecting, Level::Info, "rejected");
+    rejecting.drain().await;
+    assert_eq!(errors.try_recv().unwrap().status, None);
+    assert!(server.requests().is_empty());
+
+    let accepting = server.builder().danger_accept_invalid_certs(true).build();
+    log(&accepting, Level::Info, "accepted");
+    accepting.drain().await;
+    assert_eq!(server.wait_for(1).await[0].messages(), ["accepted"]);
+}
+
+#[tokio::test]
+async fn reports_the_size_and_outcome_of_each_request() {
+    let server = MockServer::start().await;
+    server.respond_with([Response::status(202), Response::status(500)]);
+    let stats = Arc::new(Mutex::new(Vec::new()));
+    let recorded = stats.clone();
+    let appender = server
+        .builder()
+        .on_batch_sent(move |batch| recorded.lock().unwrap().push(*batch))
+        .build();
+    log(&appender, Level::Info, "first");
+    log(&appender, Level::Info, "second");
+    appender.drain().await;
+    log(&appender, Level::Info, "failed");
+    appender.drain().await;
+
+    let requests = server.wait_for(2).await;
+    let stats = stats.lock().unwrap();
+    assert_eq!(stats.len(), 2);
+    assert_eq!(stats[0].messages, 2);
+    assert_eq!(stats[0].serialized_bytes, requests[0].body.len());
+    assert_eq!(stats[0].compressed_bytes, None);
+    assert!(stats[0].success);
+    assert_eq!(stats[1].messages, 1);
+    assert!(!stats[1].success);
+}
+
+#[tokio::test]
+async fn wraps_the_batch_in_the_envelope() {
+    let server = MockServer::start().await;
+    let appender = server
+        .builder()
+        .envelope(Envelope::new("logs").field("source", "api"))
+        .build();
+    log(&appender, Level::Info, "first");
+    log(&appender, Level::Info, "second");
+    appender.drain().await;
+
+    let body = server.wait_for(1).await[0].json();
+    assert_eq!(body["source"], "api");
+    let messages: Vec<_> = body["logs"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|entry| entry["message"].as_str().unwrap())
+        .collect();
+    assert_eq!(messages, ["first", "second"]);
+}
+
+#[tokio::test]
+async fn builds_the_body_with_the_batch_transform() {
+    let server = MockServer::start().await;
+    let appender = server
+        .builder()
+        .format(Format::Ndjson)
+        .max_messages_per_request(2)
+        .with_batch_transform(|batch| json!({ "count": batch.len(), "logs": batch }))
+        .build();
+    for message in ["first", "second", "third"] {
+        log(&appender, Level::Info, message);
+    }
+    appender.drain().await;
+
+    let requests = server.wait_for(2).await;
+    assert_eq!(requests[0].header("content-type"), Some("application/json"));
+    let bodies: Vec<_> = requests.iter().map(Request::json).collect();
+    assert_eq!(
+        (&bodies[0]["count"], &bodies[1]["count"]),
+        (&json!(2), &json!(1))
+    );
+    assert_eq!(bodies[0]["logs"][1]["message"], "second");
+    assert_eq!(bodies[1]["logs"][0]["message"], "third");
+}
+
+#[tokio::test]
+async fn keeps_requests_within_max_request_bytes() {
+    let server = MockServer::start().await;
+    let appender = server.builder().max_request_bytes(500).build();
+    let messages: Vec<_> = (0..6).map(|i| format!("message {i}")).collect();
+    for message in &messages {
+        log(&appender, Level::Info, message);
+    }
+    log(&appender, Level::Info, &"x".repeat(2000));
+    appender.drain().await;
+
+    let requests = server.requests();
+    assert!(requests.len() > 2, "{} requests", requests.len());
+    for request in &requests {
+        assert!(request.body.len() <= 500, "{} bytes", request.body.len());
+    }
+    let sent: Vec<_> = requests
+        .iter()
+        .flat_map(|request| request.messages())
+        .collect();
+    assert_eq!(sent[..6], messages[..]);
+    // The oversized message is cut to fit.
+    assert!(sent[6].starts_with("xxx") && sent[6].len() < 500);
+    let last = requests.last().unwrap().json();
+    assert_eq!(last[0]["message_truncated"], true);
+}
+
+#[tokio::test]
+async fn caps_the_messages_in_one_request() {
+    let server = MockServer::start().await;
+    let capped = server.builder().max_messages_per_request(2).build();
+    let messages: Vec<_> = (0..5).map(|i| format!("message {i}")).collect();
+    for message in &messages {
+        log(&capped, Level::Info, message);
+    }
+    capped.drain().await;
+
+    let requests = server.wait_for(3).await;
+    let counts: Vec<_> = requests
+        .iter()
+        .map(|request| request.messages().len())
+        .collect();
+    assert_eq!(counts, [2, 2, 1]);
+    let sent: Vec<_> = requests.iter().flat_map(Request::messages).collect();
+    assert_eq!(sent, messages);
+
+    // A cap of 0 is taken as 1.
+    let single = server.builder().max_messages_per_request(0).build();
+    log(&single, Level::Info, "first");
+    log(&single, Level::Info, "second");
+    single.drain().await;
+    let requests = server.wait_for(5).await;
+    assert_eq!(requests[3].messages(), ["first"]);
+    assert_eq!(requests[4].messages(), ["second"]);
+}
+
+#[tokio::test]
+async fn retries_timeouts_only_when_asked() {
+    let server = MockServer::start().await;
+    let slow = Response::status(202).delay(Duration::from_millis(500));
+    server.respond_with([slow.clone(), slow]);
+    let client = reqwest::Client::builder()
+        .timeout(Duration::from_millis(100))
+        .build()
+        .unwrap();
+    let safe = server
+        .builder()
+        .client(client.clone())
+        .retries(1, Duration::from_millis(1))
+        .error_channel(1)
+        .build();
+    let mut errors = safe.error_receiver().unwrap();
+    log(&safe, Level::Info, "not retried");
+    safe.drain().await;
+    assert_eq!(errors.try_recv().unwrap().attempts, 1);
+
+    let retrying = server
+        .builder()
+        .client(client)
+        .retries(1, Duration::from_millis(1))
+        .retry_on(RetryCondition::SafeAndTimeouts)
+        .idempotency_header("Idempotency-Key")
+        .build();
+    log(&retrying, Level::Info, "retried");
+    retrying.drain().await;
+    let requests = server.wait_for(3).await;
+    assert_eq!(requests.len(), 3);
+    let key = requests[1].header("idempotency-key");
+    assert!(key.is_some());
+    assert_eq!(requests[2].header("idempotency-key"), key);
+    assert_eq!(requests[2].messages(), ["retried"]);
+}
+
+#[tokio::test]
+async fn does_not_retry_client_errors() {
+    let server = MockServer::start().await;
+    server.respond_with([Response::status(400)]);
+    let appender = server
+        .builder()
+        .retries(3, Duration::from_millis(1))
+        .error_channel(1)
+        .build();
+    let mut errors = appender.error_receiver().unwrap();
+    log(&appender, Level::Info, "rejected");
+    appender.drain().await;
+    assert_eq!(errors.try_recv().unwrap().attempts, 1);
+    assert_eq!(server.requests().len(), 1);
+}
+
+#[tokio::test]
+async fn sends_with_the_method_to_the_path() {
+    let server = MockServer::start().await;
+    let appender = server
+        .builder()
+        .method(reqwest::Method::PUT)
+        .path("/v1/logs")
+        .build();
+    log(&appender, Level::Info, "put");
+    appender.drain().await;
+
+    let request = &server.wait_for(1).await[0];
+    assert_eq!(
+        (request.method.as_str(), request.path.as_str()),
+        ("PUT", "/v1/logs")
+    );
+    assert_eq!(request.messages(), ["put"]);
+}
+
+#[tokio::test]
+async fn stops_retrying_at_the_retry_budget() {
+    let server = MockServer::start().await;
+    server.respond_with([Response::status(503), Response::status(503)]);
+    server.respond_with([Response::status(503).header("Retry-After", "1")]);
+    let appender = server
+        .builder()
+        .retries(5, Duration::from_millis(100))
+        .max_retry_duration(Duration::from_millis(250))
+        .error_channel(2)
+        .build();
+    let mut errors = appender.error_receiver().unwrap();
+
+    // Retried after 100ms, but not after another 200ms.
+    let started = std::time::Instant::now();
+    log(&appender, Level::Info, "backoff");
+    appender.drain().await;
+    assert_eq!(errors.try_recv().unwrap().attempts, 2);
+    assert!(started.elapsed() < Duration::from_millis(250));
+
+    // The Retry-After delay counts toward the budget too.
+    let started = std::time::Instant::now();
+    log(&appender, Level::Info, "retry after");
+    appender.drain().await;
+    assert_eq!(errors.try_recv().unwrap().attempts, 1);
+    assert!(started.elapsed() < Duration::from_millis(250));
+    assert_eq!(server.requests().len(), 3);
+}
+
+/// Reverses the body, which is easy to undo in a test.
+struct Reversing;
+
+impl Compressor for Reversing {
+    fn encoding(&self) -> &str {
+        "x-reversed"
+    }
+
+    fn compress(&self, body: &[u8]) -> std::io::Result<Vec<u8>> {
+        Ok(body.iter().rev().copied().collect())
+    }
+}
+
+#[tokio::test]
+async fn compresses_with_a_custom_compressor() {
+    let server = MockServer::start().await;
+    let appender = server
+        .builder()
+        .with_compressor(Box::new(Reversing))
+        .build();
+    log(&appender, Level::Info, "reversed");
+    appender.drain().await;
+
+    let request = server.wait_for(1).await[0].clone();
+    assert_eq!(request.header("content-encoding"), Some("x-reversed"));
+    let restored = Request {
+        body: request.body.iter().rev().copied().collect(),
+        ..request
+    };
+    assert_eq!(restored.messages(), ["reversed"]);
+}
+
+#[tokio::test]
+async fn reports_clock_skew_from_the_date_header() {
+    let server = MockServer::start().await;
+    let server_time =
+        |offset| (chrono::Utc::now() + chrono::TimeDelta::seconds(offset)).to_rfc2822();
+    server.respond_with([
+        Response::status(202).header("Date", &server_time(5)),
+        Response::status(202).header("Date", &server_time(3600)),
+        Response::status(202).header("Date", &server_time(3600)),
+    ]);
+    let mut reports = Vec::new();
+    for messages in [1, 2] {
+        let (diagnostics, receiver) = DiagnosticsSink::channel(4);
+        let appender = server
+            .builder()
+            .diagnostics_sink(diagnostics)
+            .warn_on_clock_skew(Duration::from_secs(60))
+            .build();
+        for _ in 0..messages {
+            log(&appender, Level::Info, "timed");
+            appender.drain().await;
+        }
+        reports.push(std::iter::from_fn(|| receiver.try_recv().ok()).collect::<Vec<_>>());
+    }
+
+    // Within the threshold.
+    assert!(reports[0].is_empty(), "{:?}", reports[0]);
+    // Checked once per interval.
+    assert_eq!(reports[1].len(), 1, "{:?}", reports[1]);
+    assert_eq!(reports[1][0].kind, DiagnosticKind::ClockSkew);
+    assert!(
+        reports[1][0].message.contains("behind"),
+        "{}",
+        reports[1][0]
+    );
+}
+
+#[tokio::test]
+async fn resends_on_a_fresh_connection_after_a_stale_one() {
+    let server = MockServer::start().await;
+    server.respond_with([Response::status(202), Response::hang_up()]);
+    let appender = server.builder().error_channel(1).build();
+    let mut errors = appender.error_receiver().unwrap();
+    for message in ["first", "after the idle period"] {
+        log(&appender, Level::Info, message);
+        appender.drain().await;
+    }
+
+    let requests = server.wait_for(3).await;
+    assert_eq!(requests.len(), 3);
+    assert_eq!(requests[1].connection, requests[0].connection);
+    assert_ne!(requests[2].connection, requests[1].connection);
+    assert_eq!(requests[2].messages(), ["after the idle period"]);
+    // Delivered although retries are off.
+    assert!(errors.try_recv().is_err());
+}
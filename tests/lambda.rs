@@ -0,0 +1,53 @@
+#![cfg(feature = "lambda")]
+
+mod common;
+
+use betterstack_logger::lambda::FlushAfterInvocationLayer;
+use betterstack_logger::logger::BetterStackAppender;
+use common::{log, recording, texts};
+use futures_util::future::{self, BoxFuture};
+use log::Level;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use tower_layer::Layer;
+use tower_service::Service;
+
+/// Logs the event, and fails on `"fail"`.
+struct Handler(Arc<BetterStackAppender>);
+
+impl Service<&'static str> for Handler {
+    type Response = &'static str;
+    type Error = &'static str;
+    type Future = BoxFuture<'static, Result<&'static str, &'static str>>;
+
+    fn poll_ready(&mut self, _: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, event: &'static str) -> Self::Future {
+        log(&*self.0, Level::Info, event);
+        Box::pin(async move {
+            if event == "fail" {
+                Err(event)
+            } else {
+                Ok(event)
+            }
+        })
+    }
+}
+
+#[tokio::test]
+async fn sends_the_logs_before_each_invocation_returns() {
+    let (appender, sink) = recording(|builder| builder);
+    let appender = Arc::new(appender);
+    let layer = FlushAfterInvocationLayer::new(appender.drain_handle());
+    let mut service = layer.layer(Handler(appender.clone()));
+
+    for (event, expected) in [("first", Ok("first")), ("fail", Err("fail"))] {
+        future::poll_fn(|cx| service.poll_ready(cx)).await.unwrap();
+        assert_eq!(service.call(event).await, expected);
+        // Sent by the time the invocation returns, without a flush of its own.
+        assert_eq!(texts(&sink).last().map(String::as_str), Some(event));
+    }
+    assert_eq!(sink.batches().len(), 2);
+}
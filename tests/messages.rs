@@ -0,0 +1,289 @@
+#![cfg(feature = "http")]
+
+//! The messages built from records.
+
+mod common;
+
+use betterstack_logger::context;
+use betterstack_logger::logger::instance_id;
+#[cfg(feature = "threads")]
+use betterstack_logger::logger::BetterStackAppenderBuilder;
+use betterstack_logger::sink::RecordingSink;
+use common::{log, log_kv, log_to, recording, sent, texts};
+use log::Level;
+use log4rs::encode::pattern::PatternEncoder;
+use std::sync::Arc;
+use std::time::Duration;
+
+#[tokio::test]
+async fn escapes_control_characters() {
+    let (appender, sink) = recording(|builder| builder.sanitize_control_chars(true));
+    log(&appender, Level::Info, "bell\u{0007} nul\0 kept\n\t\r");
+    appender.drain().await;
+    assert_eq!(texts(&sink), ["bell\\u{0007} nul\\u{0000} kept\n\t\r"]);
+}
+
+#[tokio::test]
+async fn keeps_control_characters_by_default() {
+    let (appender, sink) = recording(|builder| builder);
+    log(&appender, Level::Info, "bell\u{0007}");
+    appender.drain().await;
+    assert_eq!(sent(&sink)[0].message, "bell\u{0007}");
+}
+
+#[cfg(feature = "threads")]
+#[tokio::test]
+async fn thread_info_can_be_turned_off() {
+    let (with, with_sink) = recording(|builder| builder);
+    let (without, without_sink) = recording(|builder| builder.with_thread_info(false));
+    log(&with, Level::Info, "with");
+    log(&without, Level::Info, "without");
+    with.drain().await;
+    without.drain().await;
+    assert_eq!(
+        sent(&with_sink)[0].thread.as_deref(),
+        std::thread::current().name()
+    );
+    assert_eq!(sent(&without_sink)[0].thread, None);
+}
+
+#[cfg(feature = "threads")]
+#[tokio::test]
+async fn labels_unnamed_threads() {
+    /// The thread sent for a message logged from an unnamed thread.
+    async fn thread_sent(
+        configure: impl FnOnce(BetterStackAppenderBuilder) -> BetterStackAppenderBuilder,
+    ) -> Option<String> {
+        let (appender, sink) = recording(configure);
+        std::thread::scope(|scope| {
+            scope.spawn(|| log(&appender, Level::Info, "from an unnamed thread"));
+        });
+        appender.drain().await;
+        sent(&sink).remove(0).thread
+    }
+
+    assert_eq!(thread_sent(|builder| builder).await.as_deref(), Some("?"));
+    let labelled = thread_sent(|builder| builder.unnamed_thread_label(Some("main".to_string())));
+    assert_eq!(labelled.await.as_deref(), Some("main"));
+    assert_eq!(
+        thread_sent(|builder| builder.unnamed_thread_label(None)).await,
+        None
+    );
+}
+
+#[tokio::test]
+async fn tags_every_message_with_the_instance_id() {
+    let (appender, sink) = recording(|builder| builder.with_instance_id(true));
+    let (other, other_sink) = recording(|builder| builder.with_instance_id(true));
+    let (explicit, explicit_sink) =
+        recording(|builder| builder.with_instance_id_value("run-7".to_string()));
+    let (off, off_sink) =
+        recording(|builder| builder.with_instance_id(true).with_instance_id(false));
+    for appender in [&appender, &appender, &other, &explicit, &off] {
+        log(appender, Level::Info, "tagged");
+        appender.drain().await;
+    }
+
+    let ids: Vec<_> = [sent(&sink), sent(&other_sink)]
+        .concat()
+        .into_iter()
+        .map(|message| message.fields["instance_id"].clone())
+        .collect();
+    assert_eq!(ids.len(), 3);
+    assert!(ids.iter().all(|id| *id == instance_id()), "{ids:?}");
+    assert!(uuid::Uuid::parse_str(instance_id()).is_ok());
+    assert_eq!(sent(&explicit_sink)[0].fields["instance_id"], "run-7");
+    assert!(!sent(&off_sink)[0].fields.contains_key("instance_id"));
+}
+
+#[tokio::test]
+async fn names_the_appender_on_every_message() {
+    let sink = Arc::new(RecordingSink::new());
+    for name in ["api", "jobs"] {
+        let appender = common::builder()
+            .disable_periodic_flush()
+            .with_appender_name(name.to_string())
+            .with_sink(Box::new(sink.clone()))
+            .build();
+        log(&appender, Level::Info, name);
+        appender.drain().await;
+    }
+    let names: Vec<_> = sent(&sink)
+        .iter()
+        .map(|message| message.fields["appender"].clone())
+        .collect();
+    assert_eq!(names, ["api", "jobs"]);
+}
+
+#[tokio::test]
+async fn adds_the_schema_version_when_set() {
+    let (versioned, versioned_sink) = recording(|builder| builder.schema_version(3));
+    let (unversioned, unversioned_sink) = recording(|builder| builder);
+    for appender in [&versioned, &unversioned] {
+        log(appender, Level::Info, "payload");
+        appender.drain().await;
+    }
+    assert_eq!(sent(&versioned_sink)[0].fields["schema_version"], 3);
+    assert!(!sent(&unversioned_sink)[0]
+        .fields
+        .contains_key("schema_version"));
+}
+
+#[tokio::test]
+async fn adds_the_uptime_when_enabled() {
+    let (appender, sink) = recording(|builder| builder.with_uptime(true));
+    let (without, without_sink) = recording(|builder| builder);
+    std::thread::sleep(Duration::from_millis(50));
+    log(&appender, Level::Info, "first");
+    std::thread::sleep(Duration::from_millis(50));
+    log(&appender, Level::Info, "second");
+    log(&without, Level::Info, "untagged");
+    appender.drain().await;
+    without.drain().await;
+
+    let uptimes: Vec<_> = sent(&sink)
+        .iter()
+        .map(|message| message.fields["uptime_ms"].as_u64().unwrap())
+        .collect();
+    assert!(uptimes[0] >= 50, "{uptimes:?}");
+    assert!(uptimes[1] >= uptimes[0] + 50, "{uptimes:?}");
+    assert!(!sent(&without_sink)[0].fields.contains_key("uptime_ms"));
+}
+
+#[tokio::test]
+async fn caps_the_number_of_fields() {
+    let (appender, sink) = recording(|builder| builder.field("static", 1).max_fields(2));
+    log_kv(&appender, "capped", &[("first", 2), ("second", 3)]);
+    log_kv(&appender, "within", &[("first", 2)]);
+    appender.drain().await;
+
+    let sent = sent(&sink);
+    assert_eq!(sent[0].fields.len(), 3);
+    assert_eq!(sent[0].fields["fields_truncated"], true);
+    assert_eq!(sent[1].fields.len(), 2);
+    assert!(!sent[1].fields.contains_key("fields_truncated"));
+}
+
+#[tokio::test]
+async fn truncates_long_messages() {
+    let (appender, sink) = recording(|builder| builder.max_message_len(8));
+    let (tiny, tiny_sink) = recording(|builder| builder.max_message_len(2));
+    let (escaped, escaped_sink) =
+        recording(|builder| builder.sanitize_control_chars(true).max_message_len(10));
+    for message in ["8 bytes!", "nine byte", "ééééé"] {
+        log(&appender, Level::Info, message);
+    }
+    log(&tiny, Level::Info, "cut");
+    log(&escaped, Level::Info, "a\u{0007}b");
+    log(&escaped, Level::Info, "a\u{0007}bc");
+    for appender in [&appender, &tiny, &escaped] {
+        appender.drain().await;
+    }
+
+    let sent = sent(&sink);
+    assert_eq!(sent[0].message, "8 bytes!");
+    assert!(!sent[0].fields.contains_key("message_truncated"));
+    assert_eq!(sent[1].message, "nine …");
+    assert_eq!(sent[1].fields["message_truncated"], true);
+    assert_eq!(sent[2].message, "éé…");
+    // Too short for the marker.
+    assert_eq!(texts(&tiny_sink), ["cu"]);
+    // The limit holds for the escaped message.
+    assert_eq!(texts(&escaped_sink), ["a\\u{0007}b", "a\\u{000…"]);
+}
+
+#[tokio::test]
+async fn caps_the_length_of_captured_values() {
+    let (appender, sink) = recording(|builder| {
+        builder
+            .field("static", "left as it is")
+            .max_field_value_len(8)
+    });
+    context::with_correlation_scope("request-1234", || {
+        let _scope = context::enter_scope("import");
+        let _nested = context::enter_scope("parse");
+        log_kv(
+            &appender,
+            "a longer message",
+            &[("long", "ééééééé"), ("short", "ok")],
+        );
+    });
+    log_to(&appender, Level::Info, "long target", "short");
+    appender.drain().await;
+
+    let sent = sent(&sink);
+    assert_eq!(sent[0].message, "a lon…");
+    // Cut at a character boundary.
+    assert_eq!(sent[0].fields["long"], "éé…");
+    assert_eq!(sent[0].fields["short"], "ok");
+    assert_eq!(sent[0].fields["static"], "left as it is");
+    assert_eq!(sent[0].fields[context::CORRELATION_FIELD], "reque…");
+    assert_eq!(sent[0].fields[context::SCOPE_FIELD], "impor…");
+    assert_eq!(sent[0].target, "test");
+    assert_eq!(sent[1].target, "long …");
+}
+
+#[tokio::test]
+async fn sends_unserializable_values_as_strings() {
+    let (appender, sink) = recording(|builder| builder);
+    // JSON objects only have string keys.
+    let counts = std::collections::BTreeMap::from([((1, 2), 3)]);
+    log_kv(
+        &appender,
+        "odd field",
+        &[("counts", log::kv::Value::from_serde(&counts))],
+    );
+    log(&appender, Level::Info, "next");
+    appender.drain().await;
+
+    let sent = sent(&sink);
+    assert_eq!(texts(&sink), ["odd field", "next"]);
+    assert!(sent[0].fields["counts"].is_string(), "{:?}", sent[0].fields);
+    assert_eq!(appender.status().serialization_errors, 0);
+}
+
+#[tokio::test]
+async fn takes_the_timestamp_from_a_key_value() {
+    let (appender, sink) = recording(|builder| builder.timestamp_from_kv("event_time"));
+    log_kv(
+        &appender,
+        "backfilled",
+        &[("event_time", "2020-01-02T03:04:05.678Z")],
+    );
+    log_kv(&appender, "invalid", &[("event_time", "yesterday")]);
+    appender.drain().await;
+
+    let sent = sent(&sink);
+    assert_eq!(sent[0].timestamp, "2020-01-02T03:04:05.678Z");
+    assert!(!sent[0].fields.contains_key("event_time"));
+    // Stamped as usual, and the value stays a field.
+    assert_ne!(sent[1].timestamp, "yesterday");
+    assert_eq!(sent[1].fields["event_time"], "yesterday");
+}
+
+#[tokio::test]
+async fn falls_back_for_empty_targets() {
+    let (appender, sink) = recording(|builder| builder);
+    let (defaulted, defaulted_sink) = recording(|builder| builder.default_target("myapp"));
+    log_to(&appender, Level::Info, "", "module path");
+    log_to(&defaulted, Level::Info, "", "default");
+    log_to(&defaulted, Level::Info, "set", "set");
+    appender.drain().await;
+    defaulted.drain().await;
+
+    assert_eq!(sent(&sink)[0].target, "tests::common");
+    let targets: Vec<_> = sent(&defaulted_sink)
+        .into_iter()
+        .map(|message| message.target)
+        .collect();
+    assert_eq!(targets, ["myapp", "set"]);
+}
+
+#[tokio::test]
+async fn renders_the_message_with_a_log4rs_encoder() {
+    let encoder = PatternEncoder::new("{l} {t} - {m}");
+    let (appender, sink) = recording(|builder| builder.message_encoder(Box::new(encoder)));
+    log(&appender, Level::Warn, "encoded");
+    appender.drain().await;
+    assert_eq!(texts(&sink), ["WARN test - encoded"]);
+}
@@ -0,0 +1,163 @@
+#![cfg(feature = "http")]
+
+mod common;
+
+use betterstack_logger::sink::{LogMessage, RecordingSink, SendError, Sink};
+use common::{log, texts, MockServer, Response};
+use futures_util::future::BoxFuture;
+use log::Level;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Fails the first `failures` batches, and records the rest.
+struct Flaky {
+    failures: AtomicU32,
+    recorder: Arc<RecordingSink>,
+}
+
+impl Sink for Flaky {
+    fn send<'a>(&'a self, batch: &'a [LogMessage]) -> BoxFuture<'a, Result<(), SendError>> {
+        let failed = self
+            .failures
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |n| n.checked_sub(1))
+            .is_ok();
+        if !failed {
+            return self.recorder.send(batch);
+        }
+        Box::pin(async move {
+            Err(SendError {
+                status: None,
+                attempts: 1,
+                batch_size: batch.len(),
+                delivered: 0,
+                reason: "unavailable".to_string(),
+            })
+        })
+    }
+}
+
+#[tokio::test]
+async fn keeps_the_order_across_a_transient_failure() {
+    let recorder = Arc::new(RecordingSink::new());
+    let appender = common::builder()
+        .disable_periodic_flush()
+        .batch_size(1)
+        .retries(0, Duration::from_millis(5))
+        .ordered_delivery(true)
+        .with_sink(Box::new(Flaky {
+            failures: AtomicU32::new(2),
+            recorder: recorder.clone(),
+        }))
+        .build();
+
+    for message in ["first", "second", "third", "fourth"] {
+        log(&appender, Level::Info, message);
+    }
+    common::eventually(|| texts(&recorder).len() == 4).await;
+    assert_eq!(texts(&recorder), ["first", "second", "third", "fourth"]);
+}
+
+#[tokio::test]
+async fn resends_only_the_requests_after_the_delivered_ones() {
+    let server = MockServer::start().await;
+    server.respond_with([Response::status(202), Response::status(503)]);
+    let appender = server
+        .builder()
+        .batch_size(3)
+        .max_messages_per_request(1)
+        .ordered_delivery(true)
+        .build();
+
+    for message in ["first", "second", "third"] {
+        log(&appender, Level::Info, message);
+    }
+    appender.drain().await;
+    let requests = server.wait_for(4).await;
+    let sent: Vec<_> = requests.iter().map(|request| request.messages()).collect();
+    // The third is held back behind the failed second.
+    assert_eq!(sent, [["first"], ["second"], ["second"], ["third"]]);
+}
+
+#[tokio::test]
+async fn drops_batches_the_server_rejects() {
+    let server = MockServer::start().await;
+    server.respond_with([Response::status(400)]);
+    let appender = server.builder().ordered_delivery(true).build();
+
+    log(&appender, Level::Info, "rejected");
+    appender.drain().await;
+    log(&appender, Level::Info, "next");
+    appender.drain().await;
+    let requests = server.wait_for(2).await;
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    assert_eq!(server.requests().len(), 2);
+    assert_eq!(requests[1].messages(), ["next"]);
+}
+
+#[tokio::test]
+async fn newer_messages_wait_in_the_channel_behind_a_full_held_batch() {
+    let recorder = Arc::new(RecordingSink::new());
+    let flaky = Arc::new(Flaky {
+        failures: AtomicU32::new(u32::MAX),
+        recorder: recorder.clone(),
+    });
+    let appender = common::builder()
+        .disable_periodic_flush()
+        .batch_size(2)
+        .retries(0, Duration::from_secs(3600))
+        .ordered_delivery(true)
+        .with_sink(Box::new(flaky.clone()))
+        .build();
+
+    for message in ["first", "second", "third", "fourth", "fifth"] {
+        log(&appender, Level::Info, message);
+    }
+    appender.drain().await;
+    assert!(recorder.batches().is_empty());
+
+    flaky.failures.store(0, Ordering::Relaxed);
+    appender.drain().await;
+    let batches: Vec<Vec<_>> = recorder
+        .batches()
+        .iter()
+        .map(|batch| {
+            batch
+                .iter()
+                .map(|message| message.message.to_string())
+                .collect()
+        })
+        .collect();
+    assert_eq!(
+        batches,
+        [
+            vec!["first", "second"],
+            vec!["third", "fourth"],
+            vec!["fifth"]
+        ]
+    );
+}
+
+#[tokio::test(start_paused = true)]
+async fn sends_no_heartbeats_while_a_batch_is_held() {
+    let recorder = Arc::new(RecordingSink::new());
+    let flaky = Arc::new(Flaky {
+        failures: AtomicU32::new(u32::MAX),
+        recorder: recorder.clone(),
+    });
+    let appender = common::builder()
+        .disable_periodic_flush()
+        .batch_size(1)
+        .retries(0, Duration::from_secs(3600))
+        .heartbeat(Duration::from_millis(10), "alive")
+        .ordered_delivery(true)
+        .with_sink(Box::new(flaky.clone()))
+        .build();
+
+    log(&appender, Level::Info, "held");
+    // Between two heartbeats, so that none is due once it is delivered.
+    tokio::time::sleep(Duration::from_millis(105)).await;
+    flaky.failures.store(0, Ordering::Relaxed);
+    appender.drain().await;
+    assert_eq!(texts(&recorder), ["held"]);
+}
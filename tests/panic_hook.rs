@@ -0,0 +1,34 @@
+#![cfg(feature = "http")]
+
+//! Panics shipped by the hook, in a binary of their own since the hook is
+//! global.
+
+mod common;
+
+use betterstack_logger::logger::install_panic_hook;
+use common::{recording, sent};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn ships_caught_panics_and_calls_the_previous_hook() {
+    let previous_called = Arc::new(AtomicBool::new(false));
+    let called = previous_called.clone();
+    std::panic::set_hook(Box::new(move |_| called.store(true, Ordering::Relaxed)));
+    let (appender, sink) = recording(|builder| builder);
+    install_panic_hook(&appender);
+
+    let line = line!() + 1;
+    let result = std::panic::catch_unwind(|| panic!("boom"));
+    assert!(result.is_err());
+    assert!(previous_called.load(Ordering::Relaxed));
+
+    // Sent by the hook itself, without a flush.
+    let sent = sent(&sink);
+    assert_eq!(sent.len(), 1);
+    assert_eq!(sent[0].level, "ERROR");
+    assert!(sent[0].message.ends_with(":\nboom"), "{}", sent[0].message);
+    assert_eq!(sent[0].fields["panic"], true);
+    assert_eq!(sent[0].file.as_deref(), Some(file!()));
+    assert_eq!(sent[0].line, Some(line));
+}
@@ -0,0 +1,256 @@
+#![cfg(feature = "http")]
+
+mod common;
+
+use betterstack_logger::diagnostics::DiagnosticsSink;
+use betterstack_logger::logger::{AdaptiveFlush, FlushReason};
+use betterstack_logger::sink::{LogMessage, RecordingSink, SendError, Sink};
+use common::{log, texts};
+use futures_util::future::BoxFuture;
+use log::Level;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::time::{Instant, MissedTickBehavior};
+
+/// Records when each batch was sent.
+#[derive(Default)]
+struct Clock {
+    sent_at: Mutex<Vec<Instant>>,
+}
+
+impl Sink for Clock {
+    fn send<'a>(&'a self, _: &'a [LogMessage]) -> BoxFuture<'a, Result<(), SendError>> {
+        self.sent_at.lock().unwrap().push(Instant::now());
+        Box::pin(async { Ok(()) })
+    }
+}
+
+#[tokio::test]
+async fn idle_ticks_have_no_effect() {
+    let sink = Arc::new(RecordingSink::new());
+    let (diagnostics, reports) = DiagnosticsSink::channel(16);
+    let appender = common::builder()
+        .flush_interval(Duration::from_millis(10))
+        .diagnostics_sink(diagnostics)
+        .with_sink(Box::new(sink.clone()))
+        .build();
+    log(&appender, Level::Info, "sent");
+    common::eventually(|| sink.batches().len() == 1).await;
+    let (status, stats, last_success) = (
+        appender.status(),
+        appender.recent_stats(),
+        appender.last_success_at(),
+    );
+
+    // Ten intervals without a message.
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    assert_eq!(texts(&sink), ["sent"]);
+    assert_eq!(appender.status(), status);
+    assert_eq!(appender.recent_stats(), stats);
+    assert_eq!(appender.last_success_at(), last_success);
+    assert!(reports.try_recv().is_err());
+}
+
+#[tokio::test]
+async fn disabled_periodic_flush_only_sends_on_request() {
+    let (appender, sink) = common::recording(|builder| {
+        builder
+            .flush_interval(Duration::from_millis(10))
+            .disable_periodic_flush()
+    });
+    log(&appender, Level::Info, "waiting");
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    assert!(sink.batches().is_empty());
+
+    appender.drain().await;
+    assert_eq!(texts(&sink), ["waiting"]);
+}
+
+#[tokio::test(start_paused = true)]
+async fn jitter_spreads_the_flushes() {
+    let start = Instant::now();
+    let clocks: Vec<_> = (0..10).map(|_| Arc::new(Clock::default())).collect();
+    let appenders: Vec<_> = clocks
+        .iter()
+        .map(|clock| {
+            let appender = common::builder()
+                .flush_interval(Duration::from_millis(100))
+                .flush_jitter(Duration::from_millis(50))
+                .with_sink(Box::new(clock.clone()))
+                .build();
+            log(&appender, Level::Info, "jittered");
+            appender
+        })
+        .collect();
+    tokio::time::sleep(Duration::from_millis(200)).await;
+
+    let delays: Vec<_> = clocks
+        .iter()
+        .map(|clock| clock.sent_at.lock().unwrap()[0] - start)
+        .collect();
+    for delay in &delays {
+        assert!(
+            (Duration::from_millis(50)..=Duration::from_millis(150)).contains(delay),
+            "{delay:?}"
+        );
+    }
+    assert!(delays.iter().any(|delay| *delay != delays[0]), "{delays:?}");
+    drop(appenders);
+}
+
+#[tokio::test(start_paused = true)]
+async fn heartbeats_are_sent_while_idle() {
+    let (appender, sink) =
+        common::recording(|builder| builder.heartbeat(Duration::from_secs(1), "alive"));
+    tokio::time::sleep(Duration::from_millis(2500)).await;
+
+    let batches = sink.batches();
+    assert_eq!(batches.len(), 2);
+    for batch in &batches {
+        assert_eq!(batch.len(), 1);
+        assert_eq!(batch[0].message, "alive");
+        assert_eq!(batch[0].level, "INFO ");
+        assert_eq!(batch[0].fields["heartbeat"], true);
+    }
+    assert_eq!(appender.recent_stats().reasons[&FlushReason::Heartbeat], 2);
+}
+
+#[tokio::test(start_paused = true)]
+async fn adaptive_flush_shortens_the_interval_under_load() {
+    let clock = Arc::new(Clock::default());
+    let appender = common::builder()
+        .flush_interval(Duration::from_millis(800))
+        .adaptive_flush(AdaptiveFlush {
+            min: Duration::from_millis(100),
+            max: Duration::from_secs(2),
+        })
+        .batch_size(2)
+        .with_sink(Box::new(clock.clone()))
+        .build();
+    let mut sent_at = vec![Instant::now()];
+    // Half a batch is waiting at every tick.
+    for flushes in 1..=5 {
+        log(&appender, Level::Info, "pressure");
+        while clock.sent_at.lock().unwrap().len() < flushes {
+            tokio::time::sleep(Duration::from_millis(1)).await;
+        }
+        sent_at.push(clock.sent_at.lock().unwrap()[flushes - 1]);
+    }
+
+    let intervals: Vec<_> = sent_at
+        .windows(2)
+        .map(|pair| (pair[1] - pair[0]).as_millis())
+        .collect();
+    assert_eq!(intervals, [800, 400, 200, 100, 100]);
+}
+
+#[tokio::test(start_paused = true)]
+async fn adaptive_flush_clamps_the_first_interval() {
+    let clock = Arc::new(Clock::default());
+    let start = Instant::now();
+    let appender = common::builder()
+        .flush_interval(Duration::from_secs(5))
+        .adaptive_flush(AdaptiveFlush {
+            min: Duration::from_millis(100),
+            max: Duration::from_secs(1),
+        })
+        .with_sink(Box::new(clock.clone()))
+        .build();
+    log(&appender, Level::Info, "clamped");
+    tokio::time::sleep(Duration::from_millis(1500)).await;
+    assert_eq!(
+        clock.sent_at.lock().unwrap()[0] - start,
+        Duration::from_secs(1)
+    );
+}
+
+#[tokio::test(start_paused = true)]
+async fn config_handle_changes_the_batch_size_and_interval() {
+    let sink = Arc::new(RecordingSink::new());
+    let appender = common::builder()
+        .flush_interval(Duration::from_secs(10))
+        .with_sink(Box::new(sink.clone()))
+        .build();
+    let config = appender.config_handle();
+    for message in ["first", "second", "third"] {
+        log(&appender, Level::Info, message);
+    }
+    tokio::time::sleep(Duration::from_millis(1)).await;
+    assert!(sink.batches().is_empty());
+
+    // The pending batch is already that large.
+    config.set_max_batch_size(2);
+    tokio::time::sleep(Duration::from_millis(1)).await;
+    assert_eq!(sink.batches().len(), 1);
+    log(&appender, Level::Info, "fourth");
+    log(&appender, Level::Info, "fifth");
+    tokio::time::sleep(Duration::from_millis(1)).await;
+    assert_eq!(sink.batches()[1].len(), 2);
+
+    config.set_flush_interval(Duration::from_secs(1));
+    log(&appender, Level::Info, "sixth");
+    tokio::time::sleep(Duration::from_millis(1500)).await;
+    assert_eq!(sink.batches().len(), 3);
+    assert_eq!(texts(&sink).last().map(String::as_str), Some("sixth"));
+}
+
+#[tokio::test(start_paused = true)]
+async fn the_first_flush_is_one_interval_after_the_start() {
+    let start = Instant::now();
+    let clock = Arc::new(Clock::default());
+    let appender = common::builder()
+        .flush_interval(Duration::from_secs(1))
+        .with_sink(Box::new(clock.clone()))
+        .build();
+    log(&appender, Level::Info, "waits for the interval");
+    tokio::time::sleep(Duration::from_millis(1500)).await;
+    assert_eq!(
+        *clock.sent_at.lock().unwrap(),
+        [start + Duration::from_secs(1)]
+    );
+}
+
+/// Records when each batch was sent, and takes 2.5s to send the first.
+#[derive(Default)]
+struct SlowFirst {
+    clock: Clock,
+}
+
+impl Sink for SlowFirst {
+    fn send<'a>(&'a self, batch: &'a [LogMessage]) -> BoxFuture<'a, Result<(), SendError>> {
+        let first = self.clock.sent_at.lock().unwrap().is_empty();
+        let sent = self.clock.send(batch);
+        Box::pin(async move {
+            if first {
+                tokio::time::sleep(Duration::from_millis(2500)).await;
+            }
+            sent.await
+        })
+    }
+}
+
+#[tokio::test(start_paused = true)]
+async fn missed_ticks_burst_or_delay() {
+    for (behavior, last_flush) in [
+        (MissedTickBehavior::Burst, Duration::from_secs(4)),
+        (MissedTickBehavior::Delay, Duration::from_millis(4500)),
+    ] {
+        let start = Instant::now();
+        let sink = Arc::new(SlowFirst::default());
+        let appender = common::builder()
+            .flush_interval(Duration::from_secs(1))
+            .missed_tick_behavior(behavior)
+            .with_sink(Box::new(sink.clone()))
+            .build();
+        // Sent at 1s, until 3.5s, missing the ticks at 2s and 3s.
+        log(&appender, Level::Info, "slow");
+        tokio::time::sleep(Duration::from_secs(2)).await;
+        log(&appender, Level::Info, "waits for the slow send");
+        tokio::time::sleep(Duration::from_millis(1700)).await;
+        log(&appender, Level::Info, "after catching up");
+        tokio::time::sleep(Duration::from_secs(2)).await;
+
+        let sent_at = sink.clock.sent_at.lock().unwrap();
+        assert_eq!(*sent_at.last().unwrap() - start, last_flush, "{behavior:?}");
+    }
+}
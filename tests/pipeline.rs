@@ -0,0 +1,448 @@
+#![cfg(feature = "http")]
+
+//! The background task: what it reports and how it survives failures.
+
+mod common;
+
+use betterstack_logger::diagnostics::{DiagnosticKind, DiagnosticsSink};
+use betterstack_logger::logger::{BetterStackAppender, FlushReason};
+use betterstack_logger::sink::{LogMessage, RecordingSink, SendError, Sink};
+use common::{log, log_kv, texts, MockServer, Response};
+use futures_util::future::BoxFuture;
+use log::Level;
+use log4rs::append::Append;
+use std::fmt;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::Duration;
+
+/// Panics on the first `panics` batches, and records the rest.
+struct Panicking {
+    panics: AtomicU32,
+    recorder: Arc<RecordingSink>,
+}
+
+impl Panicking {
+    fn new(panics: u32) -> (Panicking, Arc<RecordingSink>) {
+        let recorder = Arc::new(RecordingSink::new());
+        let sink = Panicking {
+            panics: AtomicU32::new(panics),
+            recorder: recorder.clone(),
+        };
+        (sink, recorder)
+    }
+}
+
+impl Sink for Panicking {
+    fn send<'a>(&'a self, batch: &'a [LogMessage]) -> BoxFuture<'a, Result<(), SendError>> {
+        let panic = self
+            .panics
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |n| n.checked_sub(1))
+            .is_ok();
+        assert!(!panic, "sink bug");
+        self.recorder.send(batch)
+    }
+}
+
+#[tokio::test]
+async fn reports_a_panicked_task_and_the_dropped_messages() {
+    let (diagnostics, reports) = DiagnosticsSink::channel(16);
+    let (sink, _) = Panicking::new(1);
+    let appender = common::builder()
+        .disable_periodic_flush()
+        .diagnostics_sink(diagnostics)
+        .with_sink(Box::new(sink))
+        .build();
+
+    log(&appender, Level::Info, "panics");
+    appender.drain().await;
+    let report = reports.recv_timeout(Duration::from_secs(5)).unwrap();
+    assert_eq!(report.kind, DiagnosticKind::TaskPanicked);
+    assert!(report.message.contains("sink bug"), "{report}");
+
+    common::eventually(|| appender.status().pipeline_dead).await;
+    log(&appender, Level::Info, "dropped");
+    log(&appender, Level::Info, "dropped too");
+    let report = reports.recv_timeout(Duration::from_secs(5)).unwrap();
+    assert_eq!(report.kind, DiagnosticKind::PipelineClosed);
+    // Only warned about once.
+    assert!(reports.try_recv().is_err());
+}
+
+#[tokio::test]
+async fn restarts_a_panicked_task() {
+    let (sink, recorder) = Panicking::new(1);
+    let appender = common::builder()
+        .disable_periodic_flush()
+        .diagnostics_sink(DiagnosticsSink::Ignore)
+        .restart_on_panic(true)
+        .with_sink(Box::new(sink))
+        .build();
+
+    log(&appender, Level::Info, "lost");
+    appender.drain().await;
+    log(&appender, Level::Info, "after the restart");
+    appender.drain().await;
+    common::eventually(|| !texts(&recorder).is_empty()).await;
+    assert_eq!(texts(&recorder), ["after the restart"]);
+}
+
+#[tokio::test]
+async fn drops_messages_the_validator_rejects() {
+    let (diagnostics, reports) = DiagnosticsSink::channel(16);
+    let (appender, sink) = common::recording(|builder| {
+        builder.diagnostics_sink(diagnostics).validate(|message| {
+            if message.message.is_empty() {
+                Err("empty message".to_string())
+            } else {
+                Ok(())
+            }
+        })
+    });
+    log(&appender, Level::Info, "valid");
+    log(&appender, Level::Info, "");
+    log(&appender, Level::Info, "also valid");
+    appender.drain().await;
+
+    assert_eq!(texts(&sink), ["valid", "also valid"]);
+    let report = reports.try_recv().unwrap();
+    assert_eq!(report.kind, DiagnosticKind::InvalidMessage);
+    assert!(report.message.contains("empty message"), "{report}");
+}
+
+#[tokio::test]
+async fn a_diagnostics_callback_that_logs_does_not_feed_back() {
+    let appender_cell = Arc::new(OnceLock::<Arc<BetterStackAppender>>::new());
+    let reports = Arc::new(Mutex::new(Vec::new()));
+    let diagnostics = {
+        let (appender_cell, reports) = (appender_cell.clone(), reports.clone());
+        DiagnosticsSink::callback(move |diagnostic| {
+            reports.lock().unwrap().push(diagnostic.kind);
+            // Without the guard, each flush would reject and report it anew.
+            if let Some(appender) = appender_cell.get() {
+                log(&**appender, Level::Info, "");
+            }
+        })
+    };
+    let (appender, sink) = common::recording(|builder| {
+        builder.diagnostics_sink(diagnostics).validate(|message| {
+            if message.message.is_empty() {
+                Err("empty message".to_string())
+            } else {
+                Ok(())
+            }
+        })
+    });
+    let appender = Arc::new(appender);
+    appender_cell.set(appender.clone()).ok().unwrap();
+    log(&*appender, Level::Info, "");
+    log(&*appender, Level::Info, "valid");
+    appender.drain().await;
+    appender.drain().await;
+
+    assert_eq!(texts(&sink), ["valid"]);
+    assert_eq!(*reports.lock().unwrap(), [DiagnosticKind::InvalidMessage]);
+}
+
+#[tokio::test]
+async fn records_the_batches_as_sent() {
+    let (appender, sink) = common::recording(|builder| builder.batch_size(2));
+    for message in ["first", "second", "third"] {
+        log(&appender, Level::Info, message);
+    }
+    appender.drain().await;
+
+    let batches: Vec<Vec<_>> = sink
+        .batches()
+        .iter()
+        .map(|batch| {
+            batch
+                .iter()
+                .map(|message| message.message.clone())
+                .collect()
+        })
+        .collect();
+    assert_eq!(batches, [vec!["first", "second"], vec!["third"]]);
+}
+
+#[tokio::test]
+async fn recent_stats_cover_the_last_64_flushes() {
+    let (appender, _sink) = common::recording(|builder| builder.batch_size(2));
+    assert_eq!(appender.recent_stats().flushes, 0);
+    for message in ["first", "second", "third"] {
+        log(&appender, Level::Info, message);
+    }
+    appender.drain().await;
+
+    let stats = appender.recent_stats();
+    assert_eq!(stats.flushes, 2);
+    assert_eq!(stats.average_batch_size, 1.5);
+    assert_eq!(stats.reasons[&FlushReason::BatchFull], 1);
+    assert_eq!(stats.reasons[&FlushReason::Explicit], 1);
+
+    for _ in 0..70 {
+        log(&appender, Level::Info, "single");
+        appender.drain().await;
+    }
+    let stats = appender.recent_stats();
+    assert_eq!(stats.flushes, 64);
+    assert_eq!(stats.average_batch_size, 1.0);
+    assert_eq!(stats.reasons.get(&FlushReason::BatchFull), None);
+}
+
+#[tokio::test]
+async fn dedupes_identical_messages_within_a_batch() {
+    let (appender, sink) =
+        common::recording(|builder| builder.dedupe_within_batch(true).timestamp_from_kv("at"));
+    // The same timestamp makes the copies identical.
+    let at = [("at", "2024-01-02T03:04:05.678Z")];
+    for message in ["repeated", "other", "repeated", "repeated"] {
+        log_kv(&appender, message, &at);
+    }
+    appender.drain().await;
+    log_kv(&appender, "repeated", &at);
+    appender.drain().await;
+
+    let batches = sink.batches();
+    assert_eq!(batches[0].len(), 2);
+    assert_eq!(batches[0][0].message, "repeated");
+    assert_eq!(batches[0][0].fields["duplicates"], 3);
+    assert!(!batches[0][1].fields.contains_key("duplicates"));
+    // Copies in later batches are sent again.
+    assert_eq!(batches[1][0].message, "repeated");
+    assert!(!batches[1][0].fields.contains_key("duplicates"));
+}
+
+/// Logs `inner` when displayed, i.e. while the outer message is being built,
+/// so that the inner message is queued first although it was logged later.
+struct LogsWhileDisplayed<'a> {
+    appender: &'a BetterStackAppender,
+    inner: &'static str,
+}
+
+impl LogsWhileDisplayed<'_> {
+    const DELAY: Duration = Duration::from_millis(20);
+
+    fn log_outer(&self) {
+        self.appender
+            .append(
+                &log::Record::builder()
+                    .args(format_args!("{self}"))
+                    .level(Level::Info)
+                    .build(),
+            )
+            .unwrap();
+    }
+}
+
+impl fmt::Display for LogsWhileDisplayed<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // Stamps the inner message strictly later than the outer one.
+        std::thread::sleep(LogsWhileDisplayed::DELAY);
+        log(self.appender, Level::Info, self.inner);
+        f.write_str("outer")
+    }
+}
+
+#[tokio::test]
+async fn sorts_the_batch_by_when_messages_were_logged() {
+    for sort in [false, true] {
+        let (appender, sink) = common::recording(|builder| builder.sort_batch_by_timestamp(sort));
+        let nested = LogsWhileDisplayed {
+            appender: &appender,
+            inner: "inner",
+        };
+        nested.log_outer();
+        appender.drain().await;
+        let expected = if sort {
+            ["outer", "inner"]
+        } else {
+            ["inner", "outer"]
+        };
+        assert_eq!(texts(&sink), expected);
+    }
+}
+
+#[tokio::test]
+async fn batch_age_runs_from_the_earliest_logged_message() {
+    let (appender, sink) = common::recording(|builder| builder.sort_batch_by_timestamp(true));
+    LogsWhileDisplayed {
+        appender: &appender,
+        inner: "inner",
+    }
+    .log_outer();
+    appender.drain().await;
+
+    // The outer message was received last, but logged first.
+    assert_eq!(texts(&sink), ["outer", "inner"]);
+    let stats = appender.recent_stats();
+    assert!(
+        stats.average_fill_time >= LogsWhileDisplayed::DELAY,
+        "{stats:?}"
+    );
+}
+
+/// Rejects every batch.
+struct Rejecting;
+
+impl Sink for Rejecting {
+    fn send<'a>(&'a self, batch: &'a [LogMessage]) -> BoxFuture<'a, Result<(), SendError>> {
+        Box::pin(async move {
+            Err(SendError {
+                status: None,
+                attempts: 1,
+                batch_size: batch.len(),
+                delivered: 0,
+                reason: "rejected".to_string(),
+            })
+        })
+    }
+}
+
+#[tokio::test]
+async fn reports_the_errors_of_a_custom_sink() {
+    let appender = common::builder()
+        .disable_periodic_flush()
+        .error_channel(1)
+        .with_sink(Box::new(Rejecting))
+        .build();
+    let mut errors = appender.error_receiver().unwrap();
+    log(&appender, Level::Info, "rejected");
+    appender.drain().await;
+
+    let error = errors.try_recv().unwrap();
+    assert_eq!((error.batch_size, error.reason.as_str()), (1, "rejected"));
+    assert_eq!(appender.last_success_at(), None);
+}
+
+#[tokio::test]
+async fn shutdown_sends_the_buffered_messages_and_stops() {
+    let recorder = Arc::new(RecordingSink::new());
+    let (appender, shutdown) = common::builder()
+        .disable_periodic_flush()
+        .diagnostics_sink(DiagnosticsSink::Ignore)
+        .with_sink(Box::new(recorder.clone()))
+        .spawn();
+    log(&appender, Level::Info, "buffered");
+    shutdown.shutdown().await.unwrap();
+    assert_eq!(texts(&recorder), ["buffered"]);
+    assert!(appender.status().pipeline_dead);
+
+    log(&appender, Level::Info, "too late");
+    appender.drain().await;
+    assert_eq!(texts(&recorder), ["buffered"]);
+}
+
+#[test]
+fn runs_the_uploader_with_the_custom_spawner() {
+    let uploads = tokio::runtime::Builder::new_multi_thread()
+        .worker_threads(1)
+        .thread_name("uploads")
+        .enable_all()
+        .build()
+        .unwrap();
+    let handle = uploads.handle().clone();
+    let ran_on = Arc::new(Mutex::new(Vec::new()));
+    let recorder = Arc::new(RecordingSink::new());
+    let (appender, shutdown) = common::builder()
+        .disable_periodic_flush()
+        .with_sink(Box::new(recorder.clone()))
+        .spawner({
+            let ran_on = ran_on.clone();
+            move |task| {
+                let ran_on = ran_on.clone();
+                handle.spawn(async move {
+                    let thread = std::thread::current().name().map(str::to_string);
+                    ran_on.lock().unwrap().push(thread);
+                    task.await;
+                });
+            }
+        })
+        .spawn();
+
+    let app = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .unwrap();
+    app.block_on(async {
+        log(&appender, Level::Info, "uploaded elsewhere");
+        // Waits for a task it did not spawn itself.
+        shutdown.shutdown().await.unwrap();
+    });
+    assert_eq!(texts(&recorder), ["uploaded elsewhere"]);
+    assert_eq!(*ran_on.lock().unwrap(), [Some("uploads".to_string())]);
+}
+
+#[tokio::test]
+async fn tells_a_closed_pipeline_from_a_full_channel() {
+    let (diagnostics, reports) = DiagnosticsSink::channel(16);
+    let (appender, shutdown) = common::builder()
+        .disable_periodic_flush()
+        .diagnostics_sink(diagnostics)
+        .with_sink(Box::new(RecordingSink::new()))
+        .spawn();
+    assert!(!appender.status().pipeline_dead);
+    shutdown.shutdown().await.unwrap();
+
+    for _ in 0..3 {
+        log(&appender, Level::Info, "after the shutdown");
+    }
+    let status = appender.status();
+    assert!(status.pipeline_dead);
+    assert_eq!(status.dropped, 0);
+    let report = reports.try_recv().unwrap();
+    assert_eq!(report.kind, DiagnosticKind::PipelineClosed);
+    assert!(reports.try_recv().is_err());
+}
+
+#[tokio::test]
+async fn summarizes_the_dropped_messages() {
+    let server = MockServer::start().await;
+    server.respond_with([Response::status(202).delay(Duration::from_millis(200))]);
+    let appender = server
+        .builder()
+        .batch_size(1)
+        .channel_capacity(1)
+        .drop_summary(Duration::from_millis(50))
+        .build();
+
+    // The first is being sent, the second waits, and the rest are dropped.
+    log(&appender, Level::Info, "sent");
+    server.wait_for(1).await;
+    for _ in 0..5 {
+        log(&appender, Level::Info, "more");
+    }
+    let summary = || {
+        server
+            .requests()
+            .iter()
+            .flat_map(|request| request.json().as_array().unwrap().clone())
+            .find(|entry| entry.get("dropped").is_some())
+    };
+    common::eventually(|| summary().is_some()).await;
+    let summary = summary().unwrap();
+    assert_eq!(summary["dropped"], 4);
+    assert_eq!(summary["level"], "WARN ");
+    assert!(summary["message"]
+        .as_str()
+        .unwrap()
+        .starts_with("dropped 4 log messages"));
+}
+
+#[tokio::test]
+async fn drain_waits_for_the_sends_and_keeps_the_appender_running() {
+    let server = MockServer::start().await;
+    server.respond_with([Response::status(202).delay(Duration::from_millis(100))]);
+    let appender = server.builder().batch_size(2).build();
+    for message in ["first", "second", "third"] {
+        log(&appender, Level::Info, message);
+    }
+    appender.drain().await;
+    // Both batches were answered, the slow one included.
+    assert_eq!(server.requests().len(), 2);
+    assert!(appender.last_success_at().is_some());
+
+    log(&appender, Level::Info, "after the drain");
+    appender.drain().await;
+    assert_eq!(server.requests()[2].messages(), ["after the drain"]);
+}
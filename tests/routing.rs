@@ -0,0 +1,103 @@
+#![cfg(feature = "http")]
+
+//! Records routed to other sources than the appender's.
+
+mod common;
+
+use betterstack_logger::logger::{AuditRoute, TenantRouting};
+use common::{log, log_kv, log_to, MockServer, Request};
+use log::{Level, LevelFilter};
+
+/// The messages sent with the source token `token`.
+fn messages_for(requests: &[Request], token: &str) -> Vec<String> {
+    let auth = format!("Bearer {token}");
+    requests
+        .iter()
+        .filter(|request| request.header("authorization") == Some(auth.as_str()))
+        .flat_map(Request::messages)
+        .collect()
+}
+
+#[tokio::test]
+async fn never_samples_audit_records() {
+    let server = MockServer::start().await;
+    let route = AuditRoute::new(server.url.clone(), "audit".to_string(), |record| {
+        record.target() == "audit"
+    });
+    let appender = server
+        .builder()
+        .sample_rate(Level::Info, 0.0)
+        .always_ship(LevelFilter::Off)
+        .audit_route(route)
+        .build();
+
+    log_to(&appender, Level::Info, "audit", "granted");
+    log(&appender, Level::Info, "sampled out");
+    appender.drain().await;
+    let requests = server.wait_for(1).await;
+    assert_eq!(messages_for(&requests, "audit"), ["granted"]);
+    // The copy for the appender's source is sampled like any other record.
+    assert_eq!(messages_for(&requests, "token"), Vec::<String>::new());
+}
+
+#[tokio::test]
+async fn ships_exclusive_audit_records_only_to_the_audit_source() {
+    let server = MockServer::start().await;
+    let route = AuditRoute::new(server.url.clone(), "audit".to_string(), |record| {
+        record.target() == "audit"
+    })
+    .exclusive(true);
+    let appender = server.builder().audit_route(route).build();
+
+    log_to(&appender, Level::Info, "audit", "granted");
+    log(&appender, Level::Info, "regular");
+    appender.drain().await;
+    let requests = server.wait_for(2).await;
+    assert_eq!(messages_for(&requests, "audit"), ["granted"]);
+    assert_eq!(messages_for(&requests, "token"), ["regular"]);
+}
+
+#[tokio::test]
+async fn ships_each_tenants_records_to_its_own_source() {
+    let server = MockServer::start().await;
+    let routing = TenantRouting::by_key("tenant")
+        .tenant("acme", server.url.clone(), "acme".to_string())
+        .tenant("globex", server.url.clone(), "globex".to_string());
+    let appender = server.builder().tenant_routing(routing).build();
+
+    log_kv(&appender, "for acme", &[("tenant", "acme")]);
+    log_kv(&appender, "for globex", &[("tenant", "globex")]);
+    log_kv(&appender, "for acme again", &[("tenant", "acme")]);
+    log_kv(&appender, "unknown tenant", &[("tenant", "initech")]);
+    log(&appender, Level::Info, "no tenant");
+    // Covers the tenant pipelines too.
+    appender.drain().await;
+    let requests = server.wait_for(3).await;
+    assert_eq!(
+        messages_for(&requests, "acme"),
+        ["for acme", "for acme again"]
+    );
+    assert_eq!(messages_for(&requests, "globex"), ["for globex"]);
+    assert_eq!(
+        messages_for(&requests, "token"),
+        ["unknown tenant", "no tenant"]
+    );
+}
+
+#[tokio::test]
+async fn routes_by_the_tenant_a_custom_selector_picks() {
+    let server = MockServer::start().await;
+    let routing = TenantRouting::new(|record| {
+        let tenant = record.target().strip_prefix("tenant::")?;
+        Some(tenant.to_string())
+    })
+    .tenant("acme", server.url.clone(), "acme".to_string());
+    let appender = server.builder().tenant_routing(routing).build();
+
+    log_to(&appender, Level::Info, "tenant::acme", "for acme");
+    log(&appender, Level::Info, "no tenant");
+    appender.drain().await;
+    let requests = server.wait_for(2).await;
+    assert_eq!(messages_for(&requests, "acme"), ["for acme"]);
+    assert_eq!(messages_for(&requests, "token"), ["no tenant"]);
+}
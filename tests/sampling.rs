@@ -0,0 +1,233 @@
+#![cfg(feature = "http")]
+
+//! Which records are shipped: sampling, filters and the levels that bypass
+//! them.
+
+mod common;
+
+use betterstack_logger::diagnostics::{DiagnosticKind, DiagnosticsSink};
+use common::{log, log_to, recording, sent, texts};
+use log::{Level, LevelFilter, Record};
+use log4rs::append::Append;
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+use tokio::time::Instant;
+
+#[tokio::test]
+async fn seeded_sampling_is_reproducible() {
+    let mut runs = Vec::new();
+    for _ in 0..2 {
+        let (appender, sink) = recording(|builder| {
+            builder
+                .batch_size(1000)
+                .sample_rate(Level::Info, 0.5)
+                .sampling_seed(7)
+        });
+        for i in 0..50 {
+            log(&appender, Level::Info, &i.to_string());
+        }
+        appender.drain().await;
+        runs.push(texts(&sink));
+    }
+    assert_eq!(runs[0], runs[1]);
+    assert!(!runs[0].is_empty() && runs[0].len() < 50);
+}
+
+#[tokio::test]
+async fn ships_only_what_the_filter_spec_allows() {
+    let (appender, sink) =
+        recording(|builder| builder.filter_from_str("warn,myapp=debug,myapp::noisy=error"));
+    log_to(&appender, Level::Info, "other", "other info");
+    log_to(&appender, Level::Warn, "other", "other warn");
+    log_to(&appender, Level::Debug, "myapp::api", "api debug");
+    log_to(&appender, Level::Trace, "myapp::api", "api trace");
+    log_to(&appender, Level::Warn, "myapp::noisy", "noisy warn");
+    log_to(&appender, Level::Error, "myapp::noisy", "noisy error");
+    appender.drain().await;
+    assert_eq!(texts(&sink), ["other warn", "api debug", "noisy error"]);
+}
+
+#[tokio::test]
+async fn reports_invalid_filter_directives_to_the_diagnostics_sink() {
+    let (diagnostics, reports) = DiagnosticsSink::channel(4);
+    let (appender, sink) = recording(|builder| {
+        // The sink is set after the filter, and still gets the report.
+        builder
+            .filter_from_str("warn,myapp=loud")
+            .diagnostics_sink(diagnostics)
+    });
+    let report = reports.try_recv().unwrap();
+    assert_eq!(report.kind, DiagnosticKind::InvalidFilterDirective);
+    assert!(report.message.contains("`myapp=loud`"), "{report}");
+    assert!(reports.try_recv().is_err());
+
+    // The valid directives still apply.
+    log_to(&appender, Level::Info, "myapp", "ignored directive");
+    log_to(&appender, Level::Warn, "myapp", "warn");
+    appender.drain().await;
+    assert_eq!(texts(&sink), ["warn"]);
+}
+
+#[tokio::test]
+async fn reads_the_filter_spec_from_the_environment() {
+    std::env::set_var("BETTERSTACK_TEST_FILTER", "error");
+    let (filtered, filtered_sink) =
+        recording(|builder| builder.filter_from_env("BETTERSTACK_TEST_FILTER"));
+    let (unset, unset_sink) =
+        recording(|builder| builder.filter_from_env("BETTERSTACK_TEST_UNSET"));
+    for appender in [&filtered, &unset] {
+        log(appender, Level::Info, "info");
+        appender.drain().await;
+    }
+    assert!(texts(&filtered_sink).is_empty());
+    assert_eq!(texts(&unset_sink), ["info"]);
+}
+
+#[tokio::test]
+async fn ships_by_the_longest_matching_target_prefix() {
+    let levels = HashMap::from([
+        ("myapp".to_string(), LevelFilter::Debug),
+        ("myapp::db".to_string(), LevelFilter::Info),
+    ]);
+    let (appender, sink) = recording(|builder| builder.target_levels(levels, LevelFilter::Warn));
+    log_to(&appender, Level::Debug, "myapp::api", "api debug");
+    log_to(&appender, Level::Trace, "myapp::api", "api trace");
+    log_to(&appender, Level::Debug, "myapp::db", "db debug");
+    log_to(&appender, Level::Info, "myapp::db::pool", "pool info");
+    log_to(&appender, Level::Info, "hyper", "dependency info");
+    log_to(&appender, Level::Warn, "hyper", "dependency warn");
+    appender.drain().await;
+    assert_eq!(texts(&sink), ["api debug", "pool info", "dependency warn"]);
+}
+
+#[tokio::test]
+async fn always_ships_errors_past_sampling_and_filters() {
+    let (appender, sink) = recording(|builder| {
+        builder
+            .filter_from_str("myapp=info")
+            .sample_rate(Level::Error, 0.0)
+            .sample_rate(Level::Warn, 0.0)
+    });
+    let (off, off_sink) = recording(|builder| {
+        builder
+            .sample_rate(Level::Error, 0.0)
+            .always_ship(LevelFilter::Off)
+    });
+    for i in 0..5 {
+        log_to(&appender, Level::Error, "other", &format!("error {i}"));
+        log_to(&appender, Level::Warn, "myapp", "sampled warn");
+        log(&off, Level::Error, "sampled error");
+    }
+    appender.drain().await;
+    off.drain().await;
+    assert_eq!(texts(&sink).len(), 5);
+    assert!(texts(&sink).iter().all(|text| text.starts_with("error")));
+    assert!(texts(&off_sink).is_empty());
+}
+
+#[tokio::test(start_paused = true)]
+async fn coalesces_floods_even_of_always_shipped_levels() {
+    let (appender, sink) = recording(|builder| {
+        builder
+            .coalesce_repeated(LevelFilter::Warn, 2, Duration::from_secs(1))
+            .sample_rate(Level::Error, 0.0)
+    });
+    let window = |errors: usize| {
+        for _ in 0..errors {
+            log(&appender, Level::Error, "db down");
+        }
+        log(&appender, Level::Info, "below the level");
+        log(&appender, Level::Info, "below the level");
+        log(&appender, Level::Info, "below the level");
+    };
+    let start = Instant::now();
+    let mut seen = 0;
+    let mut end_of_window = async || {
+        // Just past the end of the window, whose summaries are then sent.
+        let windows = start.elapsed().as_secs() + 1;
+        tokio::time::sleep_until(start + Duration::from_millis(windows * 1000 + 100)).await;
+        appender.drain().await;
+        let sent = sent(&sink).split_off(seen);
+        seen += sent.len();
+        sent
+    };
+
+    window(5);
+    let sent = end_of_window().await;
+    let texts: Vec<_> = sent.iter().map(|message| &*message.message).collect();
+    assert_eq!(
+        texts[..5],
+        [
+            "db down",
+            "db down",
+            "below the level",
+            "below the level",
+            "below the level"
+        ]
+    );
+    assert_eq!(sent.len(), 6);
+    let summary = &sent[5];
+    assert_eq!(summary.fields["coalesced"], 3);
+    assert_eq!(
+        (summary.level.as_str(), summary.target.as_str()),
+        ("ERROR", "test")
+    );
+    assert!(
+        summary.message.starts_with("db down (logged 5 times"),
+        "{}",
+        summary.message
+    );
+
+    // Still flooding, so only the summary is sent.
+    window(1);
+    let sent = end_of_window().await;
+    assert_eq!(sent.len(), 4);
+    assert_eq!(sent[3].fields["coalesced"], 1);
+
+    // Within the threshold for a window, so sent as usual again.
+    window(1);
+    let sent = end_of_window().await;
+    assert_eq!(sent[0].message, "db down");
+    assert_eq!(sent.len(), 4);
+}
+
+/// Counts how often it is formatted.
+#[derive(Default)]
+struct Counted(AtomicUsize);
+
+impl fmt::Display for Counted {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.fetch_add(1, Ordering::Relaxed);
+        f.write_str("counted")
+    }
+}
+
+#[tokio::test]
+async fn formats_only_the_records_that_are_queued() {
+    let (appender, sink) = recording(|builder| {
+        builder
+            .channel_capacity(1)
+            .filter_from_str("info")
+            .sample_rate(Level::Warn, 0.0)
+    });
+    let counted = Counted::default();
+    for level in [Level::Debug, Level::Warn, Level::Info, Level::Info] {
+        appender
+            .append(
+                &Record::builder()
+                    .args(format_args!("{counted}"))
+                    .level(level)
+                    .target("test")
+                    .build(),
+            )
+            .unwrap();
+    }
+    // Filtered out, sampled out, queued, and dropped as the channel is full,
+    // since the background task has not run yet.
+    assert_eq!(counted.0.load(Ordering::Relaxed), 1);
+    assert_eq!(appender.status().dropped, 1);
+    appender.drain().await;
+    assert_eq!(texts(&sink), ["counted"]);
+}
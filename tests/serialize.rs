@@ -0,0 +1,257 @@
+#![cfg(feature = "http")]
+
+mod common;
+
+use betterstack_logger::sink::{Casing, Format, HttpSink, LogMessage, MissingLinePolicy, Sink};
+use common::{message, MockServer};
+use proptest::prelude::*;
+use serde_json::{Map, Value};
+use std::sync::LazyLock;
+
+fn batch() -> Vec<LogMessage> {
+    let mut batch: Vec<_> = (0..20).map(|i| message(&format!("message {i}"))).collect();
+    batch[3].fields.insert("user".to_string(), "alice".into());
+    batch[7].thread = Some("worker".to_string());
+    batch
+}
+
+/// Sends `batch` through `sink` and returns the body the server received.
+async fn body_sent(sink: &HttpSink, server: &MockServer, batch: &[LogMessage]) -> Vec<u8> {
+    let before = server.requests().len();
+    sink.send(batch).await.unwrap();
+    server.wait_for(before + 1).await[before].body.clone()
+}
+
+#[tokio::test]
+async fn serialize_batch_gives_the_bytes_that_are_sent() {
+    let server = MockServer::start().await;
+    for format in [Format::Json, Format::Ndjson] {
+        let sink = HttpSink::new(
+            reqwest::Client::new(),
+            server.url.clone(),
+            "token".to_string(),
+        )
+        .format(format);
+        let batch = batch();
+        assert_eq!(
+            body_sent(&sink, &server, &batch).await,
+            sink.serialize_batch(&batch).unwrap()
+        );
+    }
+}
+
+#[cfg(feature = "gzip")]
+#[tokio::test]
+async fn serialize_batch_compresses_like_the_requests() {
+    use betterstack_logger::sink::Compression;
+
+    let server = MockServer::start().await;
+    let sink = HttpSink::new(
+        reqwest::Client::new(),
+        server.url.clone(),
+        "token".to_string(),
+    )
+    .compression(Compression::Gzip)
+    .compression_min_bytes(1000);
+    let batch = batch();
+    let serialized = sink.serialize_batch(&batch).unwrap();
+    assert_eq!(&serialized[..2], &[0x1f, 0x8b]);
+    assert_eq!(body_sent(&sink, &server, &batch).await, serialized);
+
+    // Too small to be compressed, both ways.
+    let small = sink.serialize_batch(&batch[..1]).unwrap();
+    assert_eq!(small[0], b'[');
+    assert_eq!(body_sent(&sink, &server, &batch[..1]).await, small);
+}
+
+fn arbitrary_value() -> impl Strategy<Value = Value> {
+    prop_oneof![
+        any::<String>().prop_map(Value::from),
+        any::<i64>().prop_map(Value::from),
+        any::<bool>().prop_map(Value::from),
+        Just(Value::Null),
+    ]
+}
+
+prop_compose! {
+    fn arbitrary_message()(
+        text in any::<String>(),
+        thread in proptest::option::of(any::<String>()),
+        line in proptest::option::of(any::<u32>()),
+        fields in proptest::collection::btree_map("k_[a-z0-9_]{0,8}", arbitrary_value(), 0..4),
+    ) -> LogMessage {
+        let mut message = message(&text);
+        message.thread = thread;
+        message.line = line;
+        message.fields = fields.into_iter().collect::<Map<_, _>>();
+        message
+    }
+}
+
+/// Checks that `entry` holds what was serialized from `message`.
+fn assert_round_trip(entry: &Value, message: &LogMessage) {
+    assert_eq!(entry["message"], *message.message);
+    assert_eq!(entry["level"], message.level);
+    assert_eq!(entry["target"], message.target);
+    assert_eq!(
+        entry.get("thread").and_then(Value::as_str),
+        message.thread.as_deref()
+    );
+    assert_eq!(entry["line"], serde_json::to_value(message.line).unwrap());
+    for (key, value) in &message.fields {
+        assert_eq!(&entry[key], value);
+    }
+}
+
+static JSON: LazyLock<HttpSink> =
+    LazyLock::new(|| HttpSink::new(reqwest::Client::new(), String::new(), String::new()));
+static NDJSON: LazyLock<HttpSink> = LazyLock::new(|| {
+    HttpSink::new(reqwest::Client::new(), String::new(), String::new()).format(Format::Ndjson)
+});
+
+proptest! {
+    #[test]
+    fn json_batches_round_trip(batch in proptest::collection::vec(arbitrary_message(), 0..8)) {
+        let body: Value = serde_json::from_slice(&JSON.serialize_batch(&batch).unwrap()).unwrap();
+        let entries = body.as_array().unwrap();
+        prop_assert_eq!(entries.len(), batch.len());
+        for (entry, message) in entries.iter().zip(&batch) {
+            assert_round_trip(entry, message);
+        }
+    }
+
+    #[test]
+    fn ndjson_batches_round_trip(batch in proptest::collection::vec(arbitrary_message(), 0..8)) {
+        let body = String::from_utf8(NDJSON.serialize_batch(&batch).unwrap()).unwrap();
+        let entries: Vec<Value> = body
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        prop_assert_eq!(entries.len(), batch.len());
+        for (entry, message) in entries.iter().zip(&batch) {
+            assert_round_trip(entry, message);
+        }
+    }
+}
+
+#[cfg(feature = "lean-ndjson")]
+proptest! {
+    #[test]
+    fn lean_ndjson_matches_serde_json(batch in proptest::collection::vec(arbitrary_message(), 0..8)) {
+        let mut expected = Vec::new();
+        for message in &batch {
+            serde_json::to_writer(&mut expected, message).unwrap();
+            expected.push(b'\n');
+        }
+        prop_assert_eq!(
+            String::from_utf8(NDJSON.serialize_batch(&batch).unwrap()).unwrap(),
+            String::from_utf8(expected).unwrap()
+        );
+    }
+}
+
+/// The entries `sink` serializes `batch` into, in either format.
+fn serialized_entries(sink: &HttpSink, batch: &[LogMessage]) -> Vec<Value> {
+    let body = sink.serialize_batch(batch).unwrap();
+    match serde_json::from_slice(&body) {
+        Ok(Value::Array(entries)) => entries,
+        _ => body
+            .split(|&byte| byte == b'\n')
+            .filter(|line| !line.is_empty())
+            .map(|line| serde_json::from_slice(line).unwrap())
+            .collect(),
+    }
+}
+
+#[test]
+fn timestamp_field_name_renames_only_the_timestamp() {
+    for format in [Format::Json, Format::Ndjson] {
+        let sink = HttpSink::new(reqwest::Client::new(), String::new(), String::new())
+            .format(format)
+            .timestamp_field_name("@timestamp");
+        let mut message = message("renamed");
+        message
+            .fields
+            .insert("timestamp".to_string(), "field".into());
+        let entry = &serialized_entries(&sink, &[message])[0];
+        assert_eq!(entry["@timestamp"], "2024-01-02T03:04:05.678Z");
+        assert_eq!(entry["timestamp"], "field");
+        assert_eq!(entry["module_path"], "tests::common");
+    }
+}
+
+#[test]
+fn key_casing_applies_to_every_key() {
+    for format in [Format::Json, Format::Ndjson] {
+        for (casing, module_path, field) in [
+            (Casing::Camel, "modulePath", "userId"),
+            (Casing::Pascal, "ModulePath", "UserId"),
+        ] {
+            let sink = HttpSink::new(reqwest::Client::new(), String::new(), String::new())
+                .format(format)
+                .key_casing(casing);
+            let mut message = message("cased");
+            message.fields.insert("user_id".to_string(), 7.into());
+            let entry = &serialized_entries(&sink, &[message])[0];
+            assert_eq!(entry[module_path], "tests::common", "{entry}");
+            assert_eq!(entry[field], 7, "{entry}");
+            assert!(entry.get("module_path").is_none(), "{entry}");
+        }
+    }
+}
+
+#[test]
+fn level_as_object_nests_the_name_and_severity() {
+    for format in [Format::Json, Format::Ndjson] {
+        let sink = HttpSink::new(reqwest::Client::new(), String::new(), String::new())
+            .format(format)
+            .level_as_object(true);
+        let levels = [
+            ("ERROR", 3),
+            ("WARN ", 4),
+            ("INFO ", 6),
+            ("DEBUG", 7),
+            ("TRACE", 7),
+        ];
+        let batch: Vec<_> = levels
+            .iter()
+            .map(|(level, _)| LogMessage {
+                level: level.to_string(),
+                ..message("leveled")
+            })
+            .collect();
+        let entries = serialized_entries(&sink, &batch);
+        for (entry, (level, severity)) in entries.iter().zip(levels) {
+            assert_eq!(
+                entry["level"],
+                serde_json::json!({"name": level.trim_end(), "severity": severity})
+            );
+        }
+    }
+}
+
+#[test]
+fn missing_line_policy_shapes_the_line() {
+    for format in [Format::Json, Format::Ndjson] {
+        for (policy, expected) in [
+            (MissingLinePolicy::Null, Some(Value::Null)),
+            (MissingLinePolicy::Omit, None),
+            (MissingLinePolicy::Zero, Some(Value::from(0))),
+        ] {
+            let sink = HttpSink::new(reqwest::Client::new(), String::new(), String::new())
+                .format(format)
+                .missing_line_policy(policy);
+            let batch = [
+                LogMessage {
+                    line: None,
+                    ..message("no line")
+                },
+                message("line"),
+            ];
+            let entries = serialized_entries(&sink, &batch);
+            assert_eq!(entries[0].get("line"), expected.as_ref(), "{policy:?}");
+            // Known lines are sent as they are.
+            assert_eq!(entries[1]["line"], 1);
+        }
+    }
+}
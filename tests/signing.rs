@@ -0,0 +1,63 @@
+#![cfg(feature = "signing")]
+
+mod common;
+
+use betterstack_logger::sink::Compressor;
+use common::{log, MockServer};
+use hmac::Mac;
+use log::Level;
+
+/// Replaces every body with the data of RFC 4231 test case 2.
+struct Rfc4231Body;
+
+impl Compressor for Rfc4231Body {
+    fn encoding(&self) -> &str {
+        "x-test"
+    }
+
+    fn compress(&self, _: &[u8]) -> std::io::Result<Vec<u8>> {
+        Ok(b"what do ya want for nothing?".to_vec())
+    }
+}
+
+#[tokio::test]
+async fn signs_the_body_as_sent() {
+    let server = MockServer::start().await;
+    let appender = server
+        .builder()
+        .with_compressor(Box::new(Rfc4231Body))
+        .with_hmac_signing(*b"Jefe", "X-Signature")
+        .build();
+    log(&appender, Level::Info, "signed");
+    appender.drain().await;
+
+    let request = &server.wait_for(1).await[0];
+    assert_eq!(request.body, b"what do ya want for nothing?");
+    assert_eq!(
+        request.header("x-signature"),
+        Some("5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843")
+    );
+}
+
+#[tokio::test]
+async fn signs_uncompressed_bodies() {
+    let server = MockServer::start().await;
+    let appender = server
+        .builder()
+        .with_hmac_signing(*b"secret", "X-Signature")
+        .build();
+    log(&appender, Level::Info, "signed");
+    appender.drain().await;
+
+    let request = &server.wait_for(1).await[0];
+    let mut mac = hmac::Hmac::<sha2::Sha256>::new_from_slice(b"secret").unwrap();
+    mac.update(&request.body);
+    let expected: String = mac
+        .finalize()
+        .into_bytes()
+        .iter()
+        .map(|byte| format!("{byte:02x}"))
+        .collect();
+    assert_eq!(request.header("x-signature"), Some(expected.as_str()));
+    assert_eq!(request.messages(), ["signed"]);
+}
@@ -0,0 +1,129 @@
+#![cfg(feature = "tracing")]
+
+mod common;
+
+use betterstack_logger::logger::UPLOADER_TASK_NAME;
+use betterstack_logger::sink::{LogMessage, SendError, Sink};
+use common::{log, recording, sent, texts};
+use futures_util::future::BoxFuture;
+use log::Level;
+use serde_json::json;
+use std::sync::{Arc, Mutex};
+use tracing_subscriber::layer::SubscriberExt;
+
+#[tokio::test]
+async fn sends_the_span_stack_from_the_root() {
+    let (appender, sink) = recording(|builder| builder);
+    let subscriber = tracing_subscriber::registry().with(appender.tracing_layer());
+    tracing::subscriber::with_default(subscriber, || {
+        let request = tracing::info_span!("request", id = 7);
+        let _entered = request.enter();
+        tracing::info_span!("query").in_scope(|| tracing::info!(rows = 3, "query done"));
+    });
+    appender.drain().await;
+
+    let message = &sent(&sink)[0];
+    assert_eq!(message.message, "query done");
+    assert_eq!(message.fields["rows"], 3);
+    assert_eq!(
+        message.fields["spans"],
+        json!([
+            {"name": "request", "fields": {"id": 7}},
+            {"name": "query", "fields": {}},
+        ])
+    );
+}
+
+#[tokio::test]
+async fn keeps_the_innermost_spans_up_to_the_depth() {
+    let (appender, sink) = recording(|builder| builder);
+    let layer = appender.tracing_layer().max_span_depth(1);
+    let subscriber = tracing_subscriber::registry().with(layer);
+    tracing::subscriber::with_default(subscriber, || {
+        let request = tracing::info_span!("request");
+        let _entered = request.enter();
+        tracing::info_span!("query").in_scope(|| tracing::info!("query done"));
+    });
+    appender.drain().await;
+
+    let spans = &sent(&sink)[0].fields["spans"];
+    assert_eq!(*spans, json!([{"name": "query", "fields": {}}]));
+}
+
+#[tokio::test]
+async fn gates_events_like_log_records() {
+    let (appender, sink) = recording(|builder| {
+        builder
+            .filter_from_str("info,noisy=warn")
+            .sample_rate(Level::Debug, 0.0)
+    });
+    let subscriber = tracing_subscriber::registry().with(appender.tracing_layer());
+    tracing::subscriber::with_default(subscriber, || {
+        tracing::info!(target: "noisy", "filtered out");
+        tracing::warn!(target: "noisy", "kept");
+        tracing::debug!("sampled out");
+        tracing::error!(target: "noisy", "always shipped");
+    });
+    appender.drain().await;
+    assert_eq!(texts(&sink), ["kept", "always shipped"]);
+}
+
+#[tokio::test]
+async fn applies_the_message_limits_to_events() {
+    let (appender, sink) =
+        recording(|builder| builder.sanitize_control_chars(true).max_message_len(16));
+    let subscriber = tracing_subscriber::registry().with(appender.tracing_layer());
+    tracing::subscriber::with_default(subscriber, || {
+        tracing::info!("bell\u{7}");
+        tracing::info!("a long event message");
+    });
+    appender.drain().await;
+
+    let sent = sent(&sink);
+    assert_eq!(sent[0].message, "bell\\u{0007}");
+    assert!(sent[1].message.len() <= 16, "{}", sent[1].message);
+    assert_eq!(sent[1].fields["message_truncated"], true);
+}
+
+#[cfg(feature = "timestamps")]
+#[tokio::test]
+async fn timestamps_events_like_records() {
+    let (appender, sink) = recording(|builder| builder);
+    let subscriber = tracing_subscriber::registry().with(appender.tracing_layer());
+    tracing::subscriber::with_default(subscriber, || tracing::info!("stamped"));
+    appender.drain().await;
+    let timestamp = &sent(&sink)[0].timestamp;
+    assert!(
+        chrono::DateTime::parse_from_rfc3339(timestamp).is_ok(),
+        "{timestamp:?}"
+    );
+}
+
+/// Records the span each batch was sent in.
+#[derive(Default)]
+struct SpanRecorder {
+    spans: Mutex<Vec<Option<&'static str>>>,
+}
+
+impl Sink for SpanRecorder {
+    fn send<'a>(&'a self, _: &'a [LogMessage]) -> BoxFuture<'a, Result<(), SendError>> {
+        let span = tracing::Span::current()
+            .metadata()
+            .map(|metadata| metadata.name());
+        self.spans.lock().unwrap().push(span);
+        Box::pin(async { Ok(()) })
+    }
+}
+
+#[tokio::test]
+async fn runs_the_background_task_in_a_named_span() {
+    let _subscriber = tracing::subscriber::set_default(tracing_subscriber::registry());
+    let recorder = Arc::new(SpanRecorder::default());
+    let appender = common::builder()
+        .disable_periodic_flush()
+        .with_sink(Box::new(recorder.clone()))
+        .build();
+    log(&appender, Level::Info, "in the span");
+    appender.drain().await;
+    assert_eq!(*recorder.spans.lock().unwrap(), [Some(UPLOADER_TASK_NAME)]);
+}
@@ -0,0 +1,69 @@
+#![cfg(all(unix, feature = "unix"))]
+
+mod common;
+
+use betterstack_logger::sink::{Sink, UnixSocketSink};
+use common::message;
+use serde_json::Value;
+use std::path::PathBuf;
+use tokio::io::AsyncReadExt;
+use tokio::net::{UnixListener, UnixStream};
+
+fn socket_path(name: &str) -> PathBuf {
+    let path = std::env::temp_dir().join(format!("betterstack-{name}-{}.sock", std::process::id()));
+    let _ = std::fs::remove_file(&path);
+    path
+}
+
+/// Reads what the sink wrote until it closes the connection or `lines` lines
+/// arrived.
+async fn read_lines(connection: &mut UnixStream, lines: usize) -> Vec<Value> {
+    let mut received = Vec::new();
+    let mut buffer = [0; 4096];
+    while received.iter().filter(|&&byte| byte == b'\n').count() < lines {
+        match connection.read(&mut buffer).await.unwrap() {
+            0 => break,
+            n => received.extend_from_slice(&buffer[..n]),
+        }
+    }
+    let text = String::from_utf8(received).unwrap();
+    assert!(text.ends_with('\n'), "a line was cut: {text:?}");
+    text.lines()
+        .map(|line| serde_json::from_str(line).unwrap())
+        .collect()
+}
+
+#[tokio::test]
+async fn writes_ndjson_and_reconnects_after_the_reader_goes_away() {
+    let path = socket_path("reconnect");
+    let listener = UnixListener::bind(&path).unwrap();
+    let sink = UnixSocketSink::new(&path);
+
+    sink.send(&[message("one"), message("two")]).await.unwrap();
+    let (mut first, _) = listener.accept().await.unwrap();
+    let lines = read_lines(&mut first, 2).await;
+    assert_eq!(lines[0]["message"], "one");
+    assert_eq!(lines[1]["message"], "two");
+    drop(first);
+
+    // The write to the closed connection fails before any of the batch was
+    // written, so it is written whole to a new one.
+    sink.send(&[message("three")]).await.unwrap();
+    let (mut second, _) = listener.accept().await.unwrap();
+    let lines = read_lines(&mut second, 1).await;
+    assert_eq!(lines.len(), 1);
+    assert_eq!(lines[0]["message"], "three");
+
+    let _ = std::fs::remove_file(&path);
+}
+
+#[tokio::test]
+async fn fails_without_a_listener() {
+    let path = socket_path("missing");
+    let error = UnixSocketSink::new(&path)
+        .send(&[message("lost")])
+        .await
+        .unwrap_err();
+    assert_eq!(error.attempts, 2);
+    assert_eq!(error.batch_size, 1);
+}
@@ -0,0 +1,52 @@
+#![cfg(feature = "http")]
+
+mod common;
+
+use common::{recording, sent, texts};
+use log::Level;
+use std::io::Write;
+
+#[tokio::test]
+async fn sends_each_written_line() {
+    let (appender, sink) = recording(|builder| builder.field("service", "api"));
+    let mut writer = appender.writer().level(Level::Warn).target("stdout");
+    write!(writer, "split ").unwrap();
+    writeln!(writer, "line").unwrap();
+    writer.write_all(b"two\r\n\nlines\npartial").unwrap();
+    appender.drain().await;
+    assert_eq!(texts(&sink), ["split line", "two", "lines"]);
+
+    drop(writer);
+    appender.drain().await;
+    let sent = sent(&sink);
+    assert_eq!(sent[3].message, "partial");
+    assert_eq!(
+        (sent[3].level.as_str(), sent[3].target.as_str()),
+        ("WARN ", "stdout")
+    );
+    assert_eq!(sent[3].fields["service"], "api");
+}
+
+#[tokio::test]
+async fn replaces_invalid_utf8() {
+    let (appender, sink) = recording(|builder| builder);
+    let mut writer = appender.writer();
+    writer.write_all(b"bad \xff byte\n").unwrap();
+    appender.drain().await;
+    assert_eq!(texts(&sink), ["bad \u{fffd} byte"]);
+}
+
+#[cfg(feature = "timestamps")]
+#[tokio::test]
+async fn timestamps_lines_like_records() {
+    let (appender, sink) = recording(|builder| builder);
+    let mut writer = appender.writer();
+    writeln!(writer, "stamped").unwrap();
+    appender.drain().await;
+    let timestamp = &sent(&sink)[0].timestamp;
+    assert!(!timestamp.is_empty());
+    assert!(
+        chrono::DateTime::parse_from_rfc3339(timestamp).is_ok(),
+        "{timestamp}"
+    );
+}